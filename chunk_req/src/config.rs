@@ -0,0 +1,46 @@
+use std::time::Duration;
+use retry::RetryPolicy;
+use ratelimit::RateLimit;
+
+/// Connection settings for talking to an overpass instance
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Root of the overpass API, without the trailing /interpreter
+    pub base_url: String,
+
+    /// Applies to both the HTTP request and the overpass query itself
+    pub timeout: Option<Duration>,
+
+    pub user_agent: String,
+
+    /// Proxy for all requests, e.g. http://localhost:3128
+    pub proxy: Option<String>,
+
+    /// Responses larger than this many bytes are rejected
+    pub max_response_size: Option<u64>,
+
+    pub retry: RetryPolicy,
+
+    /// Applied across all requests made through the same client
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            base_url: String::from("https://overpass-api.de/api"),
+            timeout: Some(Duration::from_secs(60)),
+            user_agent: format!("cimulosm/{}", env!("CARGO_PKG_VERSION")),
+            proxy: None,
+            max_response_size: Some(64 * 1024 * 1024),
+            retry: RetryPolicy::default(),
+            rate_limit: Some(RateLimit::default()),
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn interpreter_url(&self) -> String {
+        format!("{}/interpreter", self.base_url.trim_right_matches('/'))
+    }
+}
@@ -0,0 +1,77 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// Non-success HTTP status, with the server's requested delay if it gave one
+    Status(u16, Option<Duration>),
+
+    /// Connection failures, timeouts and bad responses
+    Transport(String),
+
+    /// Response exceeded the configured maximum size
+    TooLarge(u64),
+}
+
+/// The last error from a request, after giving up on retrying it
+#[derive(Debug)]
+pub struct RequestFailure {
+    pub error: RequestError,
+    pub attempts: u32,
+}
+
+impl RequestError {
+    /// Whether the same request could reasonably succeed if sent again
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            // too many requests, and overpass's various ways of saying it's busy. Not 501, which
+            // will never be implemented however many times it's asked
+            RequestError::Status(status, _) => match status {
+                429 | 500 | 502 | 503 | 504 => true,
+                _ => false,
+            },
+            RequestError::Transport(_) => true,
+            RequestError::TooLarge(_) => false,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match *self {
+            RequestError::Status(_, retry_after) => retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RequestError::Status(status, _) => write!(f, "Bad server status code {}", status),
+            RequestError::Transport(ref reason) => write!(f, "{}", reason),
+            RequestError::TooLarge(max) => write!(f, "Response exceeded maximum size of {} bytes", max),
+        }
+    }
+}
+
+impl Error for RequestError {
+    fn description(&self) -> &str {
+        match *self {
+            RequestError::Status(_, _) => "bad server status code",
+            RequestError::Transport(ref reason) => reason,
+            RequestError::TooLarge(_) => "response too large",
+        }
+    }
+}
+
+impl fmt::Display for RequestFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (after {} attempts)", self.error, self.attempts)
+    }
+}
+
+impl Error for RequestFailure {
+    fn description(&self) -> &str {
+        self.error.description()
+    }
+}
@@ -1,30 +1,160 @@
 extern crate reqwest;
+extern crate rand;
+
+#[macro_use]
+extern crate log;
 use std::io::Read;
 use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+mod query;
+mod config;
+mod error;
+mod retry;
+mod ratelimit;
+
+#[cfg(feature = "mock")]
+pub mod mock;
+
+pub use query::{FeatureClass, FeatureFilter};
+pub use config::ClientConfig;
+pub use error::{RequestError, RequestFailure};
+pub use retry::RetryPolicy;
+pub use ratelimit::{RateLimit, TokenBucket};
 
-pub fn request_osm(top_left: (f64, f64), bottom_right: (f64, f64)) -> Result<String, String> {
-    actually_request_osm(
-        bottom_right.0,
-        top_left.0,
-        top_left.1,
-        bottom_right.1
-        )
+pub struct Client {
+    http: reqwest::Client,
+    config: ClientConfig,
+    limiter: Option<TokenBucket>,
 }
 
-fn actually_request_osm(min_lat: f64, max_lat: f64, min_lon: f64, max_lon: f64) -> Result<String, String> {
+impl Client {
+    pub fn new(config: ClientConfig) -> Result<Self, String> {
+        let http = {
+            let mut builder = reqwest::Client::builder();
+
+            let mut headers = reqwest::header::Headers::new();
+            headers.set(reqwest::header::UserAgent::new(config.user_agent.clone()));
+            builder.default_headers(headers);
+
+            if let Some(timeout) = config.timeout {
+                builder.timeout(timeout);
+            }
+
+            if let Some(ref proxy) = config.proxy {
+                let proxy = reqwest::Proxy::all(proxy.as_str())
+                    .map_err(|e| format!("Bad proxy '{}': {}", proxy, e.description()))?;
+                builder.proxy(proxy);
+            }
+
+            builder.build().map_err(|e| e.description().to_owned())?
+        };
+
+        if let Some(limit) = config.rate_limit {
+            limit.validate()?;
+        }
+
+        let limiter = config.rate_limit.map(TokenBucket::new);
+        Ok(Client { http, config, limiter })
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    pub fn request_osm(&self, top_left: (f64, f64), bottom_right: (f64, f64), filter: &FeatureFilter) -> Result<String, RequestFailure> {
+        self.request_osm_with(top_left, bottom_right, filter, || ())
+    }
+
+    /// Retries according to the client's policy. `slot` is called before every attempt and its
+    /// result is held for the duration of it, so it can be used to limit concurrency without
+    /// holding on to anything while backing off
+    pub fn request_osm_with<F, G>(&self, top_left: (f64, f64), bottom_right: (f64, f64), filter: &FeatureFilter, mut slot: F) -> Result<String, RequestFailure>
+        where F: FnMut() -> G
+    {
+        let policy = &self.config.retry;
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
 
-    let url = format!("http://overpass-api.de/api/map?bbox={},{},{},{}",
-                      min_lon, min_lat, max_lon, max_lat);
-    let mut resp = reqwest::get(url.as_str())
-        .map_err(|e| e.description().to_owned())?;
+            // take the token first, so waiting for it doesn't hold up a slot
+            if let Some(ref limiter) = self.limiter {
+                limiter.acquire();
+            }
 
-    let status = resp.status();
-    if !status.is_success() {
-        return Err(format!("Bad server status code {}", resp.status()));
+            let res = {
+                let _slot = slot();
+                self.actually_request_osm(
+                    bottom_right.0,
+                    top_left.0,
+                    top_left.1,
+                    bottom_right.1,
+                    filter
+                    )
+            };
+
+            match res {
+                Ok(xml) => return Ok(xml),
+                Err(error) => {
+                    if attempts >= policy.max_attempts || !error.is_retryable() {
+                        return Err(RequestFailure { error, attempts });
+                    }
+
+                    let delay = policy.delay(attempts, error.retry_after());
+                    warn!("Request failed ({}), retrying in {:?}", error, delay);
+                    thread::sleep(delay);
+                }
+            }
+        }
     }
 
-    let mut xml = String::new();
-    resp.read_to_string(&mut xml).map_err(|e| e.description().to_owned())?;
-    Ok(xml)
+    fn actually_request_osm(&self, min_lat: f64, max_lat: f64, min_lon: f64, max_lon: f64, filter: &FeatureFilter) -> Result<String, RequestError> {
+
+        let query = query::build_query(min_lat, max_lat, min_lon, max_lon, filter, self.config.timeout);
+        let url = reqwest::Url::parse_with_params(&self.config.interpreter_url(), &[("data", query)])
+            .map_err(|e| RequestError::Transport(e.description().to_owned()))?;
+        let mut resp = self.http.get(url).send()
+            .map_err(|e| RequestError::Transport(e.description().to_owned()))?;
+
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(RequestError::Status(status.as_u16(), parse_retry_after(resp.headers())));
+        }
+
+        let mut xml = String::new();
+        match self.config.max_response_size {
+            Some(max) => {
+                // don't bother downloading something we're going to reject anyway
+                if let Some(&reqwest::header::ContentLength(len)) = resp.headers().get() {
+                    if len > max {
+                        return Err(RequestError::TooLarge(max));
+                    }
+                }
+
+                // the server may not have sent a content length, or lied about it
+                resp.by_ref().take(max + 1).read_to_string(&mut xml)
+                    .map_err(|e| RequestError::Transport(e.description().to_owned()))?;
+                if xml.len() as u64 > max {
+                    return Err(RequestError::TooLarge(max));
+                }
+            }
+            None => {
+                resp.read_to_string(&mut xml)
+                    .map_err(|e| RequestError::Transport(e.description().to_owned()))?;
+            }
+        }
+
+        Ok(xml)
+    }
 }
 
+/// Only the delay-seconds form is understood, an HTTP date falls back to the usual backoff
+fn parse_retry_after(headers: &reqwest::header::Headers) -> Option<Duration> {
+    headers.get_raw("Retry-After")
+        .and_then(|raw| raw.one())
+        .and_then(|bytes| ::std::str::from_utf8(bytes).ok())
+        .and_then(|s| s.trim().parse().ok())
+        .map(Duration::from_secs)
+}
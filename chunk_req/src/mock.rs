@@ -0,0 +1,300 @@
+//! A tiny in-process stand-in for an overpass server, so chunk loading can be exercised without
+//! the network. Bounding box queries are answered with registered fixtures, and responses can be
+//! scripted to be slow, fail or be rate limited.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// South, west, north, east, the same as in the overpass query
+pub type Bbox = (f64, f64, f64, f64);
+
+/// How to answer a request
+#[derive(Debug, Clone)]
+pub enum Response {
+    /// The fixture matching the query's bounding box
+    Fixture,
+
+    /// The fixture, but only after waiting
+    Slow(Duration),
+
+    /// An empty response with the given status
+    Status(u16),
+
+    /// 429 Too Many Requests, with an optional Retry-After in seconds
+    RateLimited(Option<u64>),
+}
+
+struct State {
+    /// Fixtures without a bbox match any query
+    fixtures: Vec<(Option<Bbox>, String)>,
+
+    /// Used in order before falling back to `default`
+    script: VecDeque<Response>,
+    default: Response,
+
+    /// Decoded overpass queries, in the order they were received
+    queries: Vec<String>,
+}
+
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    /// Listens on an arbitrary free port on localhost
+    pub fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+
+        let state = Arc::new(Mutex::new(State {
+            fixtures: Vec::new(),
+            script: VecDeque::new(),
+            default: Response::Fixture,
+            queries: Vec::new(),
+        }));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let state = state.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    if let Ok(stream) = stream {
+                        let state = state.clone();
+                        thread::spawn(move || {
+                            // the client hanging up early is its own business
+                            let _ = handle_connection(stream, &state);
+                        });
+                    }
+                }
+            })
+        };
+
+        Ok(MockServer {
+            addr,
+            state,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    /// To be used as `ClientConfig::base_url`
+    pub fn base_url(&self) -> String {
+        format!("http://{}/api", self.addr)
+    }
+
+    pub fn add_fixture(&self, bbox: Option<Bbox>, xml: String) {
+        self.state.lock().unwrap().fixtures.push((bbox, xml));
+    }
+
+    pub fn add_fixture_file<P: AsRef<Path>>(&self, bbox: Option<Bbox>, path: P) -> io::Result<()> {
+        let mut xml = String::new();
+        fs::File::open(path)?.read_to_string(&mut xml)?;
+        self.add_fixture(bbox, xml);
+        Ok(())
+    }
+
+    /// Adds every file in the directory named `<south>_<west>_<north>_<east>.osm`
+    pub fn add_fixture_dir<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let bbox = path.file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| parse_bbox(s, '_'));
+
+            if let Some(bbox) = bbox {
+                self.add_fixture_file(Some(bbox), &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues a response to be used for the next request that hasn't already got one
+    pub fn push_response(&self, response: Response) {
+        self.state.lock().unwrap().script.push_back(response);
+    }
+
+    /// Used once the scripted responses run out
+    pub fn set_default_response(&self, response: Response) {
+        self.state.lock().unwrap().default = response;
+    }
+
+    pub fn request_count(&self) -> usize {
+        self.state.lock().unwrap().queries.len()
+    }
+
+    pub fn queries(&self) -> Vec<String> {
+        self.state.lock().unwrap().queries.clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+
+        // wake up the accept loop so it notices
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    let target = {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+
+        // skip the headers, there's never a body for a GET
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+        }
+
+        request_line.split_whitespace().nth(1).unwrap_or("").to_owned()
+    };
+
+    let query = target.splitn(2, '?').nth(1)
+        .and_then(|params| {
+            params.split('&')
+                .filter_map(|p| {
+                    let mut kv = p.splitn(2, '=');
+                    match (kv.next(), kv.next()) {
+                        (Some("data"), Some(v)) => Some(percent_decode(v)),
+                        _ => None,
+                    }
+                })
+                .next()
+        })
+        .unwrap_or_default();
+
+    let (response, fixture) = {
+        let mut state = state.lock().unwrap();
+        let response = state.script.pop_front().unwrap_or_else(|| state.default.clone());
+        let fixture = find_fixture(&state.fixtures, &query);
+        state.queries.push(query);
+        (response, fixture)
+    };
+
+    match response {
+        Response::Fixture => write_fixture(&mut stream, fixture),
+        Response::Slow(delay) => {
+            thread::sleep(delay);
+            write_fixture(&mut stream, fixture)
+        }
+        Response::Status(status) => write_response(&mut stream, status, None, ""),
+        Response::RateLimited(retry_after) => write_response(&mut stream, 429, retry_after, ""),
+    }
+}
+
+fn write_fixture(stream: &mut TcpStream, fixture: Option<String>) -> io::Result<()> {
+    match fixture {
+        Some(xml) => write_response(stream, 200, None, &xml),
+        None => write_response(stream, 404, None, ""),
+    }
+}
+
+fn write_response(stream: &mut TcpStream, status: u16, retry_after: Option<u64>, body: &str) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    };
+
+    write!(stream, "HTTP/1.1 {} {}\r\n", status, reason)?;
+    write!(stream, "Content-Type: application/osm3s+xml\r\n")?;
+    write!(stream, "Content-Length: {}\r\n", body.len())?;
+    write!(stream, "Connection: close\r\n")?;
+    if let Some(secs) = retry_after {
+        write!(stream, "Retry-After: {}\r\n", secs)?;
+    }
+    write!(stream, "\r\n{}", body)?;
+    stream.flush()
+}
+
+fn find_fixture(fixtures: &[(Option<Bbox>, String)], query: &str) -> Option<String> {
+    const EPSILON: f64 = 1e-7;
+
+    let bbox = query.find("[bbox:")
+        .and_then(|start| {
+            let rest = &query[start + 6..];
+            rest.find(']').and_then(|end| parse_bbox(&rest[..end], ','))
+        });
+
+    let matches = |fixture: &Bbox, query: &Bbox| {
+        (fixture.0 - query.0).abs() < EPSILON &&
+            (fixture.1 - query.1).abs() < EPSILON &&
+            (fixture.2 - query.2).abs() < EPSILON &&
+            (fixture.3 - query.3).abs() < EPSILON
+    };
+
+    // prefer an exact bbox match over a catch-all
+    let exact = bbox.and_then(|bbox| {
+        fixtures.iter()
+            .find(|&&(ref b, _)| b.map(|b| matches(&b, &bbox)).unwrap_or(false))
+    });
+
+    exact.or_else(|| fixtures.iter().find(|&&(ref b, _)| b.is_none()))
+        .map(|&(_, ref xml)| xml.clone())
+}
+
+fn parse_bbox(s: &str, sep: char) -> Option<Bbox> {
+    let coords: Vec<f64> = s.split(sep)
+        .filter_map(|c| c.trim().parse().ok())
+        .collect();
+
+    if coords.len() == 4 {
+        Some((coords[0], coords[1], coords[2], coords[3]))
+    } else {
+        None
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,252 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Server-side timeout used when the client has none configured
+const DEFAULT_QUERY_TIMEOUT: u64 = 25;
+
+/// Member roles of the nodes where route vehicles stop, the only members of routes fetched
+const STOP_ROLES: &[&str] = &["stop", "stop_entry_only", "stop_exit_only"];
+
+/// A class of OSM feature that the parser understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeatureClass {
+    Highway,
+    LandUse,
+    Building,
+    PointOfInterest,
+    Transit,
+}
+
+/// The set of feature classes to ask overpass for
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureFilter {
+    classes: Vec<FeatureClass>,
+}
+
+impl FeatureClass {
+    /// Overpass filters selecting the elements of this class, fetched along with all their
+    /// members and the nodes of those
+    fn selectors(&self) -> &'static [&'static str] {
+        match *self {
+            FeatureClass::Highway => &[r#"way["highway"]"#],
+            // green and water areas are tagged outside of landuse=*, and areas with holes or
+            // made of several ways are multipolygon relations
+            FeatureClass::LandUse => &[
+                r#"way["landuse"]"#,
+                r#"way["leisure"]"#,
+                r#"way["natural"]"#,
+                r#"relation["landuse"]["type"="multipolygon"]"#,
+                r#"relation["leisure"]["type"="multipolygon"]"#,
+                r#"relation["natural"]["type"="multipolygon"]"#,
+            ],
+            FeatureClass::Building => &[
+                r#"way["building"]"#,
+                r#"relation["building"]["type"="multipolygon"]"#,
+            ],
+            // not every highway node, there are far too many crossings and street lamps
+            FeatureClass::PointOfInterest => &[
+                r#"node["amenity"]"#,
+                r#"node["shop"]"#,
+                r#"node["highway"~"^(bus_stop|traffic_signals)$"]"#,
+            ],
+            FeatureClass::Transit => &[
+                r#"way["railway"~"^(rail|subway|tram|light_rail)$"]"#,
+                r#"node["railway"~"^(station|halt|tram_stop)$"]"#,
+                r#"node["public_transport"]"#,
+            ],
+        }
+    }
+
+    /// Overpass filters selecting route relations of this class, fetched with only their stops.
+    /// Their ways can go on for hundreds of kilometres, and the parts in the chunk are fetched by
+    /// the other selectors anyway
+    fn route_selectors(&self) -> &'static [&'static str] {
+        match *self {
+            FeatureClass::Transit => &[
+                r#"relation["type"="route"]["route"~"^(bus|trolleybus|tram|train|subway|light_rail)$"]"#,
+            ],
+            _ => &[],
+        }
+    }
+}
+
+impl FeatureFilter {
+    /// An empty filter, which matches nothing
+    pub fn empty() -> Self {
+        FeatureFilter {
+            classes: Vec::new(),
+        }
+    }
+
+    pub fn with(mut self, class: FeatureClass) -> Self {
+        if !self.contains(class) {
+            self.classes.push(class);
+        }
+        self
+    }
+
+    pub fn without(mut self, class: FeatureClass) -> Self {
+        self.classes.retain(|c| *c != class);
+        self
+    }
+
+    pub fn contains(&self, class: FeatureClass) -> bool {
+        self.classes.contains(&class)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.classes.is_empty()
+    }
+
+    /// A hash of what the filter asks overpass for, regardless of the order classes were added
+    /// in. Unlike `Hash` it's the same across runs and builds, so it can be used to name cache
+    /// files, and it changes whenever the query for the filter does
+    pub fn cache_key(&self) -> String {
+        let mut sorted = self.clone();
+        sorted.classes.sort_by_key(|c| c.selectors());
+
+        // FNV-1a
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for b in statements(&sorted).bytes() {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        format!("{:016x}", hash)
+    }
+}
+
+impl Default for FeatureFilter {
+    /// Everything the parser understands
+    fn default() -> Self {
+        FeatureFilter::empty()
+            .with(FeatureClass::Highway)
+            .with(FeatureClass::LandUse)
+            .with(FeatureClass::Building)
+            .with(FeatureClass::PointOfInterest)
+            .with(FeatureClass::Transit)
+    }
+}
+
+/// Builds an overpass QL query for all ways, tagged nodes and relations in the given bounding box
+/// matching the filter, along with all their members and the nodes of those, and only the stops of
+/// routes. The output is OSM XML, the same as /api/map
+pub fn build_query(min_lat: f64, max_lat: f64, min_lon: f64, max_lon: f64, filter: &FeatureFilter, timeout: Option<Duration>) -> String {
+    let mut q = String::new();
+    let timeout = timeout.map(timeout_secs).unwrap_or(DEFAULT_QUERY_TIMEOUT);
+
+    // overpass bboxes are south, west, north, east
+    write!(q, "[out:xml][timeout:{}][bbox:{},{},{},{}];",
+           timeout, min_lat, min_lon, max_lat, max_lon).unwrap();
+
+    q.push_str(&statements(filter));
+    q
+}
+
+/// Overpass only takes whole seconds, and rejects a timeout of 0
+fn timeout_secs(t: Duration) -> u64 {
+    let secs = t.as_secs() + if t.subsec_nanos() > 0 { 1 } else { 0 };
+    secs.max(1)
+}
+
+/// The query after its settings, which only depends on the filter
+fn statements(filter: &FeatureFilter) -> String {
+    let mut q = String::from("(");
+    for class in &filter.classes {
+        for selector in class.selectors() {
+            write!(q, "{};", selector).unwrap();
+        }
+    }
+    q.push_str(")->.features;(");
+    for class in &filter.classes {
+        for selector in class.route_selectors() {
+            write!(q, "{};", selector).unwrap();
+        }
+    }
+    q.push_str(")->.routes;");
+
+    // recurse down to the members of each relation and the nodes of each way, but only to the
+    // stops of routes
+    q.push_str("(.features;.features >;.routes;");
+    for role in STOP_ROLES {
+        write!(q, r#"node(r.routes:"{}");"#, role).unwrap();
+    }
+    q.push_str(");out body;");
+    q
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(filter: &FeatureFilter) -> String {
+        build_query(51.5, 51.51, -0.12, -0.11, filter, Some(Duration::from_secs(10)))
+    }
+
+    #[test]
+    fn settings_and_bbox() {
+        let q = query(&FeatureFilter::default());
+        assert!(q.starts_with("[out:xml][timeout:10][bbox:51.5,-0.12,51.51,-0.11];"));
+        assert!(q.contains("(.features;.features >;.routes;"));
+        assert!(q.ends_with(");out body;"));
+    }
+
+    #[test]
+    fn default_timeout() {
+        let q = build_query(0.0, 1.0, 0.0, 1.0, &FeatureFilter::default(), None);
+        assert!(q.starts_with(&format!("[out:xml][timeout:{}]", DEFAULT_QUERY_TIMEOUT)));
+    }
+
+    #[test]
+    fn timeout_rounded_up() {
+        let q = |t| build_query(0.0, 1.0, 0.0, 1.0, &FeatureFilter::default(), Some(t));
+        assert!(q(Duration::from_millis(500)).starts_with("[out:xml][timeout:1]"));
+        assert!(q(Duration::from_millis(2100)).starts_with("[out:xml][timeout:3]"));
+        assert!(q(Duration::from_secs(0)).starts_with("[out:xml][timeout:1]"));
+    }
+
+    #[test]
+    fn only_filtered_classes() {
+        let q = query(&FeatureFilter::empty().with(FeatureClass::Highway));
+        assert!(q.contains(r#"(way["highway"];)->.features;"#));
+        assert!(q.contains("()->.routes;"));
+        assert!(!q.contains("landuse"));
+        assert!(!q.contains("building"));
+    }
+
+    #[test]
+    fn multipolygon_relations() {
+        let q = query(&FeatureFilter::empty().with(FeatureClass::LandUse).with(FeatureClass::Building));
+        assert!(q.contains(r#"way["landuse"];"#));
+        assert!(q.contains(r#"relation["landuse"]["type"="multipolygon"];"#));
+        assert!(q.contains(r#"relation["building"]["type"="multipolygon"];"#));
+    }
+
+    #[test]
+    fn only_stops_of_routes() {
+        let q = query(&FeatureFilter::empty().with(FeatureClass::Transit));
+        assert!(q.contains(r#"(relation["type"="route"]["route"~"^(bus|trolleybus|tram|train|subway|light_rail)$"];)->.routes;"#));
+        assert!(q.contains(r#"node(r.routes:"stop");"#));
+        assert!(!q.contains(".routes >"));
+    }
+
+    #[test]
+    fn empty_filter() {
+        let q = query(&FeatureFilter::empty());
+        assert!(q.contains("()->.features;()->.routes;"));
+    }
+
+    #[test]
+    fn cache_key_ignores_order() {
+        let a = FeatureFilter::empty().with(FeatureClass::Highway).with(FeatureClass::Building);
+        let b = FeatureFilter::empty().with(FeatureClass::Building).with(FeatureClass::Highway);
+        assert_eq!(a.cache_key(), b.cache_key());
+    }
+
+    #[test]
+    fn cache_key_differs_by_filter() {
+        let all = FeatureFilter::default();
+        let fewer = FeatureFilter::default().without(FeatureClass::Transit);
+        assert_ne!(all.cache_key(), fewer.cache_key());
+        assert_ne!(fewer.cache_key(), FeatureFilter::empty().cache_key());
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Sustained and burst request rate allowed by a `TokenBucket`
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Shared between all request threads, limiting how often requests can be sent regardless of
+/// how many are allowed to be in flight at once
+pub struct TokenBucket {
+    limit: RateLimit,
+    bucket: Mutex<Bucket>,
+}
+
+impl Default for RateLimit {
+    // overpass-api.de hands out a couple of slots per IP, so be gentle
+    fn default() -> Self {
+        RateLimit {
+            per_second: 0.5,
+            burst: 2,
+        }
+    }
+}
+
+impl RateLimit {
+    /// A bucket that never refills or holds no tokens would block forever, use no limit instead
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.per_second.is_finite() || self.per_second <= 0.0 {
+            return Err(format!("Rate limit must be positive, not {}", self.per_second));
+        }
+
+        if self.burst == 0 {
+            return Err(String::from("Rate limit burst must be at least 1"));
+        }
+
+        Ok(())
+    }
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            bucket: Mutex::new(Bucket {
+                tokens: f64::from(limit.burst),
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Blocks until a token is available, then takes it
+    pub fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                self.refill(&mut bucket);
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+
+                let secs = (1.0 - bucket.tokens) / self.limit.per_second;
+                Duration::from_millis((secs * 1000.0).ceil() as u64)
+            };
+
+            thread::sleep(wait);
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+
+        bucket.tokens = f64::min(
+            bucket.tokens + elapsed * self.limit.per_second,
+            f64::from(self.limit.burst),
+        );
+        bucket.last_refill = now;
+    }
+}
@@ -0,0 +1,60 @@
+use std::cmp;
+use std::time::Duration;
+use rand;
+
+/// How failed requests are retried, with exponential backoff and jitter
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Including the first attempt, so 1 disables retrying
+    pub max_attempts: u32,
+
+    /// Delay before the first retry, doubled for each one after
+    pub base_delay: Duration,
+
+    pub max_delay: Duration,
+
+    /// Also caps a server's Retry-After, in case it asks us to wait for an hour
+    pub max_retry_after: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_retry_after: Duration::from_secs(120),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Delay before the given retry, starting at 1. The server's Retry-After is honoured if given,
+    /// otherwise somewhere between half and all of the exponential backoff is used, so that
+    /// concurrent requests that failed together don't all retry together
+    pub fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return cmp::min(retry_after, self.max_retry_after);
+        }
+
+        let backoff = {
+            let shift = cmp::min(retry.saturating_sub(1), 16);
+            let millis = duration_millis(self.base_delay).saturating_mul(1 << shift);
+            cmp::min(millis, duration_millis(self.max_delay))
+        };
+
+        let jitter = (rand::random::<f64>() * (backoff / 2) as f64) as u64;
+        Duration::from_millis(backoff / 2 + jitter)
+    }
+}
+
+fn duration_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_nanos() / 1_000_000)
+}
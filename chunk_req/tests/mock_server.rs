@@ -0,0 +1,110 @@
+extern crate chunk_req;
+
+use std::time::Duration;
+use std::path::PathBuf;
+use chunk_req::mock::{MockServer, Response};
+use chunk_req::{Client, ClientConfig, FeatureFilter, RequestError, RetryPolicy};
+
+const TOP_LEFT: (f64, f64) = (51.5065, -0.12);
+const BOTTOM_RIGHT: (f64, f64) = (51.5, -0.1095);
+
+fn fixture_path() -> PathBuf {
+    let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    p.push("tests");
+    p.push("fixtures");
+    p.push("chunk.osm");
+    p
+}
+
+fn client_for(server: &MockServer) -> Client {
+    let config = ClientConfig {
+        base_url: server.base_url(),
+        timeout: Some(Duration::from_secs(2)),
+        retry: RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            max_retry_after: Duration::from_millis(100),
+        },
+        rate_limit: None,
+        ..Default::default()
+    };
+    Client::new(config).unwrap()
+}
+
+fn request(client: &Client) -> Result<String, chunk_req::RequestFailure> {
+    client.request_osm(TOP_LEFT, BOTTOM_RIGHT, &FeatureFilter::default())
+}
+
+#[test]
+fn serves_fixture_for_bbox() {
+    let server = MockServer::start().unwrap();
+    server.add_fixture_file(Some((BOTTOM_RIGHT.0, TOP_LEFT.1, TOP_LEFT.0, BOTTOM_RIGHT.1)), fixture_path()).unwrap();
+
+    let xml = request(&client_for(&server)).unwrap();
+    assert!(xml.contains("Test Street"));
+
+    let queries = server.queries();
+    assert_eq!(queries.len(), 1);
+    assert!(queries[0].contains("way[\"highway\"]"));
+}
+
+#[test]
+fn missing_fixture_is_not_retried() {
+    let server = MockServer::start().unwrap();
+
+    let failure = request(&client_for(&server)).unwrap_err();
+    match failure.error {
+        RequestError::Status(404, _) => {}
+        e => panic!("unexpected error {:?}", e),
+    }
+    assert_eq!(failure.attempts, 1);
+}
+
+#[test]
+fn retries_after_server_errors() {
+    let server = MockServer::start().unwrap();
+    server.add_fixture_file(None, fixture_path()).unwrap();
+    server.push_response(Response::Status(504));
+    server.push_response(Response::RateLimited(Some(0)));
+
+    assert!(request(&client_for(&server)).is_ok());
+    assert_eq!(server.request_count(), 3);
+}
+
+#[test]
+fn gives_up_after_max_attempts() {
+    let server = MockServer::start().unwrap();
+    server.set_default_response(Response::RateLimited(None));
+
+    let failure = request(&client_for(&server)).unwrap_err();
+    match failure.error {
+        RequestError::Status(429, _) => {}
+        e => panic!("unexpected error {:?}", e),
+    }
+    assert_eq!(failure.attempts, 3);
+    assert_eq!(server.request_count(), 3);
+}
+
+#[test]
+fn slow_response_times_out() {
+    let server = MockServer::start().unwrap();
+    server.add_fixture_file(None, fixture_path()).unwrap();
+    server.set_default_response(Response::Slow(Duration::from_secs(5)));
+
+    let client = {
+        let config = ClientConfig {
+            base_url: server.base_url(),
+            timeout: Some(Duration::from_millis(200)),
+            retry: RetryPolicy::none(),
+            rate_limit: None,
+            ..Default::default()
+        };
+        Client::new(config).unwrap()
+    };
+
+    match request(&client).unwrap_err().error {
+        RequestError::Transport(_) => {}
+        e => panic!("unexpected error {:?}", e),
+    }
+}
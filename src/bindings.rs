@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use sfml::window::Key;
+use serde_json;
+
+use error::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    PanUp,
+    PanDown,
+    PanLeft,
+    PanRight,
+    ZoomIn,
+    ZoomOut,
+    Home,
+    ToggleLoading,
+    ToggleHud,
+    RetryFailed,
+    Quit,
+}
+
+/// Maps keys to actions, any number of keys per action
+pub struct Bindings {
+    keys: Vec<(Key, Action)>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let keys = vec![
+            (Key::W, Action::PanUp),
+            (Key::Up, Action::PanUp),
+            (Key::S, Action::PanDown),
+            (Key::Down, Action::PanDown),
+            (Key::A, Action::PanLeft),
+            (Key::Left, Action::PanLeft),
+            (Key::D, Action::PanRight),
+            (Key::Right, Action::PanRight),
+            (Key::E, Action::ZoomIn),
+            (Key::Q, Action::ZoomOut),
+            (Key::Home, Action::Home),
+            (Key::Space, Action::ToggleLoading),
+            (Key::F3, Action::ToggleHud),
+            (Key::R, Action::RetryFailed),
+            (Key::Escape, Action::Quit),
+        ];
+        Bindings { keys }
+    }
+}
+
+impl Bindings {
+    /// Loads a JSON object of action names to lists of key names, e.g. {"zoom_in": ["E", "Add"]}.
+    /// Actions missing from the file keep their default keys
+    pub fn load(path: &Path) -> SimResult<Self> {
+        let map: HashMap<String, Vec<String>> = serde_json::from_reader(fs::File::open(path)?)?;
+        let mut bindings = Bindings::default();
+
+        for (name, keys) in map {
+            let action = action_from_name(&name)
+                .ok_or_else(|| ErrorKind::BadBinding(format!("unknown action '{}'", name)))?;
+
+            bindings.keys.retain(|&(_, a)| a != action);
+            for key in keys {
+                let key = key_from_name(&key)
+                    .ok_or_else(|| ErrorKind::BadBinding(format!("unknown key '{}'", key)))?;
+                bindings.keys.push((key, action));
+            }
+        }
+
+        Ok(bindings)
+    }
+
+    pub fn action(&self, key: Key) -> Option<Action> {
+        self.keys.iter()
+            .find(|&&(k, _)| k == key)
+            .map(|&(_, a)| a)
+    }
+}
+
+fn action_from_name(name: &str) -> Option<Action> {
+    Some(match name {
+        "pan_up" => Action::PanUp,
+        "pan_down" => Action::PanDown,
+        "pan_left" => Action::PanLeft,
+        "pan_right" => Action::PanRight,
+        "zoom_in" => Action::ZoomIn,
+        "zoom_out" => Action::ZoomOut,
+        "home" => Action::Home,
+        "toggle_loading" => Action::ToggleLoading,
+        "toggle_hud" => Action::ToggleHud,
+        "retry_failed" => Action::RetryFailed,
+        "quit" => Action::Quit,
+        _ => return None,
+    })
+}
+
+fn key_from_name(name: &str) -> Option<Key> {
+    const LETTERS: [Key; 26] = [
+        Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K,
+        Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V,
+        Key::W, Key::X, Key::Y, Key::Z,
+    ];
+    const DIGITS: [Key; 10] = [
+        Key::Num0, Key::Num1, Key::Num2, Key::Num3, Key::Num4, Key::Num5, Key::Num6, Key::Num7,
+        Key::Num8, Key::Num9,
+    ];
+
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        let c = c.to_ascii_uppercase();
+        if c >= 'A' && c <= 'Z' {
+            return Some(LETTERS[(c as u8 - b'A') as usize]);
+        }
+        if c >= '0' && c <= '9' {
+            return Some(DIGITS[(c as u8 - b'0') as usize]);
+        }
+    }
+
+    Some(match name {
+        "Up" => Key::Up,
+        "Down" => Key::Down,
+        "Left" => Key::Left,
+        "Right" => Key::Right,
+        "Space" => Key::Space,
+        "Escape" => Key::Escape,
+        "Home" => Key::Home,
+        "End" => Key::End,
+        "PageUp" => Key::PageUp,
+        "PageDown" => Key::PageDown,
+        "Add" => Key::Add,
+        "Subtract" => Key::Subtract,
+        "Tab" => Key::Tab,
+        "F1" => Key::F1,
+        "F2" => Key::F2,
+        "F3" => Key::F3,
+        "F4" => Key::F4,
+        "F5" => Key::F5,
+        "F6" => Key::F6,
+        "F7" => Key::F7,
+        "F8" => Key::F8,
+        "F9" => Key::F9,
+        "F10" => Key::F10,
+        "F11" => Key::F11,
+        "F12" => Key::F12,
+        _ => return None,
+    })
+}
@@ -0,0 +1,288 @@
+use sfml::graphics::*;
+use sfml::window::*;
+use sfml::system::*;
+
+use bindings::Action;
+
+/// Keyboard panning speed in screen pixels per frame, so it feels the same at any zoom
+const MOVE_SPEED: f64 = 10.0;
+
+/// Multiplier per frame while a zoom key is held
+const KEY_ZOOM_RATE: f64 = 1.03;
+
+/// Multiplier per notch of the mouse wheel
+const WHEEL_ZOOM_RATE: f64 = 1.15;
+
+/// Closest zoom, about a single street across
+const MIN_ZOOM: f64 = 0.05;
+
+/// Furthest zoom
+const MAX_ZOOM: f64 = 200.0;
+
+/// Beyond this many chunks across the window's longer side, no new chunks are fetched, so a
+/// zoomed out view doesn't flood overpass and the loader
+const MAX_FETCH_CHUNKS_ACROSS: f64 = 6.0;
+
+/// Fraction of velocity kept each frame after letting go of a drag
+const FRICTION: f64 = 0.9;
+
+/// Below this speed in screen pixels per frame, inertia stops entirely
+const MIN_SPEED: f64 = 0.1;
+
+#[derive(Debug, Default)]
+struct Held {
+    up: bool,
+    down: bool,
+    left: bool,
+    right: bool,
+    zoom_in: bool,
+    zoom_out: bool,
+}
+
+#[derive(Debug)]
+pub struct CameraChange {
+    /// Current velocity in world pixels per frame
+    x: f64,
+    y: f64,
+    w: u32,
+    h: u32,
+
+    /// World pixels per screen pixel
+    pub z: f64,
+
+    held: Held,
+
+    /// Last cursor position while the mouse button is held down
+    drag_from: Option<Vector2i>,
+
+    /// World pixels dragged since the last apply
+    drag_delta: (f64, f64),
+
+    /// Accumulated wheel zoom, and the cursor position to zoom around
+    wheel_zoom: Option<(f64, Vector2i)>,
+
+    /// Centre to jump to on the next apply, in world pixels
+    teleport: Option<Vector2f>,
+
+    pub min_chunk: (i32, i32),
+    pub max_chunk: (i32, i32),
+    chunk_changes: Vec<ChunkChange>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ChunkChange {
+    pub x: i32,
+    pub y: i32,
+    pub load: bool,
+}
+
+impl ChunkChange {
+    fn new(x: i32, y: i32, load: bool) -> Self {
+        ChunkChange { x, y, load }
+    }
+}
+
+impl CameraChange {
+    pub fn new(window_size: Vector2u, initial_zoom: f64) -> Self {
+        CameraChange {
+            x: 0.0,
+            y: 0.0,
+            w: window_size.x,
+            h: window_size.y,
+            z: initial_zoom,
+            held: Held::default(),
+            drag_from: None,
+            drag_delta: (0.0, 0.0),
+            wheel_zoom: None,
+            teleport: None,
+            min_chunk: (0, 0),
+            max_chunk: (0, 0),
+            chunk_changes: Vec::new(),
+        }
+    }
+
+    pub fn handle_action(&mut self, action: Action, pressed: bool) {
+        match action {
+            Action::PanUp => self.held.up = pressed,
+            Action::PanDown => self.held.down = pressed,
+            Action::PanLeft => self.held.left = pressed,
+            Action::PanRight => self.held.right = pressed,
+            Action::ZoomIn => self.held.zoom_in = pressed,
+            Action::ZoomOut => self.held.zoom_out = pressed,
+            _ => (),
+        }
+    }
+
+    pub fn start_drag(&mut self, x: i32, y: i32) {
+        self.drag_from = Some(Vector2i::new(x, y));
+    }
+
+    pub fn end_drag(&mut self) {
+        // keep the velocity from the last frame of the drag to carry on gliding
+        self.drag_from = None;
+    }
+
+    pub fn mouse_moved(&mut self, x: i32, y: i32) {
+        if let Some(from) = self.drag_from {
+            // the world moves with the cursor, so the view moves the opposite way
+            self.drag_delta.0 -= f64::from(x - from.x) * self.z;
+            self.drag_delta.1 -= f64::from(y - from.y) * self.z;
+            self.drag_from = Some(Vector2i::new(x, y));
+        }
+    }
+
+    /// Zooms in for positive deltas, keeping the world point under the cursor where it is
+    pub fn scroll(&mut self, delta: f32, x: i32, y: i32) {
+        let factor = WHEEL_ZOOM_RATE.powf(-f64::from(delta));
+        let total = self.wheel_zoom.map(|(f, _)| f).unwrap_or(1.0) * factor;
+        self.wheel_zoom = Some((total, Vector2i::new(x, y)));
+    }
+
+    /// Unit vector of the current panning, or zero if stationary
+    pub fn pan_direction(&self) -> (f64, f64) {
+        let len = (self.x * self.x + self.y * self.y).sqrt();
+        if len > 0.0 {
+            (self.x / len, self.y / len)
+        } else {
+            (0.0, 0.0)
+        }
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.w = width;
+        self.h = height;
+    }
+
+    /// Centres the view on the given world pixel on the next apply, however far away it is
+    pub fn teleport(&mut self, centre: Vector2f) {
+        self.teleport = Some(centre);
+        self.x = 0.0;
+        self.y = 0.0;
+    }
+
+    /// Chunks across the window's longer side at the current zoom
+    fn chunks_across(&self, chunk_size: Vector2i) -> f64 {
+        f64::max(
+            f64::from(self.w) * self.z / f64::from(chunk_size.x),
+            f64::from(self.h) * self.z / f64::from(chunk_size.y),
+        )
+    }
+
+    pub fn apply(&mut self, window: &mut RenderWindow, chunk_size: Vector2i) -> &Vec<ChunkChange> {
+        let mut view = window.view().to_owned();
+
+        // zoom, around the cursor for the wheel and the centre of the screen for keys
+        {
+            let (mut factor, anchor) = match self.wheel_zoom.take() {
+                Some((f, anchor)) => (f, Some(anchor)),
+                None => (1.0, None),
+            };
+            if self.held.zoom_in {
+                factor /= KEY_ZOOM_RATE;
+            }
+            if self.held.zoom_out {
+                factor *= KEY_ZOOM_RATE;
+            }
+
+            let anchored_before = anchor.map(|a| window.map_pixel_to_coords(&a, &view));
+
+            self.z = f64::min(f64::max(self.z * factor, MIN_ZOOM), MAX_ZOOM);
+            view.set_size(Vector2f::new(self.w as f32 * self.z as f32, self.h as f32 * self.z as f32));
+
+            if let (Some(anchor), Some(before)) = (anchor, anchored_before) {
+                let after = window.map_pixel_to_coords(&anchor, &view);
+                view.move_((before.x - after.x, before.y - after.y));
+            }
+        }
+
+        if let Some(centre) = self.teleport.take() {
+            view.set_center(centre);
+        }
+
+        // pan
+        {
+            let (dx, dy) = {
+                let dir = |neg: bool, pos: bool| match (neg, pos) {
+                    (true, false) => -1.0,
+                    (false, true) => 1.0,
+                    _ => 0.0,
+                };
+                (dir(self.held.left, self.held.right), dir(self.held.up, self.held.down))
+            };
+
+            if self.drag_from.is_some() {
+                self.x = self.drag_delta.0;
+                self.y = self.drag_delta.1;
+            } else if dx != 0.0 || dy != 0.0 {
+                let len = (dx * dx + dy * dy).sqrt();
+                self.x = dx / len * MOVE_SPEED * self.z;
+                self.y = dy / len * MOVE_SPEED * self.z;
+            } else {
+                // glide to a stop
+                self.x *= FRICTION;
+                self.y *= FRICTION;
+
+                let speed = (self.x * self.x + self.y * self.y).sqrt() / self.z;
+                if speed < MIN_SPEED {
+                    self.x = 0.0;
+                    self.y = 0.0;
+                }
+            }
+
+            self.drag_delta = (0.0, 0.0);
+            view.move_((self.x as f32, self.y as f32));
+        }
+
+        window.set_view(&view);
+
+        // chunks visible
+        {
+            self.chunk_changes.clear();
+
+            // keep what's already loaded, and carry on from the last fetched rectangle once
+            // zoomed back in
+            if self.chunks_across(chunk_size) > MAX_FETCH_CHUNKS_ACROSS {
+                return &self.chunk_changes;
+            }
+
+            let tl = window.map_pixel_to_coords(&Vector2i::new(0, 0), &view);
+            let br = {
+                let win_size = window.size();
+                let size_i = Vector2i::new(win_size.x as i32, win_size.y as i32);
+                window.map_pixel_to_coords(&size_i, &view)
+            };
+
+            let min = (
+                (tl.x /  chunk_size.x as f32).floor() as i32,
+                (tl.y /  chunk_size.y as f32).floor() as i32,
+            );
+            let max = (
+                (br.x /  chunk_size.x as f32).floor() as i32,
+                (br.y /  chunk_size.y as f32).floor() as i32,
+            );
+
+            // the old and new visible rectangles can be any size and overlap by any amount, or
+            // not at all
+            let (old_min, old_max) = (self.min_chunk, self.max_chunk);
+            diff_rects(min, max, old_min, old_max, true, &mut self.chunk_changes);
+            diff_rects(old_min, old_max, min, max, false, &mut self.chunk_changes);
+
+            self.min_chunk = min;
+            self.max_chunk = max;
+            &self.chunk_changes
+        }
+    }
+}
+
+/// Pushes a change for every chunk in the inclusive rectangle a that isn't in b
+fn diff_rects(a_min: (i32, i32), a_max: (i32, i32), b_min: (i32, i32), b_max: (i32, i32), load: bool, out: &mut Vec<ChunkChange>) {
+    let in_b = |x: i32, y: i32| x >= b_min.0 && x <= b_max.0 && y >= b_min.1 && y <= b_max.1;
+
+    for x in a_min.0..a_max.0 + 1 {
+        for y in a_min.1..a_max.1 + 1 {
+            if !in_b(x, y) {
+                out.push(ChunkChange::new(x, y, load));
+            }
+        }
+    }
+}
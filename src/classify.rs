@@ -0,0 +1,188 @@
+use parser::{BuildingType, LandUseType, PartialWorld, RoadType};
+use tags::Tags;
+
+/// Bumped whenever the rules change, so cached chunks are classified again when loaded
+pub const VERSION: u32 = 2;
+
+/// The first rule whose key and value globs match a tag wins
+const ROAD_RULES: &[(&str, &str, RoadType)] = &[
+    ("highway", "motorway", RoadType::Motorway),
+    ("highway", "motorway_link", RoadType::MotorwayLink),
+    ("highway", "trunk", RoadType::Trunk),
+    ("highway", "trunk_link", RoadType::TrunkLink),
+    ("highway", "primary", RoadType::Primary),
+    ("highway", "primary_link", RoadType::PrimaryLink),
+    ("highway", "secondary", RoadType::Secondary),
+    ("highway", "secondary_link", RoadType::SecondaryLink),
+    ("highway", "tertiary", RoadType::Tertiary),
+    ("highway", "tertiary_link", RoadType::TertiaryLink),
+    ("highway", "unclassified", RoadType::Minor),
+    ("highway", "road", RoadType::Minor),
+    ("highway", "residential", RoadType::Residential),
+    ("highway", "living_street", RoadType::LivingStreet),
+    ("highway", "service", RoadType::Service),
+    ("highway", "track", RoadType::Track),
+    ("highway", "pedestrian", RoadType::Pedestrian),
+    ("highway", "footway", RoadType::Footway),
+    ("highway", "path", RoadType::Footway),
+    ("highway", "bridleway", RoadType::Footway),
+    ("highway", "cycleway", RoadType::Cycleway),
+    ("highway", "steps", RoadType::Steps),
+];
+
+/// Specific tags before general ones, e.g. a school's grounds are often also landuse=residential
+const LAND_USE_RULES: &[(&str, &str, LandUseType)] = &[
+    ("amenity", "school", LandUseType::Education),
+    ("amenity", "college", LandUseType::Education),
+    ("amenity", "university", LandUseType::Education),
+    ("amenity", "kindergarten", LandUseType::Education),
+    ("amenity", "grave_yard", LandUseType::Cemetery),
+    ("military", "*", LandUseType::Military),
+    ("landuse", "residential", LandUseType::Residential),
+    ("landuse", "commercial", LandUseType::Commercial),
+    ("landuse", "retail", LandUseType::Retail),
+    ("landuse", "industrial", LandUseType::Industrial),
+    ("landuse", "brownfield", LandUseType::Brownfield),
+    ("landuse", "construction", LandUseType::Brownfield),
+    ("landuse", "military", LandUseType::Military),
+    ("landuse", "education", LandUseType::Education),
+    ("landuse", "cemetery", LandUseType::Cemetery),
+    ("landuse", "railway", LandUseType::Railway),
+    ("landuse", "farm*", LandUseType::Agriculture),
+    ("landuse", "orchard", LandUseType::Agriculture),
+    ("landuse", "vineyard", LandUseType::Agriculture),
+    ("landuse", "allotments", LandUseType::Agriculture),
+    ("landuse", "forest", LandUseType::Forest),
+    ("landuse", "recreation_ground", LandUseType::Park),
+    ("landuse", "village_green", LandUseType::Park),
+    ("landuse", "grass", LandUseType::Green),
+    ("landuse", "meadow", LandUseType::Green),
+    ("landuse", "reservoir", LandUseType::Water),
+    ("landuse", "basin", LandUseType::Water),
+    ("leisure", "park", LandUseType::Park),
+    ("leisure", "garden", LandUseType::Park),
+    ("leisure", "playground", LandUseType::Park),
+    ("leisure", "pitch", LandUseType::Park),
+    ("leisure", "golf_course", LandUseType::Park),
+    ("leisure", "nature_reserve", LandUseType::Green),
+    ("natural", "wood", LandUseType::Forest),
+    ("natural", "water", LandUseType::Water),
+    ("natural", "wetland", LandUseType::Wetland),
+    ("natural", "scrub", LandUseType::Green),
+    ("natural", "heath", LandUseType::Green),
+    ("natural", "grassland", LandUseType::Green),
+];
+
+/// What's inside before what the building looks like, e.g. a school is often building=yes
+const BUILDING_RULES: &[(&str, &str, BuildingType)] = &[
+    ("amenity", "school", BuildingType::Education),
+    ("amenity", "college", BuildingType::Education),
+    ("amenity", "university", BuildingType::Education),
+    ("amenity", "kindergarten", BuildingType::Education),
+    ("amenity", "hospital", BuildingType::Healthcare),
+    ("amenity", "clinic", BuildingType::Healthcare),
+    ("amenity", "place_of_worship", BuildingType::Religious),
+    ("amenity", "townhall", BuildingType::Civic),
+    ("amenity", "fire_station", BuildingType::Civic),
+    ("amenity", "police", BuildingType::Civic),
+    ("amenity", "library", BuildingType::Civic),
+    ("shop", "*", BuildingType::Retail),
+    ("office", "*", BuildingType::Commercial),
+    ("building", "house", BuildingType::Residential),
+    ("building", "detached", BuildingType::Residential),
+    ("building", "semidetached_house", BuildingType::Residential),
+    ("building", "terrace", BuildingType::Residential),
+    ("building", "bungalow", BuildingType::Residential),
+    ("building", "apartments", BuildingType::Residential),
+    ("building", "residential", BuildingType::Residential),
+    ("building", "dormitory", BuildingType::Residential),
+    ("building", "farm", BuildingType::Residential),
+    ("building", "commercial", BuildingType::Commercial),
+    ("building", "office", BuildingType::Commercial),
+    ("building", "hotel", BuildingType::Commercial),
+    ("building", "retail", BuildingType::Retail),
+    ("building", "supermarket", BuildingType::Retail),
+    ("building", "kiosk", BuildingType::Retail),
+    ("building", "industrial", BuildingType::Industrial),
+    ("building", "warehouse", BuildingType::Industrial),
+    ("building", "factory", BuildingType::Industrial),
+    ("building", "school", BuildingType::Education),
+    ("building", "university", BuildingType::Education),
+    ("building", "college", BuildingType::Education),
+    ("building", "kindergarten", BuildingType::Education),
+    ("building", "hospital", BuildingType::Healthcare),
+    ("building", "church", BuildingType::Religious),
+    ("building", "cathedral", BuildingType::Religious),
+    ("building", "chapel", BuildingType::Religious),
+    ("building", "mosque", BuildingType::Religious),
+    ("building", "synagogue", BuildingType::Religious),
+    ("building", "temple", BuildingType::Religious),
+    ("building", "religious", BuildingType::Religious),
+    ("building", "civic", BuildingType::Civic),
+    ("building", "public", BuildingType::Civic),
+    ("building", "government", BuildingType::Civic),
+    ("building", "train_station", BuildingType::Transport),
+    ("building", "transportation", BuildingType::Transport),
+    ("building", "parking", BuildingType::Transport),
+    ("building", "hangar", BuildingType::Transport),
+    ("building", "barn", BuildingType::Agricultural),
+    ("building", "farm_auxiliary", BuildingType::Agricultural),
+    ("building", "greenhouse", BuildingType::Agricultural),
+    ("building", "stable", BuildingType::Agricultural),
+    ("building", "cowshed", BuildingType::Agricultural),
+    ("building", "garage", BuildingType::Outbuilding),
+    ("building", "garages", BuildingType::Outbuilding),
+    ("building", "shed", BuildingType::Outbuilding),
+    ("building", "carport", BuildingType::Outbuilding),
+    ("building", "hut", BuildingType::Outbuilding),
+    ("building", "roof", BuildingType::Outbuilding),
+];
+
+fn first_match<T: Copy>(rules: &[(&str, &str, T)], tags: &Tags) -> Option<T> {
+    rules.iter()
+        .find(|&&(key, value, _)| tags.matches(key, value))
+        .map(|&(_, _, class)| class)
+}
+
+/// None if the tags don't say, in which case the parser's classification is used
+pub fn road_type(tags: &Tags) -> Option<RoadType> {
+    first_match(ROAD_RULES, tags)
+}
+
+pub fn land_use_type(tags: &Tags) -> Option<LandUseType> {
+    first_match(LAND_USE_RULES, tags)
+}
+
+pub fn building_type(tags: &Tags) -> Option<BuildingType> {
+    first_match(BUILDING_RULES, tags)
+}
+
+/// Brings a chunk classified by an older version up to date, returning false if it already was.
+/// Features cached with their tags are classified again, and those without keep their old class,
+/// which has the same name in every version
+pub fn upgrade(world: &mut PartialWorld) -> bool {
+    if world.classification >= VERSION {
+        return false;
+    }
+
+    for r in world.roads.values_mut() {
+        if let Some(road_type) = road_type(&r.tags) {
+            r.road_type = road_type;
+        }
+    }
+
+    for lu in world.land_uses.values_mut() {
+        if let Some(land_use_type) = land_use_type(&lu.tags) {
+            lu.land_use_type = land_use_type;
+        }
+    }
+
+    for b in world.buildings.values_mut() {
+        if let Some(building_type) = building_type(&b.tags) {
+            b.building_type = building_type;
+        }
+    }
+
+    world.classification = VERSION;
+    true
+}
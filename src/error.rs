@@ -2,6 +2,9 @@ use std::io;
 use std::ffi;
 use std::sync;
 use serde_json;
+use zip;
+use chunk_req;
+use parser::ParseIssue;
 
 error_chain! {
 
@@ -14,6 +17,7 @@ error_chain! {
         Ffi(ffi::NulError);
         Sync(sync::mpsc::RecvError);
         Deserialize(serde_json::Error);
+        Zip(zip::result::ZipError);
     }
 
     errors {
@@ -21,12 +25,49 @@ error_chain! {
                 display("the chunk {:?} is already loaded", pos)
             }
 
+            ChunkRequestCancelled(pos: (i32, i32)) {
+                display("the request for chunk {:?} was cancelled", pos)
+            }
+
+            ChunkLoadFailed(pos: (i32, i32), attempts: u32, reason: String) {
+                display("loading chunk {:?} failed after {} attempts: {}", pos, attempts, reason)
+            }
+
+            BadBinding(reason: String) {
+                display("bad key binding: {}", reason)
+            }
+
+            BadLogConfig(reason: String) {
+                display("bad log config: {}", reason)
+            }
+
+            BadStyle(reason: String) {
+                display("bad map style: {}", reason)
+            }
+
             OsmRequest(reason: String) {
                 display("osm request failed: {}", reason)
             }
 
-            OsmParse(err: i32) {
-                display("failed to parse osm with err code {}", err)
+            OsmHttpStatus(status: u16, attempts: u32) {
+                display("osm request failed with status {} after {} attempts", status, attempts)
+            }
+
+            OsmParse(issue: ParseIssue) {
+                display("failed to parse osm: {}", issue)
+            }
+
+            BadGtfs(reason: String) {
+                display("bad gtfs feed: {}", reason)
             }
     }
 }
+
+impl From<chunk_req::RequestFailure> for Error {
+    fn from(failure: chunk_req::RequestFailure) -> Self {
+        match failure.error {
+            chunk_req::RequestError::Status(status, _) => ErrorKind::OsmHttpStatus(status, failure.attempts).into(),
+            _ => ErrorKind::OsmRequest(failure.to_string()).into(),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use sfml::graphics::*;
+use sfml::system::*;
+
+use std::f64::consts::PI;
+
+use world::{Area, Feature, Point, PointOfInterest, Road, TransitLine, World};
+use parser::RoadType;
+use stroke::{self, Join};
+use style::{parse_colour, AreaStyle, LineStyle, PoiStyle, RoadStyle, Style};
+use simplify::{self, ChunkLod};
+use latlon;
+
+/// Width of a single lane, for roads that say how many they have
+const LANE_METRES: f64 = 3.5;
+
+/// Zoom levels per doubling at which road geometry is rebuilt
+const ZOOM_BUCKETS_PER_OCTAVE: f64 = 2.0;
+
+/// Corners of a circle glyph
+const CIRCLE_SEGMENTS: usize = 12;
+
+/// Drawn in this order across all chunks, so roads are never hidden under a neighbouring chunk's
+/// land use
+#[derive(Debug, Clone, Copy)]
+pub enum Layer {
+    LandUse,
+    LandUseOutline,
+    Building,
+    BuildingOutline,
+    RailwayCasing,
+    Railway,
+    RoadCasing,
+    Road,
+    TransitLine,
+    PointOfInterest,
+}
+
+pub const LAYERS: [Layer; 10] = [
+    Layer::LandUse,
+    Layer::LandUseOutline,
+    Layer::Building,
+    Layer::BuildingOutline,
+    Layer::RailwayCasing,
+    Layer::Railway,
+    Layer::RoadCasing,
+    Layer::Road,
+    Layer::TransitLine,
+    Layer::PointOfInterest,
+];
+
+/// Shape a point of interest is drawn as
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Glyph {
+    Circle,
+    Square,
+    Diamond,
+    Triangle,
+    Cross,
+}
+
+/// Vertices for a set of features, built once and drawn with one call per layer
+#[derive(Default)]
+pub struct ChunkGeometry {
+    land_uses: Vec<Vertex>,
+    land_use_outlines: Vec<Vertex>,
+    buildings: Vec<Vertex>,
+    building_outlines: Vec<Vertex>,
+    railway_casings: Vec<Vertex>,
+    railways: Vec<Vertex>,
+    road_casings: Vec<Vertex>,
+    roads: Vec<Vertex>,
+    transit_lines: Vec<Vertex>,
+    pois: Vec<Vertex>,
+}
+
+/// Road widths depend on the zoom, so geometry is only rebuilt when this changes rather than on
+/// every frame of a zoom
+pub fn zoom_bucket(z: f64) -> f64 {
+    2f64.powf((z.log2() * ZOOM_BUCKETS_PER_OCTAVE).round() / ZOOM_BUCKETS_PER_OCTAVE)
+}
+
+impl ChunkGeometry {
+    /// Features that have since been unloaded are skipped. `zoom` is in world pixels per screen
+    /// pixel, see `zoom_bucket`, and picks the level of detail from `lod`
+    pub fn build(world: &World, features: &[Feature], zoom: f64, style: &Style, lod: &ChunkLod) -> Self {
+        let mut geom = ChunkGeometry::default();
+        let level = simplify::level_for_zoom(zoom);
+        let mut roads = Vec::new();
+        let mut railways = Vec::new();
+        let mut land_uses = Vec::new();
+
+        for &feature in features {
+            match feature {
+                Feature::Road(id) => if let Some(r) = world.loaded_roads.get(&id) {
+                    let road_style = style.road(r.road_type);
+                    if road_style.zoom_range.contains(zoom) {
+                        let points = lod.road(id, level).unwrap_or(&r.segments[..]);
+                        roads.push((r, points, road_style));
+                    }
+                },
+                Feature::LandUse(id) => if let Some(lu) = world.loaded_land_uses.get(&id) {
+                    let area_style = style.land_use(lu.land_use_type);
+                    if area_style.zoom_range.contains(zoom) {
+                        land_uses.push((feature, lu, area_style));
+                    }
+                },
+                Feature::Building(id) => if let Some(b) = world.loaded_buildings.get(&id) {
+                    let area_style = style.building(b.building_type);
+                    if area_style.zoom_range.contains(zoom) {
+                        let (fill, outline) = (&mut geom.buildings, &mut geom.building_outlines);
+                        match lod.area(feature, level) {
+                            Some(simplified) => push_area(fill, outline, simplified, area_style, zoom),
+                            None => push_area(fill, outline, b, area_style, zoom),
+                        }
+                    }
+                },
+                Feature::PointOfInterest(id) => if let Some(p) = world.loaded_pois.get(&id) {
+                    let poi_style = style.poi(p.category);
+                    if poi_style.zoom_range.contains(zoom) {
+                        push_glyph(&mut geom.pois, p, poi_style, zoom);
+                    }
+                },
+                Feature::Railway(id) => if let Some(r) = world.loaded_railways.get(&id) {
+                    let railway_style = style.railway(r.kind);
+                    if railway_style.zoom_range.contains(zoom) {
+                        railways.push((&r.points, railway_style));
+                    }
+                },
+                Feature::TransitLine(id) => if let Some(l) = world.loaded_transit_lines.get(&id) {
+                    let line_style = style.transit_line(l.mode);
+                    if line_style.zoom_range.contains(zoom) {
+                        push_transit_line(&mut geom.transit_lines, l, line_style, zoom);
+                    }
+                },
+            }
+        }
+
+        land_uses.sort_by_key(|&(_, _, s)| s.z_order);
+        for (feature, lu, area_style) in land_uses {
+            let (fill, outline) = (&mut geom.land_uses, &mut geom.land_use_outlines);
+            match lod.area(feature, level) {
+                Some(simplified) => push_area(fill, outline, simplified, area_style, zoom),
+                None => push_area(fill, outline, lu, area_style, zoom),
+            }
+        }
+
+        let pixels_per_metre = latlon::pixels_per_metre(&world.origin);
+
+        railways.sort_by_key(|&(_, s)| s.z_order);
+        for (points, railway_style) in railways {
+            let (width, casing_width) = line_widths(railway_style.width, railway_style, pixels_per_metre, zoom);
+            if let (Some(casing_width), Some(casing)) = (casing_width, railway_style.casing) {
+                stroke::stroke(&mut geom.railway_casings, points, casing_width as f32, railway_style.join, casing.0);
+            }
+            stroke::stroke(&mut geom.railways, points, width as f32, railway_style.join, railway_style.colour.0);
+        }
+
+        // major roads over minor ones where they cross
+        roads.sort_by_key(|&(_, _, s)| s.z_order);
+        for (r, points, road_style) in roads {
+            let (width, casing_width) = road_widths(r, road_style, pixels_per_metre, zoom);
+            if let (Some(casing_width), Some(casing)) = (casing_width, road_style.casing) {
+                stroke::stroke(&mut geom.road_casings, points, casing_width as f32, road_style.join, casing.0);
+            }
+            stroke::stroke(&mut geom.roads, points, width as f32, road_style.join, road_style.colour.0);
+        }
+
+        geom
+    }
+
+    pub fn draw(&self, target: &mut RenderTarget, layer: Layer) {
+        let (vertices, primitive) = match layer {
+            Layer::LandUse => (&self.land_uses, PrimitiveType::Triangles),
+            Layer::LandUseOutline => (&self.land_use_outlines, PrimitiveType::Triangles),
+            Layer::Building => (&self.buildings, PrimitiveType::Triangles),
+            Layer::BuildingOutline => (&self.building_outlines, PrimitiveType::Triangles),
+            Layer::RailwayCasing => (&self.railway_casings, PrimitiveType::Triangles),
+            Layer::Railway => (&self.railways, PrimitiveType::Triangles),
+            Layer::RoadCasing => (&self.road_casings, PrimitiveType::Triangles),
+            Layer::Road => (&self.roads, PrimitiveType::Triangles),
+            Layer::TransitLine => (&self.transit_lines, PrimitiveType::Triangles),
+            Layer::PointOfInterest => (&self.pois, PrimitiveType::Triangles),
+        };
+
+        if !vertices.is_empty() {
+            target.draw_primitives(vertices, primitive, RenderStates::default());
+        }
+    }
+
+    pub fn draw_all(&self, target: &mut RenderTarget) {
+        for &layer in &LAYERS {
+            self.draw(target, layer);
+        }
+    }
+}
+
+/// Width of the road and its casing if it has one, in world pixels. Real width up close, but
+/// never thinner than the style's minimum on screen when zoomed out
+pub fn road_widths(road: &Road, style: &RoadStyle, pixels_per_metre: f64, zoom: f64) -> (f64, Option<f64>) {
+    let metres = match (road.lanes, road.road_type) {
+        (Some(lanes), t) if !t.is_path() => f64::from(lanes.max(1)) * LANE_METRES,
+        _ => style.width,
+    };
+
+    line_widths(metres, style, pixels_per_metre, zoom)
+}
+
+/// As `road_widths`, for anything else drawn like a road
+pub fn line_widths(metres: f64, style: &RoadStyle, pixels_per_metre: f64, zoom: f64) -> (f64, Option<f64>) {
+    let width = f64::max(metres * pixels_per_metre, style.min_width * zoom);
+    let casing = style.casing.map(|_| width + 2.0 * f64::max(zoom, width * 0.15));
+    (width, casing)
+}
+
+/// Corners of the glyph around the centre, all visible from the centre so it can be filled as a
+/// fan. `radius` is in world pixels
+pub fn glyph_outline(glyph: Glyph, x: f64, y: f64, radius: f64) -> Vec<(f64, f64)> {
+    let polygon = |corners: usize, start: f64| -> Vec<(f64, f64)> {
+        (0..corners)
+            .map(|i| start + 2.0 * PI * i as f64 / corners as f64)
+            .map(|a| (x + radius * a.cos(), y + radius * a.sin()))
+            .collect()
+    };
+
+    match glyph {
+        Glyph::Circle => polygon(CIRCLE_SEGMENTS, 0.0),
+        Glyph::Square => polygon(4, PI / 4.0),
+        Glyph::Diamond => polygon(4, 0.0),
+        // pointing up, y is down
+        Glyph::Triangle => polygon(3, -PI / 2.0),
+        Glyph::Cross => {
+            let (r, t) = (radius, radius / 3.0);
+            [(t, -r), (t, -t), (r, -t), (r, t), (t, t), (t, r),
+             (-t, r), (-t, t), (-r, t), (-r, -t), (-t, -t), (-t, -r)]
+                .iter()
+                .map(|&(dx, dy)| (x + dx, y + dy))
+                .collect()
+        }
+    }
+}
+
+fn push_glyph(out: &mut Vec<Vertex>, poi: &PointOfInterest, style: &PoiStyle, zoom: f64) {
+    let (x, y) = (f64::from(poi.position.x), f64::from(poi.position.y));
+    let corners = glyph_outline(style.glyph, x, y, style.size * zoom);
+    let vertex = |(vx, vy): (f64, f64)| Vertex::with_pos_color(Vector2f::new(vx as f32, vy as f32), style.colour.0);
+
+    for i in 0..corners.len() {
+        out.push(vertex((x, y)));
+        out.push(vertex(corners[i]));
+        out.push(vertex(corners[(i + 1) % corners.len()]));
+    }
+}
+
+/// The line's own colour if it has one, with the style's opacity so the roads still show through
+pub fn transit_line_colour(line: &TransitLine, style: &LineStyle) -> Color {
+    match line.tags.get("colour").and_then(parse_colour) {
+        Some(c) => Color::rgba(c.r, c.g, c.b, style.colour.0.a),
+        None => style.colour.0,
+    }
+}
+
+fn push_transit_line(out: &mut Vec<Vertex>, line: &TransitLine, style: &LineStyle, zoom: f64) {
+    let colour = transit_line_colour(line, style);
+    for points in &line.path {
+        stroke::stroke(out, points, (style.width * zoom) as f32, Join::Round, colour);
+    }
+}
+
+fn push_area<A: Area>(fill: &mut Vec<Vertex>, outline: &mut Vec<Vertex>, area: &A, style: &AreaStyle, zoom: f64) {
+    let colour = style.fill.0;
+    fill.extend(area.triangle_points().map(|p| {
+        Vertex::with_pos_color(Vector2f::new(p.x as f32, p.y as f32), colour)
+    }));
+
+    if let Some(outline_colour) = style.outline {
+        let width = (style.outline_width * zoom) as f32;
+        let rings = Some(area.outline()).into_iter().chain(area.holes().iter().map(|h| &h[..]));
+        for ring in rings {
+            let closed: Vec<Point> = ring.iter().chain(ring.first()).cloned().collect();
+            stroke::stroke(outline, &closed, width, Join::Miter, outline_colour.0);
+        }
+    }
+}
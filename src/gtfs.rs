@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::time::Instant;
+use csv;
+use serde::de::DeserializeOwned;
+use zip::ZipArchive;
+use zip::result::ZipError;
+
+use error::*;
+use parser;
+use latlon;
+use transit::{SimTime, TransitMode};
+use world::{Id, LatLon, Point, World};
+
+/// How far a GTFS stop can be from an OSM one and still be the same stop
+const STOP_MATCH_METRES: f64 = 50.0;
+
+/// A date and the day of the week it falls on, for picking the services that run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimDay {
+    /// As in GTFS, e.g. 20180612
+    pub date: u32,
+
+    /// 0 is Monday
+    pub weekday: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct GtfsStop {
+    pub gtfs_id: String,
+    pub name: String,
+    pub position: Point,
+
+    /// The OSM stop or station it was matched to, see `Timetable::match_stops`
+    pub matched: Option<Id>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GtfsRoute {
+    pub gtfs_id: String,
+    pub short_name: String,
+    pub long_name: String,
+    pub mode: TransitMode,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StopTime {
+    /// Index into `Timetable::stops`
+    pub stop: usize,
+    pub arrival: SimTime,
+    pub departure: SimTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trip {
+    /// Index into `Timetable::routes`
+    pub route: usize,
+    service: usize,
+
+    /// In the order they're called at
+    pub stop_times: Vec<StopTime>,
+}
+
+/// Days a service runs, from calendar.txt
+#[derive(Debug, Clone)]
+struct Calendar {
+    weekdays: [bool; 7],
+    start_date: u32,
+    end_date: u32,
+}
+
+/// A GTFS feed's timetable, with its stops projected into world pixels
+#[derive(Debug)]
+pub struct Timetable {
+    pub stops: Vec<GtfsStop>,
+    pub routes: Vec<GtfsRoute>,
+    pub trips: Vec<Trip>,
+
+    /// By service index, None if the feed has no calendar.txt and every service runs every day.
+    /// Exceptions in calendar_dates.txt are ignored
+    calendars: Option<Vec<Option<Calendar>>>,
+}
+
+/// A vehicle somewhere along its trip
+#[derive(Debug, Clone, Copy)]
+pub struct Vehicle {
+    pub trip: usize,
+    pub position: Point,
+}
+
+/// Runs a timetable's vehicles in simulated time, which passes `speed` times faster than real time
+pub struct TransitSim {
+    pub timetable: Timetable,
+    pub day: SimDay,
+    pub time: SimTime,
+    pub speed: f64,
+    last_tick: Instant,
+}
+
+#[derive(Deserialize)]
+struct StopRecord {
+    stop_id: String,
+    #[serde(default)]
+    stop_name: String,
+    stop_lat: Option<f64>,
+    stop_lon: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct RouteRecord {
+    route_id: String,
+    #[serde(default)]
+    route_short_name: String,
+    #[serde(default)]
+    route_long_name: String,
+    route_type: u32,
+}
+
+#[derive(Deserialize)]
+struct TripRecord {
+    route_id: String,
+    service_id: String,
+    trip_id: String,
+}
+
+#[derive(Deserialize)]
+struct StopTimeRecord {
+    trip_id: String,
+    arrival_time: String,
+    departure_time: String,
+    stop_id: String,
+    stop_sequence: u32,
+}
+
+#[derive(Deserialize)]
+struct CalendarRecord {
+    service_id: String,
+    monday: u8,
+    tuesday: u8,
+    wednesday: u8,
+    thursday: u8,
+    friday: u8,
+    saturday: u8,
+    sunday: u8,
+    start_date: u32,
+    end_date: u32,
+}
+
+impl SimDay {
+    /// None if it isn't a valid yyyymmdd date
+    pub fn from_date(date: u32) -> Option<Self> {
+        let (y, m, d) = (date / 10000, date / 100 % 100, date % 100);
+        if m < 1 || m > 12 || d < 1 || d > 31 {
+            return None;
+        }
+
+        // Sakamoto's method, which counts from Sunday
+        const OFFSETS: [u32; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let y = if m < 3 { y - 1 } else { y };
+        let sunday_based = (y + y / 4 - y / 100 + y / 400 + OFFSETS[m as usize - 1] + d) % 7;
+
+        Some(SimDay {
+            date,
+            weekday: ((sunday_based + 6) % 7) as usize,
+        })
+    }
+}
+
+/// "hh:mm:ss", where hours can go past 24 for trips running past midnight
+pub fn parse_time(s: &str) -> Option<SimTime> {
+    let mut parts = s.trim().split(':').map(|p| p.parse::<u32>().ok());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Some(h)), Some(Some(m)), Some(Some(s)), None) => Some(f64::from(h * 3600 + m * 60 + s)),
+        _ => None,
+    }
+}
+
+fn mode_from_route_type(route_type: u32) -> TransitMode {
+    match route_type {
+        0 | 5 => TransitMode::Tram,
+        1 => TransitMode::Subway,
+        2 => TransitMode::Train,
+        12 => TransitMode::LightRail,
+        // buses, trolleybuses and anything else on the roads
+        _ => TransitMode::Bus,
+    }
+}
+
+/// Parses a CSV file from the feed, or returns None if it isn't there
+fn read_records<R: Read + ::std::io::Seek, T: DeserializeOwned>(archive: &mut ZipArchive<R>, name: &str) -> SimResult<Option<Vec<T>>> {
+    let mut contents = String::new();
+    match archive.by_name(name) {
+        Ok(mut file) => file.read_to_string(&mut contents)?,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    // many feeds start with a byte order mark, which would become part of the first header
+    let contents = contents.trim_left_matches('\u{feff}');
+    let records = csv::Reader::from_reader(contents.as_bytes())
+        .deserialize()
+        .collect::<Result<Vec<T>, _>>()
+        .map_err(|e| ErrorKind::BadGtfs(format!("{}: {}", name, e)))?;
+    Ok(Some(records))
+}
+
+fn require<T>(records: Option<Vec<T>>, name: &str) -> SimResult<Vec<T>> {
+    records.ok_or_else(|| ErrorKind::BadGtfs(format!("missing {}", name)).into())
+}
+
+impl Timetable {
+    /// Reads stops, routes, trips, stop_times and calendar from a GTFS zip, projecting stops
+    /// relative to the world's origin like everything else in it
+    pub fn load(path: &Path, origin: &LatLon) -> SimResult<Self> {
+        let mut archive = ZipArchive::new(fs::File::open(path)?)?;
+        let origin = parser::convert_latlon(origin.lat, origin.lon);
+
+        let mut stop_index = HashMap::new();
+        let mut stops = Vec::new();
+        for r in require(read_records::<_, StopRecord>(&mut archive, "stops.txt")?, "stops.txt")? {
+            // stations and entrances without their own position aren't stopped at
+            if let (Some(lat), Some(lon)) = (r.stop_lat, r.stop_lon) {
+                let p = parser::convert_latlon(lat, lon);
+                stop_index.insert(r.stop_id.clone(), stops.len());
+                stops.push(GtfsStop {
+                    gtfs_id: r.stop_id,
+                    name: r.stop_name,
+                    position: Point { x: p.x - origin.x, y: p.y - origin.y },
+                    matched: None,
+                });
+            }
+        }
+
+        let mut route_index = HashMap::new();
+        let mut routes = Vec::new();
+        for r in require(read_records::<_, RouteRecord>(&mut archive, "routes.txt")?, "routes.txt")? {
+            route_index.insert(r.route_id.clone(), routes.len());
+            routes.push(GtfsRoute {
+                gtfs_id: r.route_id,
+                short_name: r.route_short_name,
+                long_name: r.route_long_name,
+                mode: mode_from_route_type(r.route_type),
+            });
+        }
+
+        let mut service_index = HashMap::new();
+        let mut trip_index = HashMap::new();
+        let mut trips = Vec::new();
+        for r in require(read_records::<_, TripRecord>(&mut archive, "trips.txt")?, "trips.txt")? {
+            let route = *route_index.get(&r.route_id)
+                .ok_or_else(|| ErrorKind::BadGtfs(format!("trip {} has unknown route {}", r.trip_id, r.route_id)))?;
+            let services = service_index.len();
+            let service = *service_index.entry(r.service_id).or_insert(services);
+
+            trip_index.insert(r.trip_id, trips.len());
+            trips.push(Trip {
+                route,
+                service,
+                stop_times: Vec::new(),
+            });
+        }
+
+        let mut sequences: Vec<Vec<(u32, StopTime)>> = vec![Vec::new(); trips.len()];
+        let mut untimed = 0;
+        for r in require(read_records::<_, StopTimeRecord>(&mut archive, "stop_times.txt")?, "stop_times.txt")? {
+            let trip = *trip_index.get(&r.trip_id)
+                .ok_or_else(|| ErrorKind::BadGtfs(format!("stop time for unknown trip {}", r.trip_id)))?;
+            let stop = match stop_index.get(&r.stop_id) {
+                Some(&stop) => stop,
+                None => return Err(ErrorKind::BadGtfs(format!("trip {} calls at unknown stop {}", r.trip_id, r.stop_id)).into()),
+            };
+
+            // only timepoints have to have times, the rest are skipped rather than guessed
+            match (parse_time(&r.arrival_time), parse_time(&r.departure_time)) {
+                (Some(arrival), Some(departure)) => sequences[trip].push((r.stop_sequence, StopTime { stop, arrival, departure })),
+                _ => untimed += 1,
+            }
+        }
+
+        for (trip, mut sequence) in trips.iter_mut().zip(sequences) {
+            sequence.sort_by_key(|&(seq, _)| seq);
+            trip.stop_times = sequence.into_iter().map(|(_, st)| st).collect();
+        }
+
+        if untimed > 0 {
+            debug!("Skipped {} stop times without times", untimed);
+        }
+
+        let calendars = match read_records::<_, CalendarRecord>(&mut archive, "calendar.txt")? {
+            Some(records) => {
+                let mut calendars = vec![None; service_index.len()];
+                for r in records {
+                    if let Some(&service) = service_index.get(&r.service_id) {
+                        calendars[service] = Some(Calendar {
+                            weekdays: [r.monday == 1, r.tuesday == 1, r.wednesday == 1, r.thursday == 1,
+                                       r.friday == 1, r.saturday == 1, r.sunday == 1],
+                            start_date: r.start_date,
+                            end_date: r.end_date,
+                        });
+                    }
+                }
+                Some(calendars)
+            }
+            None => None,
+        };
+
+        info!("Loaded {} stops, {} routes and {} trips from {:?}", stops.len(), routes.len(), trips.len(), path);
+        Ok(Timetable { stops, routes, trips, calendars })
+    }
+
+    /// The first day any service runs, for feeds that only cover a certain range of dates
+    pub fn first_date(&self) -> Option<u32> {
+        self.calendars.as_ref()?.iter()
+            .filter_map(|c| c.as_ref().map(|c| c.start_date))
+            .min()
+    }
+
+    pub fn runs_on(&self, trip: &Trip, day: &SimDay) -> bool {
+        match self.calendars {
+            None => true,
+            Some(ref calendars) => match calendars[trip.service] {
+                Some(ref c) => c.weekdays[day.weekday] && day.date >= c.start_date && day.date <= c.end_date,
+                None => false,
+            },
+        }
+    }
+
+    /// Links unmatched stops to the nearest loaded OSM stop or station, if there's one close
+    /// enough. Called as chunks load, returning how many were newly matched
+    pub fn match_stops(&mut self, world: &World) -> usize {
+        let max_distance = (STOP_MATCH_METRES * latlon::pixels_per_metre(&world.origin)) as i32;
+        let mut matched = 0;
+
+        for stop in self.stops.iter_mut().filter(|s| s.matched.is_none()) {
+            stop.matched = world.nearest_transit_stop(&stop.position, max_distance);
+            if stop.matched.is_some() {
+                matched += 1;
+            }
+        }
+
+        matched
+    }
+
+    /// Where the day's vehicles are at the time, waiting at a stop or part way between two.
+    /// Trips left over from the day before aren't included
+    pub fn vehicles_at(&self, day: &SimDay, time: SimTime) -> Vec<Vehicle> {
+        let mut vehicles = Vec::new();
+
+        for (i, trip) in self.trips.iter().enumerate() {
+            let (first, last) = match (trip.stop_times.first(), trip.stop_times.last()) {
+                (Some(first), Some(last)) => (first, last),
+                _ => continue,
+            };
+            if time < first.departure || time > last.arrival || !self.runs_on(trip, day) {
+                continue;
+            }
+
+            let next = trip.stop_times.iter().position(|st| st.arrival > time).unwrap_or(trip.stop_times.len() - 1);
+            let position = if next == 0 {
+                self.stops[first.stop].position
+            } else {
+                let (from, to) = (&trip.stop_times[next - 1], &trip.stop_times[next]);
+                let (a, b) = (&self.stops[from.stop].position, &self.stops[to.stop].position);
+                let progress = if time <= from.departure || to.arrival <= from.departure {
+                    0.0
+                } else {
+                    (time - from.departure) / (to.arrival - from.departure)
+                };
+
+                Point {
+                    x: a.x + (f64::from(b.x - a.x) * progress).round() as i32,
+                    y: a.y + (f64::from(b.y - a.y) * progress).round() as i32,
+                }
+            };
+
+            vehicles.push(Vehicle { trip: i, position });
+        }
+
+        vehicles
+    }
+}
+
+impl TransitSim {
+    pub fn new(timetable: Timetable, day: SimDay, start: SimTime, speed: f64) -> Self {
+        TransitSim {
+            timetable,
+            day,
+            time: start,
+            speed,
+            last_tick: Instant::now(),
+        }
+    }
+
+    /// Advances the clock by the real time since the last tick
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        let elapsed = now - self.last_tick;
+        self.last_tick = now;
+        self.time += (elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9) * self.speed;
+    }
+
+    pub fn vehicles(&self) -> Vec<Vehicle> {
+        self.timetable.vehicles_at(&self.day, self.time)
+    }
+
+    pub fn mode_of(&self, vehicle: &Vehicle) -> TransitMode {
+        self.timetable.routes[self.timetable.trips[vehicle.trip].route].mode
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::time::Instant;
+use sfml::graphics::*;
+use sfml::system::*;
+
+use world::WorldStats;
+
+/// Frames averaged over for the frame rate
+const FRAME_HISTORY: usize = 60;
+
+const PADDING: f32 = 4.0;
+
+/// Frame timing and loader statistics, drawn in the top right corner
+pub struct Hud {
+    pub visible: bool,
+    last_frame: Instant,
+
+    /// Most recent last, in seconds
+    frame_times: VecDeque<f64>,
+}
+
+impl Hud {
+    pub fn new() -> Self {
+        Hud {
+            visible: false,
+            last_frame: Instant::now(),
+            frame_times: VecDeque::with_capacity(FRAME_HISTORY),
+        }
+    }
+
+    /// Call once per frame, whether or not it's visible
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        let elapsed = now - self.last_frame;
+        self.last_frame = now;
+
+        if self.frame_times.len() == FRAME_HISTORY {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9);
+    }
+
+    pub fn render(&self, target: &mut RenderTarget, text: &mut Text, stats: &WorldStats) {
+        if !self.visible {
+            return;
+        }
+
+        let view = target.view().to_owned();
+        let screen = target.default_view().to_owned();
+        target.set_view(&screen);
+
+        text.set_string(&self.describe(stats));
+        let bounds = text.local_bounds();
+        let width = target.size().x as f32;
+        text.set_position((width - bounds.width - bounds.left - PADDING * 2.0, PADDING * 2.0));
+
+        let bounds = text.global_bounds();
+        let mut background = RectangleShape::with_size(Vector2f::new(
+            bounds.width + PADDING * 2.0,
+            bounds.height + PADDING * 2.0,
+        ));
+        background.set_position((bounds.left - PADDING, bounds.top - PADDING));
+        background.set_fill_color(&Color::rgba(0, 0, 0, 200));
+
+        target.draw(&background);
+        target.draw(text);
+        target.set_view(&view);
+    }
+
+    fn describe(&self, stats: &WorldStats) -> String {
+        let mut s = String::new();
+
+        let n = self.frame_times.len().max(1) as f64;
+        let mean = self.frame_times.iter().sum::<f64>() / n;
+        let worst = self.frame_times.iter().cloned().fold(0.0, f64::max);
+        let fps = if mean > 0.0 { 1.0 / mean } else { 0.0 };
+        writeln!(s, "fps: {:.0} ({:.1} ms, worst {:.1} ms)", fps, mean * 1000.0, worst * 1000.0).unwrap();
+
+        writeln!(s, "roads: {}  land uses: {}  buildings: {}  pois: {}",
+                 stats.roads, stats.land_uses, stats.buildings, stats.pois).unwrap();
+        writeln!(s, "railways: {}  transit lines: {}", stats.railways, stats.transit_lines).unwrap();
+        writeln!(s, "chunks: {} loaded, {} loading, {} prefetching, {} failed",
+                 stats.chunks, stats.loading, stats.prefetching, stats.failed).unwrap();
+        writeln!(s, "requests: {} queued, {} in flight", stats.queued, stats.in_flight).unwrap();
+        writeln!(s, "cache: {} chunk hits, {} osm hits, {} misses", stats.chunk_cache_hits, stats.osm_cache_hits, stats.cache_misses).unwrap();
+        write!(s, "memory: {:.1} MB", stats.memory as f64 / (1024.0 * 1024.0)).unwrap();
+
+        s
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt::Write;
+use sfml::graphics::*;
+use sfml::system::*;
+
+use world::{Feature, World};
+
+const PADDING: f32 = 4.0;
+
+/// Human readable summary of a loaded feature, or None if it's since been unloaded
+pub fn describe(world: &World, feature: Feature) -> Option<String> {
+    let mut s = String::new();
+
+    match feature {
+        Feature::Road(id) => {
+            let road = world.loaded_roads.get(&id)?;
+            writeln!(s, "Road {}", id).unwrap();
+            writeln!(s, "type: {:?}", road.road_type).unwrap();
+            if !road.name.is_empty() {
+                writeln!(s, "name: {}", road.name).unwrap();
+            }
+        }
+        Feature::LandUse(id) => {
+            let land_use = world.loaded_land_uses.get(&id)?;
+            writeln!(s, "Land use {}", id).unwrap();
+            writeln!(s, "type: {:?}", land_use.land_use_type).unwrap();
+        }
+        Feature::Building(id) => {
+            let building = world.loaded_buildings.get(&id)?;
+            writeln!(s, "Building {}", id).unwrap();
+            writeln!(s, "type: {:?}", building.building_type).unwrap();
+        }
+        Feature::PointOfInterest(id) => {
+            let poi = world.loaded_pois.get(&id)?;
+            writeln!(s, "Point of interest {}", id).unwrap();
+            writeln!(s, "category: {:?}", poi.category).unwrap();
+            if !poi.name.is_empty() {
+                writeln!(s, "name: {}", poi.name).unwrap();
+            }
+
+            for (line_id, _) in world.lines_at_stop(id) {
+                let line = &world.loaded_transit_lines[&line_id];
+                writeln!(s, "served by: {:?} {}", line.mode, if line.reference.is_empty() { &line.name } else { &line.reference }).unwrap();
+            }
+        }
+        Feature::Railway(id) => {
+            let railway = world.loaded_railways.get(&id)?;
+            writeln!(s, "Railway {}", id).unwrap();
+            writeln!(s, "type: {:?}", railway.kind).unwrap();
+            if !railway.name.is_empty() {
+                writeln!(s, "name: {}", railway.name).unwrap();
+            }
+        }
+        Feature::TransitLine(id) => {
+            let line = world.loaded_transit_lines.get(&id)?;
+            writeln!(s, "Transit line {}", id).unwrap();
+            writeln!(s, "mode: {:?}", line.mode).unwrap();
+            if !line.reference.is_empty() {
+                writeln!(s, "ref: {}", line.reference).unwrap();
+            }
+            if !line.name.is_empty() {
+                writeln!(s, "name: {}", line.name).unwrap();
+            }
+            writeln!(s, "stops: {}", line.stops.len()).unwrap();
+        }
+    }
+
+    if let Some(tags) = world.tags(feature) {
+        for (key, value) in tags.iter() {
+            writeln!(s, "{} = {}", key, value).unwrap();
+        }
+    }
+
+    let chunks: Vec<String> = world.chunks_containing(feature).iter()
+        .map(|&(x, y)| format!("({}, {})", x, y))
+        .collect();
+    write!(s, "chunks: {}", chunks.join(" ")).unwrap();
+
+    Some(s)
+}
+
+/// Draws the description in the top left corner of the screen, regardless of the current view
+pub fn render(target: &mut RenderTarget, text: &mut Text, description: &str) {
+    let view = target.view().to_owned();
+    let screen = target.default_view().to_owned();
+    target.set_view(&screen);
+
+    text.set_string(description);
+    text.set_position((PADDING * 2.0, PADDING * 2.0));
+
+    let bounds = text.global_bounds();
+    let mut background = RectangleShape::with_size(Vector2f::new(
+        bounds.width + PADDING * 2.0,
+        bounds.height + PADDING * 2.0,
+    ));
+    background.set_position((bounds.left - PADDING, bounds.top - PADDING));
+    background.set_fill_color(&Color::rgba(0, 0, 0, 200));
+    background.set_outline_color(&Color::WHITE);
+    background.set_outline_thickness(1.0);
+
+    target.draw(&background);
+    target.draw(text);
+    target.set_view(&view);
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use sfml::graphics::*;
+use sfml::system::*;
+
+use world::{Feature, World};
+use parser::RoadType;
+use geometry;
+
+/// Labels are only drawn when zoomed in closer than this many world pixels per screen pixel
+pub const MAX_LABEL_ZOOM: f64 = 1.5;
+
+/// Screen pixels along a road between repeats of its name
+const REPEAT_SPACING: f32 = 300.0;
+
+/// Screen pixels kept clear around each label
+const PADDING: f32 = 4.0;
+
+/// Screen space bounding box of a placed label
+struct Placed {
+    left: f32,
+    top: f32,
+    right: f32,
+    bottom: f32,
+}
+
+impl Placed {
+    fn overlaps(&self, other: &Placed) -> bool {
+        self.left < other.right && other.left < self.right &&
+            self.top < other.bottom && other.top < self.bottom
+    }
+}
+
+/// Where the names of the roads a chunk draws can go, laid out for a zoom bucket. Laid out again
+/// when drawn at another, and dropped when the chunk's features change
+pub struct ChunkLabels {
+    /// See `geometry::zoom_bucket`
+    zoom: f64,
+    labels: Vec<Label>,
+}
+
+struct Label {
+    name: String,
+    rank: u8,
+    bounds: FloatRect,
+
+    /// Centre in world pixels and rotation in degrees of each spot along the road
+    positions: Vec<(Vector2f, f32)>,
+}
+
+impl ChunkLabels {
+    fn build(world: &World, features: &[Feature], text: &mut Text, zoom: f64) -> Self {
+        let labels = features.iter()
+            .filter_map(|f| match *f {
+                Feature::Road(id) => world.loaded_roads.get(&id),
+                _ => None,
+            })
+            .filter(|r| !r.name.is_empty() && r.segments.len() > 1)
+            .map(|r| {
+                text.set_string(&r.name);
+                let bounds = text.local_bounds();
+
+                let points: Vec<Vector2f> = r.segments.iter()
+                    .map(|p| Vector2f::new(p.x as f32, p.y as f32))
+                    .collect();
+                let mut positions = Vec::new();
+                place_along(&points, bounds.width, zoom as f32, |centre, angle| positions.push((centre, angle)));
+
+                Label {
+                    name: r.name.clone(),
+                    rank: get_label_rank(&r.road_type),
+                    bounds,
+                    positions,
+                }
+            })
+            .filter(|l| !l.positions.is_empty())
+            .collect();
+
+        Self { zoom, labels }
+    }
+}
+
+/// Draws road names along the roads in screen space, so they stay the same size at any zoom.
+/// Names of major roads are placed first, and any label that would overlap another is skipped.
+/// Chunks missing from `cache` or laid out for another zoom bucket are laid out and added to it
+pub fn render(target: &mut RenderTarget, world: &World, cache: &mut HashMap<(i32, i32), ChunkLabels>, text: &mut Text, zoom: f64) {
+    if zoom > MAX_LABEL_ZOOM {
+        return;
+    }
+
+    let bucket = geometry::zoom_bucket(zoom);
+    for (x, y) in world.loaded_chunk_coords() {
+        let stale = cache.get(&(x, y)).map_or(true, |c| c.zoom != bucket);
+        if stale {
+            cache.insert((x, y), ChunkLabels::build(world, world.chunk_features(x, y), text, bucket));
+        }
+    }
+
+    let mut candidates: Vec<&Label> = cache.values()
+        .flat_map(|c| c.labels.iter())
+        .collect();
+    candidates.sort_by_key(|l| l.rank);
+
+    let view = target.view().to_owned();
+    let screen = target.default_view().to_owned();
+    let size = target.size();
+    let mut placed: Vec<Placed> = Vec::new();
+
+    let mut labels = Vec::new();
+    for label in candidates {
+        let bounds = label.bounds;
+        let (width, height) = (bounds.width, bounds.height);
+
+        for &(position, angle) in &label.positions {
+            let centre = {
+                let px = target.map_coords_to_pixel(&position, &view);
+                Vector2f::new(px.x as f32, px.y as f32)
+            };
+            if centre.x < 0.0 || centre.y < 0.0 || centre.x > size.x as f32 || centre.y > size.y as f32 {
+                continue;
+            }
+
+            let candidate = bounding_box(centre, angle, width, height);
+            if placed.iter().any(|p| p.overlaps(&candidate)) {
+                continue;
+            }
+
+            placed.push(candidate);
+            labels.push((&label.name, centre, angle, bounds));
+        }
+    }
+
+    target.set_view(&screen);
+    for (name, centre, angle, bounds) in labels {
+        text.set_string(name);
+        text.set_origin((bounds.left + bounds.width / 2.0, bounds.top + bounds.height / 2.0));
+        text.set_position(centre);
+        text.set_rotation(angle);
+        target.draw(text);
+    }
+    target.set_view(&view);
+
+    text.set_rotation(0.0);
+    text.set_origin((0.0, 0.0));
+}
+
+/// Calls `place` with the centre and rotation in degrees of each label position along the line,
+/// at intervals and only on segments long enough to hold the whole label. `width` is in screen
+/// pixels, and `zoom` is world pixels per screen pixel
+fn place_along<F: FnMut(Vector2f, f32)>(points: &[Vector2f], width: f32, zoom: f32, mut place: F) {
+    let needed = (width + PADDING * 2.0) * zoom;
+    let spacing = REPEAT_SPACING * zoom;
+    let mut next = spacing / 2.0;
+    let mut travelled = 0.0;
+
+    for w in points.windows(2) {
+        let (a, b) = (w[0], w[1]);
+        let d = b - a;
+        let len = (d.x * d.x + d.y * d.y).sqrt();
+
+        while travelled + len >= next && len >= needed {
+            // as close to the wanted spot as fits on the segment
+            let along = (next - travelled).max(needed / 2.0).min(len - needed / 2.0);
+            let centre = a + d * (along / len);
+
+            // never upside down
+            let mut angle = d.y.atan2(d.x).to_degrees();
+            if angle > 90.0 {
+                angle -= 180.0;
+            } else if angle < -90.0 {
+                angle += 180.0;
+            }
+
+            place(centre, angle);
+            next = travelled + along + spacing;
+        }
+
+        // if the wanted spot was on a segment too short, the next one long enough is used
+        travelled += len;
+    }
+}
+
+/// Axis aligned box around the rotated label, with padding
+fn bounding_box(centre: Vector2f, angle: f32, width: f32, height: f32) -> Placed {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let half_w = (width * cos.abs() + height * sin.abs()) / 2.0 + PADDING;
+    let half_h = (width * sin.abs() + height * cos.abs()) / 2.0 + PADDING;
+
+    Placed {
+        left: centre.x - half_w,
+        top: centre.y - half_h,
+        right: centre.x + half_w,
+        bottom: centre.y + half_h,
+    }
+}
+
+/// Lower is placed first
+fn get_label_rank(road_type: &RoadType) -> u8 {
+    match road_type.base() {
+        RoadType::Motorway | RoadType::Trunk => 0,
+        RoadType::Primary => 1,
+        RoadType::Secondary => 2,
+        RoadType::Tertiary => 3,
+        RoadType::Minor | RoadType::Residential | RoadType::LivingStreet => 4,
+        RoadType::Service | RoadType::Track => 5,
+        RoadType::Pedestrian | RoadType::Footway | RoadType::Cycleway | RoadType::Steps => 6,
+        _ => 7,
+    }
+}
@@ -28,3 +28,11 @@ fn get_chunk_bottom_right(top_left: &LatLon) -> LatLon {
         lon: top_left.lon + CHUNK_LON
     }
 }
+
+/// World pixels per metre on the ground around the given point, see `parser::convert_latlon`
+pub fn pixels_per_metre(at: &LatLon) -> f64 {
+    const EQUATOR_METRES: f64 = 40_075_016.686;
+    const WORLD_PIXELS: f64 = (1 << 26) as f64;
+
+    WORLD_PIXELS / (EQUATOR_METRES * at.lat.to_radians().cos())
+}
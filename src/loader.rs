@@ -0,0 +1,529 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use chunk_req;
+use parser;
+use error::SimResult;
+use world::{self, LatLon, PartialChunk, PrefetchedChunk};
+
+/// All visible requests are served before any prefetches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Visible,
+    Prefetch,
+}
+
+/// Everything a worker needs to load a chunk, without touching the world
+pub struct LoadRequest {
+    pub coord: (i32, i32),
+    pub bounds: (LatLon, LatLon),
+    pub world_dir: PathBuf,
+    pub client: Arc<chunk_req::Client>,
+    pub features: chunk_req::FeatureFilter,
+    pub parse_mode: parser::ParseMode,
+    pub completion: Completion,
+}
+
+/// Where the result of a load is sent
+pub enum Completion {
+    /// Loaded into the world by whoever receives it
+    Load(mpsc::Sender<PartialChunk>),
+
+    /// Only cached on disk, so only whether that worked
+    Prefetch(mpsc::Sender<PrefetchedChunk>),
+}
+
+/// Heap entry, ordered so the closest chunk to the focus is popped first
+struct Queued {
+    priority: Priority,
+    distance: f64,
+
+    /// Breaks ties in request order
+    seq: u64,
+
+    coord: (i32, i32),
+}
+
+struct Queue {
+    heap: BinaryHeap<Queued>,
+
+    /// The source of truth for what's queued, with the sequence number of its heap entry. Heap
+    /// entries of cancelled or replaced requests are left behind and skipped when popped, until
+    /// they outnumber the live ones and the heap is rebuilt without them
+    requests: HashMap<(i32, i32), (u64, LoadRequest)>,
+    in_flight: HashMap<(i32, i32), InFlight>,
+
+    /// Centre of the camera in chunk coordinates
+    focus: (f64, f64),
+
+    /// Offset from the focus towards where the camera is heading, used to order prefetches
+    lead: (f64, f64),
+    next_seq: u64,
+    shutdown: bool,
+}
+
+/// A chunk a worker is loading
+struct InFlight {
+    /// The highest of the running request and any that joined it
+    priority: Priority,
+
+    /// Requests made while it was loading, which are sent the same result rather than loading
+    /// it again
+    joined: Vec<Completion>,
+}
+
+/// Fixed pool of worker threads loading chunks in order of distance from the camera
+pub struct ChunkLoader {
+    shared: Arc<(Mutex<Queue>, Condvar)>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Completion {
+    fn priority(&self) -> Priority {
+        match *self {
+            Completion::Load(_) => Priority::Visible,
+            Completion::Prefetch(_) => Priority::Prefetch,
+        }
+    }
+}
+
+impl LoadRequest {
+    pub fn priority(&self) -> Priority {
+        self.completion.priority()
+    }
+}
+
+impl Queued {
+    fn new(coord: (i32, i32), seq: u64, priority: Priority, focus: (f64, f64), lead: (f64, f64)) -> Self {
+        let focus = match priority {
+            Priority::Visible => focus,
+            Priority::Prefetch => (focus.0 + lead.0, focus.1 + lead.1),
+        };
+
+        let dx = f64::from(coord.0) + 0.5 - focus.0;
+        let dy = f64::from(coord.1) + 0.5 - focus.1;
+        Queued {
+            priority,
+            distance: dx * dx + dy * dy,
+            seq,
+            coord,
+        }
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    // reversed, as BinaryHeap is a max heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.priority.cmp(&self.priority)
+            .then_with(|| other.distance.partial_cmp(&self.distance).unwrap_or(Ordering::Equal))
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl Queue {
+    fn new() -> Self {
+        Queue {
+            heap: BinaryHeap::new(),
+            requests: HashMap::new(),
+            in_flight: HashMap::new(),
+            focus: (0.0, 0.0),
+            lead: (0.0, 0.0),
+            next_seq: 0,
+            shutdown: false,
+        }
+    }
+
+    fn pop(&mut self) -> Option<LoadRequest> {
+        while let Some(q) = self.heap.pop() {
+            let live = self.requests.get(&q.coord).map(|&(seq, _)| seq == q.seq).unwrap_or(false);
+            if live {
+                let (_, req) = self.requests.remove(&q.coord).unwrap();
+                self.in_flight.insert(q.coord, InFlight {
+                    priority: req.priority(),
+                    joined: Vec::new(),
+                });
+                return Some(req);
+            }
+        }
+        None
+    }
+
+    /// Stops tracking a chunk a worker has loaded, returning the requests that joined it
+    fn finish(&mut self, coord: (i32, i32)) -> Vec<Completion> {
+        self.in_flight.remove(&coord).map(|f| f.joined).unwrap_or_default()
+    }
+
+    fn push(&mut self, request: LoadRequest) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.heap.push(Queued::new(request.coord, seq, request.priority(), self.focus, self.lead));
+        self.requests.insert(request.coord, (seq, request));
+    }
+
+    /// Gives the request back if its chunk is already queued or loading, unless it's replacing a
+    /// prefetch with a visible request. A visible request for a chunk being prefetched joins the
+    /// running load
+    fn submit(&mut self, request: LoadRequest) -> Result<(), LoadRequest> {
+        if let Some(running) = self.in_flight.get_mut(&request.coord) {
+            if request.priority() < running.priority {
+                running.priority = request.priority();
+                running.joined.push(request.completion);
+                return Ok(());
+            }
+            return Err(request);
+        }
+
+        match self.requests.get(&request.coord) {
+            Some(&(_, ref queued)) if request.priority() < queued.priority() => {}
+            Some(_) => return Err(request),
+            None => {}
+        }
+
+        // replacing a request leaves its heap entry behind, like cancelling it
+        self.push(request);
+        self.compact();
+        Ok(())
+    }
+
+    fn cancel(&mut self, coord: (i32, i32)) -> Option<LoadRequest> {
+        let cancelled = self.requests.remove(&coord).map(|(_, req)| req);
+        self.compact();
+        cancelled
+    }
+
+    fn cancel_prefetches<F: Fn((i32, i32)) -> bool>(&mut self, keep: F) -> Vec<(i32, i32)> {
+        let cancelled: Vec<(i32, i32)> = self.requests.values()
+            .filter(|&&(_, ref req)| req.priority() == Priority::Prefetch && !keep(req.coord))
+            .map(|&(_, ref req)| req.coord)
+            .collect();
+
+        for coord in &cancelled {
+            self.requests.remove(coord);
+        }
+        self.compact();
+        cancelled
+    }
+
+    /// Rebuilds the heap once stale entries outnumber live ones, so it doesn't grow without bound
+    /// when requests are cancelled faster than they're popped
+    fn compact(&mut self) {
+        if self.heap.len() > 2 * self.requests.len() {
+            self.rebuild_heap();
+        }
+    }
+
+    /// Drops stale entries and recalculates distances from the current focus
+    fn rebuild_heap(&mut self) {
+        let (focus, lead) = (self.focus, self.lead);
+        self.heap = self.requests.values()
+            .map(|&(seq, ref req)| Queued::new(req.coord, seq, req.priority(), focus, lead))
+            .collect();
+    }
+
+    fn set_focus(&mut self, focus: (f64, f64), lead: (f64, f64)) {
+        if self.focus != focus || self.lead != lead {
+            self.focus = focus;
+            self.lead = lead;
+            self.rebuild_heap();
+        }
+    }
+}
+
+impl ChunkLoader {
+    pub fn new(worker_count: usize) -> Self {
+        let shared = Arc::new((Mutex::new(Queue::new()), Condvar::new()));
+
+        let workers = (0..worker_count)
+            .map(|i| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("chunk-loader-{}", i))
+                    .spawn(move || work(&shared))
+                    .expect("Failed to spawn chunk loader")
+            })
+            .collect();
+
+        ChunkLoader { shared, workers }
+    }
+
+    /// Gives the request back if its chunk is already queued or loading, unless it's replacing a
+    /// prefetch with a visible request
+    pub fn submit(&self, request: LoadRequest) -> Result<(), LoadRequest> {
+        let &(ref lock, ref cvar) = &*self.shared;
+        lock.lock().unwrap().submit(request)?;
+        cvar.notify_one();
+        Ok(())
+    }
+
+    /// Removes the chunk from the queue if it hasn't been started yet
+    pub fn cancel(&self, coord: (i32, i32)) -> Option<LoadRequest> {
+        let &(ref lock, _) = &*self.shared;
+        lock.lock().unwrap().cancel(coord)
+    }
+
+    /// Removes all queued prefetches that the predicate rejects, returning their coordinates
+    pub fn cancel_prefetches<F: Fn((i32, i32)) -> bool>(&self, keep: F) -> Vec<(i32, i32)> {
+        let &(ref lock, _) = &*self.shared;
+        lock.lock().unwrap().cancel_prefetches(keep)
+    }
+
+    /// Reorders the queue around a new centre and heading, in chunk coordinates
+    pub fn set_focus(&self, focus: (f64, f64), lead: (f64, f64)) {
+        let &(ref lock, _) = &*self.shared;
+        lock.lock().unwrap().set_focus(focus, lead);
+    }
+
+    pub fn queued_count(&self) -> usize {
+        let &(ref lock, _) = &*self.shared;
+        lock.lock().unwrap().requests.len()
+    }
+}
+
+impl Drop for ChunkLoader {
+    fn drop(&mut self) {
+        {
+            let &(ref lock, ref cvar) = &*self.shared;
+            lock.lock().unwrap().shutdown = true;
+            cvar.notify_all();
+        }
+
+        for w in self.workers.drain(..) {
+            let _ = w.join();
+        }
+    }
+}
+
+fn work(shared: &(Mutex<Queue>, Condvar)) {
+    let &(ref lock, ref cvar) = shared;
+
+    loop {
+        let request = {
+            let mut queue = lock.lock().unwrap();
+            loop {
+                if queue.shutdown {
+                    return;
+                }
+
+                if let Some(req) = queue.pop() {
+                    break req;
+                }
+
+                queue = cvar.wait(queue).unwrap();
+            }
+        };
+
+        let res = world::attempt_load(&request);
+        let mut completions = lock.lock().unwrap().finish(request.coord);
+        completions.push(request.completion);
+        complete(request.coord, res, completions);
+    }
+}
+
+/// Sends the result to everything waiting for it. Only one of them can be a load, as a visible
+/// request for a chunk that's already loading is rejected
+fn complete(coord: (i32, i32), res: SimResult<parser::PartialWorld>, completions: Vec<Completion>) {
+    let outcome = res.as_ref().map(|_| ()).map_err(|e| e.to_string());
+    let mut res = Some(res);
+
+    for completion in completions {
+        match completion {
+            Completion::Load(channel) => {
+                if let Some(res) = res.take() {
+                    let _ = channel.send(PartialChunk(res, coord));
+                }
+            }
+            Completion::Prefetch(channel) => {
+                let _ = channel.send(PrefetchedChunk(outcome.clone(), coord));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(coord: (i32, i32), priority: Priority) -> LoadRequest {
+        let client = chunk_req::Client::new(chunk_req::ClientConfig::default()).unwrap();
+        LoadRequest {
+            coord,
+            bounds: (LatLon::new(0.0, 0.0), LatLon::new(0.0, 0.0)),
+            world_dir: PathBuf::new(),
+            client: Arc::new(client),
+            features: chunk_req::FeatureFilter::default(),
+            parse_mode: parser::ParseMode::Strict,
+            completion: match priority {
+                Priority::Visible => Completion::Load(mpsc::channel().0),
+                Priority::Prefetch => Completion::Prefetch(mpsc::channel().0),
+            },
+        }
+    }
+
+    fn pop_all(queue: &mut Queue) -> Vec<(i32, i32)> {
+        let mut coords = Vec::new();
+        while let Some(req) = queue.pop() {
+            coords.push(req.coord);
+        }
+        coords
+    }
+
+    #[test]
+    fn closest_to_focus_first() {
+        let mut queue = Queue::new();
+        queue.set_focus((5.5, 0.5), (0.0, 0.0));
+        for &x in &[0, 9, 5, 3, 6] {
+            queue.submit(request((x, 0), Priority::Visible)).ok().unwrap();
+        }
+
+        assert_eq!(pop_all(&mut queue), vec![(5, 0), (6, 0), (3, 0), (9, 0), (0, 0)]);
+    }
+
+    #[test]
+    fn ties_in_request_order() {
+        let mut queue = Queue::new();
+        queue.set_focus((0.5, 0.5), (0.0, 0.0));
+        for &coord in &[(1, 0), (0, 1), (-1, 0), (0, -1)] {
+            queue.submit(request(coord, Priority::Visible)).ok().unwrap();
+        }
+
+        assert_eq!(pop_all(&mut queue), vec![(1, 0), (0, 1), (-1, 0), (0, -1)]);
+    }
+
+    #[test]
+    fn visible_before_prefetch() {
+        let mut queue = Queue::new();
+        queue.submit(request((0, 0), Priority::Prefetch)).ok().unwrap();
+        queue.submit(request((10, 10), Priority::Visible)).ok().unwrap();
+
+        assert_eq!(pop_all(&mut queue), vec![(10, 10), (0, 0)]);
+    }
+
+    #[test]
+    fn prefetches_ordered_around_lead() {
+        let mut queue = Queue::new();
+        queue.submit(request((-2, 0), Priority::Prefetch)).ok().unwrap();
+        queue.submit(request((2, 0), Priority::Prefetch)).ok().unwrap();
+        queue.set_focus((0.5, 0.5), (2.0, 0.0));
+
+        assert_eq!(pop_all(&mut queue), vec![(2, 0), (-2, 0)]);
+    }
+
+    #[test]
+    fn duplicates_rejected() {
+        let mut queue = Queue::new();
+        queue.submit(request((0, 0), Priority::Visible)).ok().unwrap();
+
+        assert!(queue.submit(request((0, 0), Priority::Visible)).is_err());
+        assert!(queue.submit(request((0, 0), Priority::Prefetch)).is_err());
+        assert_eq!(pop_all(&mut queue), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn prefetch_upgraded_to_visible() {
+        let mut queue = Queue::new();
+        queue.submit(request((0, 0), Priority::Prefetch)).ok().unwrap();
+        queue.submit(request((5, 5), Priority::Visible)).ok().unwrap();
+        queue.submit(request((0, 0), Priority::Visible)).ok().unwrap();
+
+        let first = queue.pop().unwrap();
+        assert_eq!(first.coord, (0, 0));
+        assert_eq!(first.priority(), Priority::Visible);
+        assert_eq!(pop_all(&mut queue), vec![(5, 5)]);
+    }
+
+    #[test]
+    fn cancelled_not_popped() {
+        let mut queue = Queue::new();
+        for x in 0..4 {
+            queue.submit(request((x, 0), Priority::Visible)).ok().unwrap();
+        }
+
+        assert_eq!(queue.cancel((1, 0)).map(|r| r.coord), Some((1, 0)));
+        assert!(queue.cancel((1, 0)).is_none());
+        assert_eq!(pop_all(&mut queue), vec![(0, 0), (2, 0), (3, 0)]);
+    }
+
+    #[test]
+    fn resubmitted_after_cancel_uses_new_priority() {
+        let mut queue = Queue::new();
+        queue.submit(request((0, 0), Priority::Visible)).ok().unwrap();
+        queue.submit(request((5, 5), Priority::Visible)).ok().unwrap();
+        queue.cancel((0, 0));
+        queue.submit(request((0, 0), Priority::Prefetch)).ok().unwrap();
+
+        assert_eq!(pop_all(&mut queue), vec![(5, 5), (0, 0)]);
+    }
+
+    #[test]
+    fn cancel_prefetches_keeps_visible() {
+        let mut queue = Queue::new();
+        queue.submit(request((0, 0), Priority::Visible)).ok().unwrap();
+        queue.submit(request((1, 0), Priority::Prefetch)).ok().unwrap();
+        queue.submit(request((2, 0), Priority::Prefetch)).ok().unwrap();
+
+        let mut cancelled = queue.cancel_prefetches(|coord| coord == (2, 0));
+        cancelled.sort();
+        assert_eq!(cancelled, vec![(1, 0)]);
+        assert_eq!(pop_all(&mut queue), vec![(0, 0), (2, 0)]);
+    }
+
+    #[test]
+    fn heap_compacted_after_cancelling() {
+        let mut queue = Queue::new();
+        queue.submit(request((0, 0), Priority::Visible)).ok().unwrap();
+        for x in 1..100 {
+            queue.submit(request((x, 0), Priority::Visible)).ok().unwrap();
+            queue.cancel((x, 0));
+        }
+
+        assert_eq!(queue.requests.len(), 1);
+        assert!(queue.heap.len() <= 2);
+    }
+
+    #[test]
+    fn visible_joins_running_prefetch() {
+        let mut queue = Queue::new();
+        queue.submit(request((0, 0), Priority::Prefetch)).ok().unwrap();
+        assert_eq!(queue.pop().map(|r| r.coord), Some((0, 0)));
+
+        assert!(queue.submit(request((0, 0), Priority::Prefetch)).is_err());
+        assert!(queue.submit(request((0, 0), Priority::Visible)).is_ok());
+        assert!(queue.submit(request((0, 0), Priority::Visible)).is_err());
+        assert!(queue.pop().is_none());
+
+        let joined = queue.finish((0, 0));
+        assert_eq!(joined.len(), 1);
+        assert_eq!(joined[0].priority(), Priority::Visible);
+
+        // loaded, so it can be requested again
+        assert!(queue.submit(request((0, 0), Priority::Visible)).is_ok());
+    }
+
+    #[test]
+    fn nothing_joins_running_load() {
+        let mut queue = Queue::new();
+        queue.submit(request((0, 0), Priority::Visible)).ok().unwrap();
+        queue.pop().unwrap();
+
+        assert!(queue.submit(request((0, 0), Priority::Visible)).is_err());
+        assert!(queue.submit(request((0, 0), Priority::Prefetch)).is_err());
+        assert!(queue.finish((0, 0)).is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+use log::{self, LevelFilter, Log, Metadata, Record};
+
+use error::*;
+
+/// Prefix of the targets of this crate's modules, so filters can just say e.g. "world"
+const CRATE_PREFIX: &str = "sim::";
+
+struct Logger {
+    default: LevelFilter,
+
+    /// Longest match wins
+    targets: Vec<(String, LevelFilter)>,
+
+    start: Instant,
+    out: Mutex<Box<Write + Send>>,
+}
+
+/// Times a section of work, logging how long it took when dropped
+pub struct Span {
+    target: &'static str,
+    name: String,
+    start: Instant,
+}
+
+/// Installs the global logger. `spec` is a comma separated list of a default level and
+/// target=level pairs, e.g. "info,world=debug,chunk_req=warn". Logs go to stderr unless a file
+/// is given, which is appended to
+pub fn init(spec: &str, file: Option<&Path>) -> SimResult<()> {
+    let (default, targets) = parse_spec(spec)?;
+
+    let out: Box<Write + Send> = match file {
+        Some(path) => Box::new(fs::OpenOptions::new().create(true).append(true).open(path)?),
+        None => Box::new(io::stderr()),
+    };
+
+    let max = targets.iter().map(|&(_, l)| l).fold(default, ::std::cmp::max);
+    let logger = Logger {
+        default,
+        targets,
+        start: Instant::now(),
+        out: Mutex::new(out),
+    };
+
+    log::set_boxed_logger(Box::new(logger))
+        .map_err(|e| ErrorKind::BadLogConfig(e.to_string()))?;
+    log::set_max_level(max);
+    Ok(())
+}
+
+fn parse_spec(spec: &str) -> SimResult<(LevelFilter, Vec<(String, LevelFilter)>)> {
+    let parse_level = |s: &str| s.trim().parse::<LevelFilter>()
+        .map_err(|_| ErrorKind::BadLogConfig(format!("unknown level '{}'", s)));
+
+    let mut default = LevelFilter::Info;
+    let mut targets = Vec::new();
+
+    for part in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let mut split = part.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(target), Some(level)) => targets.push((target.trim().to_owned(), parse_level(level)?)),
+            (Some(level), None) => default = parse_level(level)?,
+            _ => unreachable!(),
+        }
+    }
+
+    Ok((default, targets))
+}
+
+impl Logger {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let target = if target.starts_with(CRATE_PREFIX) {
+            &target[CRATE_PREFIX.len()..]
+        } else {
+            target
+        };
+
+        self.targets.iter()
+            .filter(|&&(ref t, _)| target == t.as_str() || target.starts_with(&format!("{}::", t)))
+            .max_by_key(|&&(ref t, _)| t.len())
+            .map(|&(_, l)| l)
+            .unwrap_or(self.default)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let elapsed = self.start.elapsed();
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{:>9.3} {:<5} {}: {}", secs, record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self) {
+        let _ = self.out.lock().unwrap().flush();
+    }
+}
+
+impl Span {
+    pub fn new(target: &'static str, name: String) -> Self {
+        trace!(target: target, "{} started", name);
+        Span {
+            target,
+            name,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let ms = elapsed.as_secs() as f64 * 1000.0 + f64::from(elapsed.subsec_nanos()) * 1e-6;
+        debug!(target: self.target, "{} took {:.1} ms", self.name, ms);
+    }
+}
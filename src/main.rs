@@ -5,6 +5,9 @@ extern crate chunk_req;
 extern crate std_semaphore;
 extern crate serde;
 extern crate serde_json;
+extern crate csv;
+extern crate zip;
+extern crate xml;
 
 #[macro_use]
 extern crate serde_derive;
@@ -15,43 +18,201 @@ extern crate lazy_static;
 #[macro_use]
 extern crate error_chain;
 
+#[macro_use]
+extern crate log;
+
 use sfml::graphics::*;
 use sfml::window::*;
 use sfml::system::*;
 use std::env;
+use std::path::{Path, PathBuf};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::sync::mpsc::{self, Sender, Receiver};
+use std::time::Duration;
 
 mod world;
 mod error;
 mod parser;
+mod osm_xml;
 mod latlon;
+mod loader;
+mod camera;
+mod bindings;
+mod picking;
+mod inspector;
+mod triangulate;
+mod geometry;
+mod stroke;
+mod labels;
+mod style;
+mod svg;
+mod simplify;
+mod minimap;
+mod hud;
+mod logging;
+mod tags;
+mod poi;
+mod classify;
+mod transit;
+mod gtfs;
+mod planner;
 mod building;
 
 use world::*;
 use error::*;
+use camera::CameraChange;
+use geometry::ChunkGeometry;
+use labels::ChunkLabels;
+use simplify::ChunkLod;
+use minimap::Minimap;
+use hud::Hud;
+use bindings::{Action, Bindings};
+use style::{Style as MapStyle, StyleWatcher};
+use gtfs::{SimDay, Timetable, TransitSim};
+use planner::{Journey, Leg, Planner};
+
+/// Screen pixels the cursor can move between press and release to still count as a click
+const CLICK_DISTANCE: i32 = 4;
+
+/// Screen pixels from a road or point of interest that still counts as hovering over it
+const PICK_TOLERANCE: f64 = 5.0;
+
+/// Screen pixels from a selected point of interest to its highlight
+const POI_HIGHLIGHT_RADIUS: f32 = 8.0;
+
+/// Chunks beyond the edge of the screen to fetch in the background
+const DEFAULT_PREFETCH_RADIUS: i32 = 1;
+
+const DEFAULT_STYLE_PATH: &str = "res/style.json";
 
-const MOVE_SPEED: f64 = 5.0;
-const ZOOM_SPEED: f64 = 0.05;
+const DEFAULT_LOG: &str = "info";
+
+/// Frames between checks for changes to the style file
+const STYLE_POLL_FRAMES: u32 = 30;
+
+/// Screen pixels from the centre of a transit vehicle to its edge
+const VEHICLE_RADIUS: f32 = 3.0;
+
+/// Simulated seconds per real second
+const DEFAULT_SIM_SPEED: f64 = 60.0;
+
+const DEFAULT_SIM_START: &str = "08:00:00";
 
 fn main() {
-    let origin = {
-        let var = env::var("LATLON");
-        let mut split = var.as_ref().expect("$LATLON missing in env").split(',');
-        let (lat, lon): (f64, f64) = match (split.next(), split.next()) {
-            (Some(slat), Some(slon)) => (slat.parse().expect("Bad latitude"), slon.parse().expect("Bad longitude")),
-            _ => panic!("<lat>,<lon> expected"),
-        };
-        LatLon::new(lat, lon)
+    {
+        let spec = env::var("LOG").unwrap_or_else(|_| DEFAULT_LOG.to_owned());
+        let file = env::var("LOG_FILE").ok();
+        logging::init(&spec, file.as_ref().map(Path::new)).expect("Failed to initialise logging");
+    }
+
+    let origin = parse_latlon(&env::var("LATLON").expect("$LATLON missing in env"));
+
+    let client = chunk_req::Client::new(overpass_config()).expect("Failed to create overpass client");
+    let mut world = World::new(String::from("test"), origin, client);
+
+    // strict unless asked otherwise
+    if let Ok(mode) = env::var("OSM_PARSE_MODE") {
+        world.set_parse_mode(match mode.as_ref() {
+            "strict" => parser::ParseMode::Strict,
+            "lenient" => parser::ParseMode::Lenient,
+            _ => panic!("Bad parse mode, expected strict or lenient"),
+        });
+    }
+
+    let style = {
+        let path = env::var("STYLE").unwrap_or_else(|_| DEFAULT_STYLE_PATH.to_owned());
+        StyleWatcher::load(PathBuf::from(path)).expect("Failed to load style")
+    };
+
+    // export instead of opening the viewer
+    if let Ok(path) = env::var("EXPORT_PNG") {
+        render_png(&mut world, &style.style, &path, (500, 500));
+        return;
+    }
+    if let Ok(path) = env::var("EXPORT_SVG") {
+        world.request_chunk_sync(0, 0).unwrap();
+        world.request_chunk_sync(0, -1).unwrap();
+        svg::export(&world, &style.style, Path::new(&path)).expect("Failed to export svg");
+        return;
+    }
+
+    let prefetch_radius = env::var("PREFETCH_RADIUS")
+        .map(|r| r.parse().expect("Bad prefetch radius"))
+        .unwrap_or(DEFAULT_PREFETCH_RADIUS);
+
+    let bindings = match env::var("KEY_BINDINGS") {
+        Ok(path) => Bindings::load(path.as_ref()).expect("Failed to load key bindings"),
+        Err(_) => Bindings::default(),
+    };
+
+    let transit = env::var("GTFS").ok().map(|path| transit_sim(&path, &world.origin));
+
+    // somewhere other than the origin to start, and to go back to with home
+    let start = env::var("START_LATLON").ok().map(|s| parse_latlon(&s));
+
+     Renderer::new(500, 500, &mut world, prefetch_radius, bindings, style, transit, start).start().unwrap();
+}
+
+/// "<lat>,<lon>"
+fn parse_latlon(s: &str) -> LatLon {
+    let mut split = s.split(',');
+    let (lat, lon): (f64, f64) = match (split.next(), split.next()) {
+        (Some(slat), Some(slon)) => (slat.trim().parse().expect("Bad latitude"), slon.trim().parse().expect("Bad longitude")),
+        _ => panic!("<lat>,<lon> expected"),
     };
+    LatLon::new(lat, lon)
+}
+
+/// Runs the feed's timetable from $SIM_START on $SIM_DATE, or its first day if not given
+fn transit_sim(path: &str, origin: &LatLon) -> TransitSim {
+    let timetable = Timetable::load(Path::new(path), origin).expect("Failed to load gtfs feed");
+
+    let date = match env::var("SIM_DATE") {
+        Ok(date) => date.parse().expect("Bad simulation date, expected yyyymmdd"),
+        Err(_) => timetable.first_date().expect("$SIM_DATE missing in env, and the feed has no calendar"),
+    };
+    let day = SimDay::from_date(date).unwrap_or_else(|| panic!("Bad simulation date {}", date));
+
+    let start = env::var("SIM_START").unwrap_or_else(|_| DEFAULT_SIM_START.to_owned());
+    let start = gtfs::parse_time(&start).expect("Bad simulation start time, expected hh:mm:ss");
+
+    let speed = env::var("SIM_SPEED")
+        .map(|s| s.parse().expect("Bad simulation speed"))
+        .unwrap_or(DEFAULT_SIM_SPEED);
+
+    TransitSim::new(timetable, day, start, speed)
+}
+
+// all optional, falling back to the public overpass instance
+fn overpass_config() -> chunk_req::ClientConfig {
+    let mut config = chunk_req::ClientConfig::default();
 
-    let mut world = World::new(String::from("test"), origin);
+    if let Ok(url) = env::var("OVERPASS_URL") {
+        config.base_url = url;
+    }
+    if let Ok(secs) = env::var("OVERPASS_TIMEOUT") {
+        let secs: u64 = secs.parse().expect("Bad overpass timeout");
+        config.timeout = if secs == 0 { None } else { Some(Duration::from_secs(secs)) };
+    }
+    if let Ok(agent) = env::var("OVERPASS_USER_AGENT") {
+        config.user_agent = agent;
+    }
+    if let Ok(proxy) = env::var("OVERPASS_PROXY") {
+        config.proxy = Some(proxy);
+    }
+    if let Ok(max) = env::var("OVERPASS_MAX_SIZE") {
+        let max: u64 = max.parse().expect("Bad overpass max response size");
+        config.max_response_size = if max == 0 { None } else { Some(max) };
+    }
+    if let Ok(attempts) = env::var("OVERPASS_ATTEMPTS") {
+        config.retry.max_attempts = attempts.parse().expect("Bad overpass attempt count");
+    }
 
-     // render_png(&mut world, "/tmp/render.png", (500, 500));
-     Renderer::new(500, 500, &mut world).start().unwrap();
+    config
 }
 
-fn render_png(world: &mut World, out_path: &'static str, dims: (u32, u32)) {
+fn render_png(world: &mut World, style: &MapStyle, out_path: &str, dims: (u32, u32)) {
 
     world.request_chunk_sync(0, 0).unwrap();
     world.request_chunk_sync(0, -1).unwrap();
@@ -60,7 +221,7 @@ fn render_png(world: &mut World, out_path: &'static str, dims: (u32, u32)) {
         let size = Vector2f::new(dims.0 as f32, dims.1 as f32);
         let mut t = RenderTexture::new(dims.0, dims.1, false).unwrap();
         let mut r = RectangleShape::with_size(size);
-        r.set_fill_color(&Color::BLACK);
+        r.set_fill_color(&style.background.0);
         t.draw(&r);
 
         let mut v = t.view().to_owned();
@@ -70,9 +231,17 @@ fn render_png(world: &mut World, out_path: &'static str, dims: (u32, u32)) {
         t
     };
 
-    render_world(&mut texture, world, None);
+    let geometry: HashMap<_, _> = world.loaded_chunk_coords().into_iter()
+        .map(|(x, y)| {
+            let features = world.chunk_features(x, y);
+            let lod = ChunkLod::build(world, features);
+            ((x, y), ChunkGeometry::build(world, features, 1.0, style, &lod))
+        })
+        .collect();
+
+    render_world(&mut texture, world, &geometry, None);
     let mut copy = texture.texture().copy_to_image().unwrap();
-    println!("Saving to {:?}", out_path);
+    info!(target: "renderer", "Saving to {:?}", out_path);
     copy.flip_vertically();
     copy.save_to_file(out_path);
 
@@ -96,20 +265,61 @@ enum StateChange {
 #[derive(Debug)]
 struct ChunkState(LoadState, StateChange);
 
+fn get_state_colour(state: &LoadState, progress: f64) -> Color {
+    let mut c = match *state {
+        LoadState::Loading => Color::GREEN,
+        LoadState::Unloading => Color::BLUE,
+        LoadState::Unloaded => Color::BLACK,
+        LoadState::Failed => Color::RED,
+    };
+    c.a = (progress * 255.0) as u8;
+    c
+}
+
 struct Renderer<'a> {
     window: RenderWindow,
     world: &'a mut World,
 
-    render_cache: Vec<Vertex>,
+    /// Built when a chunk finishes loading and dropped when it unloads
+    geometry: HashMap<(i32, i32), ChunkGeometry>,
+
+    /// Simplified features, kept to rebuild the geometry at other zooms
+    lods: HashMap<(i32, i32), ChunkLod>,
+
+    /// Laid out road names, dropped along with the geometry
+    labels: HashMap<(i32, i32), ChunkLabels>,
+
+    /// Zoom bucket the geometry was built for
+    geometry_zoom: f64,
     chunk_size: Vector2i,
 
     load_new_chunks: bool,
+    prefetch_radius: i32,
+    bindings: Bindings,
+    style: StyleWatcher,
     chunk_states: HashMap<(i32, i32), ChunkState>,
     load_channel: (Sender<world::PartialChunk>, Receiver<world::PartialChunk>),
+
+    minimap: Minimap,
+    hud: Hud,
+
+    cursor: Vector2i,
+    press_pos: Option<Vector2i>,
+    hovered: Option<Feature>,
+    selected: Option<Feature>,
+
+    transit: Option<TransitSim>,
+
+    /// Where the camera starts and goes back to, instead of the origin's chunk
+    start: Option<LatLon>,
+
+    /// Set by the first right click, and planned to from the second
+    trip_origin: Option<Point>,
+    journey: Option<Journey>,
 }
 
 impl<'a> Renderer<'a> {
-    fn new(width: u32, height: u32, world: &'a mut World) -> Self {
+    fn new(width: u32, height: u32, world: &'a mut World, prefetch_radius: i32, bindings: Bindings, style: StyleWatcher, transit: Option<TransitSim>, start: Option<LatLon>) -> Self {
         let mut window = RenderWindow::new(
             (width, height),
             "Hiya",
@@ -131,11 +341,27 @@ impl<'a> Renderer<'a> {
         Self {
             window,
             world,
-            render_cache: Vec::new(),
+            geometry: HashMap::new(),
+            lods: HashMap::new(),
+            labels: HashMap::new(),
+            geometry_zoom: 1.0,
             chunk_size,
             load_new_chunks: true,
+            prefetch_radius,
+            bindings,
+            style,
             chunk_states: HashMap::new(),
             load_channel: mpsc::channel(),
+            minimap: Minimap::default(),
+            hud: Hud::new(),
+            cursor: Vector2i::new(0, 0),
+            press_pos: None,
+            hovered: None,
+            selected: None,
+            transit,
+            start,
+            trip_origin: None,
+            journey: None,
         }
     }
 
@@ -146,104 +372,395 @@ impl<'a> Renderer<'a> {
 
     fn start(mut self) -> SimResult<()> {
         let mut cam = CameraChange::new(self.window.size(), 0.4);
-        self.centre_on_chunk(0, 0);
+        self.teleport_home(&mut cam);
 
-        self.request_chunk_async(0, 0);
+        if self.start.is_none() {
+            self.request_chunk_async(0, 0);
+        }
 
         let font = Font::from_file("res/ScreenMedium.ttf").expect("Could not load font");
         let mut text = Text::new("", &font, 8);
+        let mut inspector_text = Text::new("", &font, 12);
+        let mut label_text = {
+            let mut t = Text::new("", &font, 11);
+            t.set_fill_color(&Color::WHITE);
+            t.set_outline_color(&Color::BLACK);
+            t.set_outline_thickness(1.0);
+            t
+        };
 
-        let background_colour = Color::rgb(40, 40, 50);
+        let mut frame: u32 = 0;
         loop {
+            frame = frame.wrapping_add(1);
+            self.hud.tick();
+
             while let Some(e) = self.window.poll_event() {
                 match e {
-                    Event::KeyPressed { code: Key::Escape, .. } => return Ok(()),
-                    Event::KeyPressed { code: Key::Space, .. } => {
-                        self.load_new_chunks = !self.load_new_chunks;
-                        println!("Loading new chunks: {}", self.load_new_chunks);
+                    Event::KeyPressed { code, .. } => match self.bindings.action(code) {
+                        Some(Action::Quit) => return Ok(()),
+                        Some(Action::Home) => self.teleport_home(&mut cam),
+                        Some(Action::ToggleHud) => self.hud.visible = !self.hud.visible,
+                        Some(Action::RetryFailed) => self.retry_failed_chunks(),
+                        Some(Action::ToggleLoading) => {
+                            self.load_new_chunks = !self.load_new_chunks;
+                            info!(target: "renderer", "Loading new chunks: {}", self.load_new_chunks);
+                        },
+                        Some(action) => cam.handle_action(action, true),
+                        None => {}
+                    },
+                    Event::KeyReleased { code, .. } => {
+                        if let Some(action) = self.bindings.action(code) {
+                            cam.handle_action(action, false);
+                        }
+                    },
+                    Event::MouseButtonPressed { button: mouse::Button::Left, x, y } => {
+                        if let Some(pos) = self.minimap.world_at(x, y) {
+                            cam.teleport(pos);
+                        } else {
+                            self.press_pos = Some(Vector2i::new(x, y));
+                            cam.start_drag(x, y);
+                        }
                     },
-                    Event::KeyPressed { code, .. } => cam.handle_key(code, true),
-                    Event::KeyReleased { code, .. } => cam.handle_key(code, false),
+                    Event::MouseButtonReleased { button: mouse::Button::Left, x, y } => {
+                        cam.end_drag();
+
+                        // select whatever's under the cursor, unless it was a drag
+                        if let Some(from) = self.press_pos.take() {
+                            if (x - from.x).abs() <= CLICK_DISTANCE && (y - from.y).abs() <= CLICK_DISTANCE {
+                                self.selected = self.pick(Vector2i::new(x, y), &cam);
+                            }
+                        }
+                    },
+                    Event::MouseButtonReleased { button: mouse::Button::Right, x, y } => {
+                        self.plan_trip(Vector2i::new(x, y));
+                    },
+                    Event::MouseMoved { x, y } => {
+                        self.cursor = Vector2i::new(x, y);
+                        cam.mouse_moved(x, y);
+                    },
+                    Event::MouseWheelScrolled { delta, x, y, .. } => cam.scroll(delta, x, y),
                     Event::Resized { width, height } => cam.resize(width, height),
                     _ => {}
                 }
             }
 
+            if let Some(ref mut transit) = self.transit {
+                transit.tick();
+            }
+
             // tick chunk states
-            self.chunk_states.retain(|&_, state| {
-                if let ChunkState(_, StateChange::Counter(ref mut i)) = *state {
+            let mut expired = Vec::new();
+            self.chunk_states.retain(|&coord, state| {
+                if let ChunkState(ref load_state, StateChange::Counter(ref mut i)) = *state {
                     *i -= 0.01;
-                    *i > 0.0
-                } else {
-                    true
+                    if *i <= 0.0 {
+                        if let LoadState::Unloading = *load_state {
+                            expired.push(coord);
+                        }
+                        return false;
+                    }
                 }
+                true
             });
 
+            for (x, y) in expired {
+                self.unload_chunk(x, y);
+            }
+
             // update chunk states with new
-            {
+            let (pan_x, pan_y) = cam.pan_direction();
+            let visible_changed = {
                 let chunk_changes = cam.apply(&mut self.window, self.chunk_size);
+
+                // load closest to the centre of the screen first, and prefetch ahead of it
+                let centre = self.window.view().center();
+                let lead = f64::from(self.prefetch_radius);
+                self.world.set_load_focus((
+                    f64::from(centre.x) / f64::from(self.chunk_size.x),
+                    f64::from(centre.y) / f64::from(self.chunk_size.y),
+                ), (pan_x * lead, pan_y * lead));
+
                 if self.load_new_chunks && !chunk_changes.is_empty() {
                     for c in chunk_changes.iter() {
                         let state = if c.load {
                             self.request_chunk_async(c.x, c.y);
                             ChunkState(LoadState::Loading, StateChange::Constant)
                         } else {
+                            // no point loading something that's already scrolled out of view
+                            self.world.cancel_chunk_request(c.x, c.y);
                             ChunkState(LoadState::Unloading, StateChange::Counter(1.0))
                         };
 
-                        // TODO temporarily disable unloading
-                        if let ChunkState(LoadState::Loading, _) = state {
-                            self.chunk_states.insert((c.x, c.y), state);
-                        }
+                        self.chunk_states.insert((c.x, c.y), state);
                     }
                 }
+
+                self.load_new_chunks && !chunk_changes.is_empty()
+            };
+
+            if visible_changed {
+                self.prefetch_around(&cam);
+            }
+
+            // road widths depend on the zoom
+            let zoom = geometry::zoom_bucket(cam.z);
+            let restyled = frame % STYLE_POLL_FRAMES == 0 && self.style.poll();
+            if zoom != self.geometry_zoom || restyled {
+                self.geometry_zoom = zoom;
+                let coords: Vec<_> = self.geometry.keys().cloned().collect();
+                for coord in coords {
+                    self.build_geometry(coord);
+                }
             }
 
+            self.world.finish_prefetches();
+
             // finish loading for loaded chunks
             while let Ok(chunk) = self.load_channel.1.try_recv() {
-                let PartialChunk(res, coord) = chunk;
+                let coord = chunk.1;
                 self.chunk_states.remove(&coord);
 
-                match res {
-                    Err(Error(ErrorKind::ChunkAlreadyLoaded(_), _)) => {}
+                match self.world.finish_chunk_request(chunk) {
+                    Err(Error(ErrorKind::ChunkAlreadyLoaded(_), _)) |
+                    Err(Error(ErrorKind::ChunkRequestCancelled(_), _)) => {}
                     Err(e) => {
-                        println!("Failed to load a chunk: {}", e.description());
+                        warn!(target: "renderer", "{}", e);
                         self.chunk_states.insert(coord, ChunkState(LoadState::Failed, StateChange::Constant));
                     },
-                    Ok(_) => self.world.finish_chunk_request(PartialChunk(res, coord)),
+                    Ok(()) => {
+                        self.load_geometry(coord);
+
+                        if let Some(ref mut transit) = self.transit {
+                            let matched = transit.timetable.match_stops(self.world);
+                            if matched > 0 {
+                                debug!(target: "renderer", "Matched {} gtfs stops", matched);
+                            }
+                        }
+
+                        // scrolled away while it was being fetched
+                        let (x, y) = coord;
+                        if x < cam.min_chunk.0 || x > cam.max_chunk.0 || y < cam.min_chunk.1 || y > cam.max_chunk.1 {
+                            self.chunk_states.insert(coord, ChunkState(LoadState::Unloading, StateChange::Counter(1.0)));
+                        }
+                    },
                 }
             }
 
 
-            self.window.clear(&background_colour);
+            let background = self.style.style.background.0;
+            self.window.clear(&background);
+            let cursor = self.cursor;
+            self.hovered = self.pick(cursor, &cam);
+
             self.render_world(&mut text, &cam);
+            self.render_journey();
+            self.render_vehicles();
+            labels::render(&mut self.window, self.world, &mut self.labels, &mut label_text, cam.z);
+            self.render_minimap(&cam);
+            if self.hud.visible {
+                let stats = self.world.stats();
+                self.hud.render(&mut self.window, &mut inspector_text, &stats);
+            }
+            if let Some(description) = self.selected.and_then(|f| inspector::describe(self.world, f)) {
+                inspector::render(&mut self.window, &mut inspector_text, &description);
+            }
             self.window.display();
         }
     }
 
-    fn centre_on_chunk(&mut self, x: i32, y: i32) {
-        let mut view = self.window.view().to_owned();
+    /// Swaps the queued prefetches for the ring of chunks around the current view, extended
+    /// further in the direction the camera is panning
+    fn prefetch_around(&mut self, cam: &CameraChange) {
+        if self.prefetch_radius <= 0 {
+            return;
+        }
+
+        let r = self.prefetch_radius;
+        let (pan_x, pan_y) = cam.pan_direction();
+        let ahead = |pan: f64| if pan > 0.0 { r } else { 0 };
+
+        let min = (cam.min_chunk.0 - r - ahead(-pan_x), cam.min_chunk.1 - r - ahead(-pan_y));
+        let max = (cam.max_chunk.0 + r + ahead(pan_x), cam.max_chunk.1 + r + ahead(pan_y));
+        let in_ring = |(x, y): (i32, i32)| {
+            let visible = x >= cam.min_chunk.0 && x <= cam.max_chunk.0 &&
+                y >= cam.min_chunk.1 && y <= cam.max_chunk.1;
+            let in_range = x >= min.0 && x <= max.0 && y >= min.1 && y <= max.1;
+            in_range && !visible
+        };
+
+        self.world.cancel_prefetches(&in_ring);
+
+        for x in min.0..max.0 + 1 {
+            for y in min.1..max.1 + 1 {
+                if in_ring((x, y)) {
+                    self.world.prefetch_chunk(x, y);
+                }
+            }
+        }
+    }
+
+    fn retry_failed_chunks(&mut self) {
+        let coords = self.world.retry_failed_chunks(&self.load_channel.0);
+        info!(target: "renderer", "Retrying {} failed chunks", coords.len());
 
+        for coord in coords {
+            self.chunk_states.insert(coord, ChunkState(LoadState::Loading, StateChange::Constant));
+        }
+    }
+
+    /// Drops the chunk's geometry, and rebuilds any chunks that took over drawing its features
+    fn unload_chunk(&mut self, x: i32, y: i32) {
+        self.geometry.remove(&(x, y));
+        self.lods.remove(&(x, y));
+        self.labels.remove(&(x, y));
+        for coord in self.world.unload_chunk(x, y) {
+            self.load_geometry(coord);
+        }
+    }
+
+    /// Simplifies the chunk's features and drops their labels, then builds its geometry
+    fn load_geometry(&mut self, (x, y): (i32, i32)) {
+        let lod = ChunkLod::build(self.world, self.world.chunk_features(x, y));
+        self.lods.insert((x, y), lod);
+        self.labels.remove(&(x, y));
+        self.build_geometry((x, y));
+    }
+
+    /// Builds the chunk's geometry for the current zoom and style
+    fn build_geometry(&mut self, (x, y): (i32, i32)) {
+        let geom = match self.lods.get(&(x, y)) {
+            Some(lod) => ChunkGeometry::build(self.world, self.world.chunk_features(x, y), self.geometry_zoom, &self.style.style, lod),
+            None => return,
+        };
+        self.geometry.insert((x, y), geom);
+    }
+
+    fn teleport_to_chunk(&self, cam: &mut CameraChange, x: i32, y: i32) {
         let (cx, cy) = (self.chunk_size.x as f32, self.chunk_size.y as f32);
-        view.set_center(
-            (cx * x as f32 + cx / 2.0,
-             cy * y as f32 + cy / 2.0));
-        self.window.set_view(&view);
+        cam.teleport(Vector2f::new(
+            cx * x as f32 + cx / 2.0,
+            cy * y as f32 + cy / 2.0));
     }
 
-    fn render_world(&mut self, text: &mut Text, cam: &CameraChange) {
-        fn get_state_colour(state: &LoadState, progress: f64) -> Color {
-            let mut c = match *state {
-                LoadState::Loading => Color::GREEN,
-                LoadState::Unloading => Color::BLUE,
-                LoadState::Unloaded => Color::BLACK,
-                LoadState::Failed => Color::RED,
+    fn teleport_home(&self, cam: &mut CameraChange) {
+        match self.start {
+            Some(ref start) => self.teleport_to_latlon(cam, start),
+            None => self.teleport_to_chunk(cam, 0, 0),
+        }
+    }
+
+    fn teleport_to_latlon(&self, cam: &mut CameraChange, latlon: &LatLon) {
+        let p = self.world.convert_latlon_to_pixel(latlon);
+        cam.teleport(Vector2f::new(p.x as f32, p.y as f32));
+    }
+
+    fn pick(&self, pixel: Vector2i, cam: &CameraChange) -> Option<Feature> {
+        let pos = self.window.map_pixel_to_coords_current_view(&pixel);
+        picking::pick(self.world, f64::from(pos.x), f64::from(pos.y), PICK_TOLERANCE * cam.z)
+    }
+
+    /// Plans a trip from the last right clicked point to this one, on foot and by transit if
+    /// there's a timetable
+    fn plan_trip(&mut self, pixel: Vector2i) {
+        let pos = self.window.map_pixel_to_coords_current_view(&pixel);
+        let point = Point { x: pos.x as i32, y: pos.y as i32 };
+
+        let from = match self.trip_origin.take() {
+            Some(from) => from,
+            None => {
+                self.trip_origin = Some(point);
+                self.journey = None;
+                return;
+            }
+        };
+
+        let transit = match self.transit {
+            Some(ref transit) => transit,
+            None => {
+                info!(target: "renderer", "No gtfs feed to plan with");
+                return;
+            }
+        };
+
+        let planner = Planner::new(&transit.timetable, &transit.day, latlon::pixels_per_metre(&self.world.origin));
+        let journey = planner.plan(from, point, transit.time);
+        for leg in &journey.legs {
+            match *leg {
+                Leg::Walk { departure, arrival, .. } => {
+                    info!(target: "renderer", "{} - {} walk", format_time(departure), format_time(arrival));
+                }
+                Leg::Ride { trip, from: board, to: alight, departure, arrival } => {
+                    let timetable = &transit.timetable;
+                    let route = &timetable.routes[timetable.trips[trip].route];
+                    info!(target: "renderer", "{} - {} {:?} {} from {} to {}", format_time(departure), format_time(arrival),
+                          route.mode, route.short_name, timetable.stops[board].name, timetable.stops[alight].name);
+                }
+            }
+        }
+        self.journey = Some(journey);
+    }
+
+    /// Walks in white and rides in the colour of their mode
+    fn render_journey(&mut self) {
+        let (journey, transit) = match (&self.journey, &self.transit) {
+            (&Some(ref journey), &Some(ref transit)) => (journey, transit),
+            _ => return,
+        };
+
+        let style = &self.style.style;
+        let mut vertices = Vec::new();
+        for leg in &journey.legs {
+            let (from, to, colour) = match *leg {
+                Leg::Walk { from, to, .. } => (from, to, Color::WHITE),
+                Leg::Ride { trip, from, to, .. } => {
+                    let timetable = &transit.timetable;
+                    let mode = timetable.routes[timetable.trips[trip].route].mode;
+                    (timetable.stops[from].position, timetable.stops[to].position, style.transit_line(mode).colour.0)
+                }
             };
-            c.a = (progress * 255.0) as u8;
-            c
+
+            vertices.push(Vertex::with_pos_color(Vector2f::new(from.x as f32, from.y as f32), colour));
+            vertices.push(Vertex::with_pos_color(Vector2f::new(to.x as f32, to.y as f32), colour));
         }
+        self.window.draw_primitives(&vertices, PrimitiveType::Lines, RenderStates::default());
+    }
 
-        render_world(&mut self.window, self.world, Some(&mut self.render_cache));
+    /// Squares the same size on screen at any zoom, in the colour of their mode
+    fn render_vehicles(&mut self) {
+        let transit = match self.transit {
+            Some(ref transit) => transit,
+            None => return,
+        };
+
+        let r = VEHICLE_RADIUS * self.window.view().size().x / self.window.size().x as f32;
+        let style = &self.style.style;
+        let mut vertices = Vec::new();
+        for v in transit.vehicles() {
+            let colour = style.transit_line(transit.mode_of(&v)).colour.0;
+            let (x, y) = (v.position.x as f32, v.position.y as f32);
+            for &(dx, dy) in &[(-r, -r), (r, -r), (r, r), (-r, r)] {
+                vertices.push(Vertex::with_pos_color(Vector2f::new(x + dx, y + dy), colour));
+            }
+        }
+        self.window.draw_primitives(&vertices, PrimitiveType::Quads, RenderStates::default());
+    }
+
+    fn render_minimap(&mut self, cam: &CameraChange) {
+        let loaded = self.world.loaded_chunk_coords().into_iter()
+            .map(|coord| (coord, Color::rgb(120, 120, 130)));
+
+        // at least half visible, even as the state fades out
+        let states = self.chunk_states.iter().map(|(&coord, &ChunkState(ref state, ref change))| {
+            let i = if let StateChange::Counter(i) = *change { i } else { 1.0 };
+            (coord, get_state_colour(state, 0.5 + i / 2.0))
+        });
+
+        let chunks: Vec<_> = loaded.chain(states).collect();
+        self.minimap.render(&mut self.window, &chunks, (cam.min_chunk, cam.max_chunk), self.chunk_size);
+    }
+
+    fn render_world(&mut self, text: &mut Text, cam: &CameraChange) {
+        render_world(&mut self.window, self.world, &self.geometry, self.hovered.or(self.selected));
 
         // chunk outlines
         let mut rect = {
@@ -281,214 +798,51 @@ impl<'a> Renderer<'a> {
     }
 }
 
-fn render_world(target: &mut RenderTarget, world: &World, render_cache: Option<&mut Vec<Vertex>>) {
-    fn get_road_colour(road_type: &parser::RoadType) -> Color {
-        match *road_type {
-            parser::RoadType::Motorway |
-            parser::RoadType::Primary |
-            parser::RoadType::Secondary => Color::rgb(255, 50, 50), // red
-            parser::RoadType::Minor => Color::rgb(50, 50, 255), // blue
-            parser::RoadType::Pedestrian => Color::rgb(100, 100, 100), // grey
-            parser::RoadType::Residential => Color::rgb(50, 255, 50), // green
-            _ => Color::rgb(255, 255, 255), // white
-        }
-    }
-    fn get_land_use_colour(land_use_type: &parser::LandUseType) -> Color {
-        let mut c = match *land_use_type {
-            parser::LandUseType::Residential => Color::rgb(46, 204, 113), // green
-            parser::LandUseType::Commercial => Color::rgb(243, 156, 18), // orange
-            parser::LandUseType::Agriculture => Color::rgb(211, 84, 0), // dark orange
-            parser::LandUseType::Industrial => Color::rgb(192, 57, 43), // dark red
-            parser::LandUseType::Green => Color::rgb(39, 240, 96), // more green
-            parser::LandUseType::Water => Color::rgb(41, 128, 185), // blue
-            _ => Color::rgb(255, 255, 255), // white
-        };
-
-        c.a = 40;
-        c
-    }
-
-    for lu in &world.loaded_land_uses {
-        let colour = get_land_use_colour(&lu.land_use_type);
-        let mut shape = ConvexShape::new(lu.points.len() as u32);
-        shape.set_fill_color(&Color::TRANSPARENT);
-        shape.set_outline_color(&colour);
-        shape.set_outline_thickness(2.0);
-        for (i, p) in lu.points.iter().enumerate() {
-            shape.set_point(i as u32, Vector2f::new(p.x as f32, p.y as f32));
-        }
-        target.draw(&shape);
-    }
-
-
-    let mut backup_vec: Vec<Vertex> = Vec::new();
-    let vertices = render_cache.unwrap_or(&mut backup_vec);
-    for r in &world.loaded_roads {
-        let colour = get_road_colour(&r.road_type);
-        vertices.clear();
-        vertices.extend(
-            r.segments.iter().map(|s| {
-                Vertex::with_pos_color(Vector2f::new(s.x as f32, s.y as f32), colour)
-            })
-            );
-        target.draw_primitives(vertices, PrimitiveType::LineStrip, RenderStates::default());
-    }
-}
-
-#[derive(Debug)]
-struct CameraChange {
-    x: f64,
-    y: f64,
-    w: u32,
-    h: u32,
-
-    z: f64,
-    dz: f64,
-
-    pub min_chunk: (i32, i32),
-    pub max_chunk: (i32, i32),
-    chunk_changes: Vec<ChunkChange>,
-}
-
-#[derive(Debug, Copy, Clone)]
-struct ChunkChange {
-    x: i32,
-    y: i32,
-    load: bool,
-}
-
-impl ChunkChange {
-    fn new(x: i32, y: i32, load: bool) -> Self {
-        ChunkChange { x, y, load }
-    }
+/// "hh:mm:ss", with hours past 24 for times after midnight
+fn format_time(time: transit::SimTime) -> String {
+    let secs = time as u32;
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
 }
 
-impl CameraChange {
-    fn new(window_size: Vector2u, initial_zoom: f64) -> Self {
-        CameraChange {
-            x: 0.0,
-            y: 0.0,
-            w: window_size.x,
-            h: window_size.y,
-            dz: 0.0,
-            z: initial_zoom,
-            min_chunk: (0, 0),
-            max_chunk: (0, 0),
-            chunk_changes: Vec::new(),
+fn render_world(target: &mut RenderTarget, world: &World, chunks: &HashMap<(i32, i32), ChunkGeometry>, highlight: Option<Feature>) {
+    for &layer in &geometry::LAYERS {
+        for geom in chunks.values() {
+            geom.draw(target, layer);
         }
     }
 
-    fn handle_key(&mut self, key: Key, pressed: bool) {
-        let mult = if pressed { 1.0 } else { 0.0 };
-        match key {
-            Key::Q => self.dz = -ZOOM_SPEED * mult,
-            Key::E => self.dz = ZOOM_SPEED * mult,
-            Key::W => self.y = -MOVE_SPEED * mult,
-            Key::S => self.y = MOVE_SPEED * mult,
-            Key::A => self.x = -MOVE_SPEED * mult,
-            Key::D => self.x = MOVE_SPEED * mult,
-            _ => (),
-        }
-    }
-
-    fn resize(&mut self, width: u32, height: u32) {
-        self.w = width;
-        self.h = height;
-    }
-
-    fn apply(&mut self, window: &mut RenderWindow, chunk_size: Vector2i) -> &Vec<ChunkChange> {
-        let mut view = window.view().to_owned();
-
-        if self.dz != 0.0 {
-            // zoom in/out
-            self.z += self.dz;
-            self.z = f64::min(self.z, 4.5);
-            self.z = f64::max(self.z, 0.25);
-        }
-
-        view.set_size(Vector2f::new(self.w as f32 * self.z as f32, self.h as f32 * self.z as f32));
-        view.move_((self.x as f32, self.y as f32));
-        window.set_view(&view);
-
-        // chunks visible
-        {
-            self.chunk_changes.clear();
-
-            let tl = window.map_pixel_to_coords(&Vector2i::new(0, 0), &view);
-            let br = {
-                let win_size = window.size();
-                let size_i = Vector2i::new(win_size.x as i32, win_size.y as i32);
-                window.map_pixel_to_coords(&size_i, &view)
-            };
-
-            let min_x = (tl.x /  chunk_size.x as f32).floor() as i32;
-            let min_y = (tl.y /  chunk_size.y as f32).floor() as i32;
-            let max_y = (br.y /  chunk_size.y as f32).floor() as i32;
-            let max_x = (br.x /  chunk_size.x as f32).floor() as i32;
-
-            let x_min_change = min_x - self.min_chunk.0;
-            let x_max_change = max_x - self.max_chunk.0;
-            let y_min_change = min_y - self.min_chunk.1;
-            let y_max_change = max_y - self.max_chunk.1;
-
-            // just one at a time for now
-            assert!(x_min_change == 0 || x_min_change.abs() == 1);
-            assert!(x_max_change == 0 || x_max_change.abs() == 1);
-            assert!(y_max_change == 0 || y_max_change.abs() == 1);
-            assert!(y_max_change == 0 || y_max_change.abs() == 1);
-
-            if x_min_change != 0 {
-                let (x, load) = if x_min_change < 0 {
-                    (min_x, true)
-                } else {
-                    (min_x - x_min_change, false)
-                };
-
-                for y in min_y..max_y + 1 {
-                    self.chunk_changes.push(ChunkChange::new(x, y, load));
-                }
-            }
-
-            if x_max_change != 0 {
-                let (x, load) = if x_max_change > 0 {
-                    (max_x, true)
-                } else {
-                    (max_x - x_max_change, false)
-                };
-
-                for y in min_y..max_y + 1 {
-                    self.chunk_changes.push(ChunkChange::new(x, y, load));
-                }
-            }
-
-            if y_min_change != 0 {
-                let (y, load) = if y_min_change < 0 {
-                    (min_y, true)
-                } else {
-                    (min_y - y_min_change, false)
-                };
-
-                for x in min_x..max_x + 1 {
-                    self.chunk_changes.push(ChunkChange::new(x, y, load));
-                }
-            }
-
-            if y_max_change != 0 {
-                let (y, load) = if y_max_change > 0 {
-                    (max_y, true)
-                } else {
-                    (max_y - y_max_change, false)
-                };
+    if let Some(feature) = highlight {
+        let outline = match feature {
+            Feature::Road(id) => world.loaded_roads.get(&id).map(|r| (Cow::from(&r.segments[..]), false)),
+            Feature::LandUse(id) => world.loaded_land_uses.get(&id).map(|lu| (Cow::from(&lu.points[..]), true)),
+            Feature::Building(id) => world.loaded_buildings.get(&id).map(|b| (Cow::from(&b.points[..]), true)),
+            Feature::Railway(id) => world.loaded_railways.get(&id).map(|r| (Cow::from(&r.points[..]), false)),
+            Feature::TransitLine(id) => world.loaded_transit_lines.get(&id).map(|l| {
+                let stops: Vec<Point> = l.stops.iter().map(|s| s.position).collect();
+                (Cow::from(stops), false)
+            }),
+            Feature::PointOfInterest(id) => world.loaded_pois.get(&id).map(|p| {
+                // a square around it, the same size on screen at any zoom
+                let r = (POI_HIGHLIGHT_RADIUS * target.view().size().x / target.size().x as f32) as i32;
+                let Point { x, y } = p.position;
+                let corners = vec![
+                    Point { x: x - r, y: y - r }, Point { x: x + r, y: y - r },
+                    Point { x: x + r, y: y + r }, Point { x: x - r, y: y + r },
+                ];
+                (Cow::from(corners), true)
+            }),
+        };
 
-                for x in min_x..max_x + 1 {
-                    self.chunk_changes.push(ChunkChange::new(x, y, load));
-                }
+        if let Some((points, closed)) = outline {
+            let mut vertices = Vec::with_capacity(points.len() + 1);
+            vertices.extend(points.iter().map(|p| {
+                Vertex::with_pos_color(Vector2f::new(p.x as f32, p.y as f32), Color::YELLOW)
+            }));
+            if closed && !points.is_empty() {
+                let first = vertices[0];
+                vertices.push(first);
             }
-
-
-            self.min_chunk = (min_x, min_y);
-            self.max_chunk = (max_x, max_y);
-            &self.chunk_changes
+            target.draw_primitives(&vertices, PrimitiveType::LineStrip, RenderStates::default());
         }
     }
 }
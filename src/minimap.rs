@@ -0,0 +1,110 @@
+use sfml::graphics::*;
+use sfml::system::*;
+
+/// Side of the minimap in screen pixels
+const SIZE: f32 = 150.0;
+
+/// Screen pixels between the minimap and the corner of the screen
+const MARGIN: f32 = 8.0;
+
+/// Chunks shown around everything known about
+const BORDER_CHUNKS: i32 = 1;
+
+/// Overview of the chunks around the camera, drawn in the bottom right corner
+pub struct Minimap {
+    /// Top left of the map on screen, as last drawn
+    screen_pos: Vector2f,
+
+    /// World pixel at the top left of the map
+    origin: Vector2f,
+
+    /// World pixels per minimap pixel
+    scale: f32,
+}
+
+impl Default for Minimap {
+    fn default() -> Self {
+        Minimap {
+            screen_pos: Vector2f::new(0.0, 0.0),
+            origin: Vector2f::new(0.0, 0.0),
+            scale: 0.0,
+        }
+    }
+}
+
+impl Minimap {
+    /// The world pixel under the given screen pixel, if it's on the minimap
+    pub fn world_at(&self, x: i32, y: i32) -> Option<Vector2f> {
+        let (mx, my) = (x as f32 - self.screen_pos.x, y as f32 - self.screen_pos.y);
+        if self.scale == 0.0 || mx < 0.0 || my < 0.0 || mx > SIZE || my > SIZE {
+            return None;
+        }
+
+        Some(Vector2f::new(self.origin.x + mx * self.scale, self.origin.y + my * self.scale))
+    }
+
+    /// Draws the given chunks in their colours and the viewport's outline, zoomed to fit them all
+    pub fn render(&mut self, target: &mut RenderTarget, chunks: &[((i32, i32), Color)], visible: ((i32, i32), (i32, i32)), chunk_size: Vector2i) {
+        let view = target.view().to_owned();
+        let screen = target.default_view().to_owned();
+        let size = target.size();
+
+        // fit everything, keeping chunks square
+        let (mut min, mut max) = visible;
+        for &((x, y), _) in chunks {
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+        }
+        let (min, max) = ((min.0 - BORDER_CHUNKS, min.1 - BORDER_CHUNKS), (max.0 + BORDER_CHUNKS, max.1 + BORDER_CHUNKS));
+
+        let (cw, ch) = (chunk_size.x as f32, chunk_size.y as f32);
+        let region = Vector2f::new((max.0 - min.0 + 1) as f32 * cw, (max.1 - min.1 + 1) as f32 * ch);
+        self.scale = region.x.max(region.y) / SIZE;
+        self.origin = Vector2f::new(
+            min.0 as f32 * cw - (self.scale * SIZE - region.x) / 2.0,
+            min.1 as f32 * ch - (self.scale * SIZE - region.y) / 2.0,
+        );
+        self.screen_pos = Vector2f::new(size.x as f32 - SIZE - MARGIN, size.y as f32 - SIZE - MARGIN);
+
+        target.set_view(&screen);
+
+        let mut background = RectangleShape::with_size(Vector2f::new(SIZE, SIZE));
+        background.set_position(self.screen_pos);
+        background.set_fill_color(&Color::rgba(0, 0, 0, 200));
+        background.set_outline_color(&Color::WHITE);
+        background.set_outline_thickness(1.0);
+        target.draw(&background);
+
+        let mut rect = RectangleShape::with_size(Vector2f::new(cw / self.scale, ch / self.scale));
+        for &((x, y), colour) in chunks {
+            rect.set_position(self.to_screen(x as f32 * cw, y as f32 * ch));
+            rect.set_fill_color(&colour);
+            target.draw(&rect);
+        }
+
+        // viewport
+        let (tl, br) = {
+            let centre = view.center();
+            let half = view.size() / 2.0;
+            (centre - half, centre + half)
+        };
+        let mut viewport = RectangleShape::with_size(Vector2f::new(
+            (br.x - tl.x) / self.scale,
+            (br.y - tl.y) / self.scale,
+        ));
+        viewport.set_position(self.to_screen(tl.x, tl.y));
+        viewport.set_fill_color(&Color::TRANSPARENT);
+        viewport.set_outline_color(&Color::WHITE);
+        viewport.set_outline_thickness(1.0);
+        target.draw(&viewport);
+
+        target.set_view(&view);
+    }
+
+    fn to_screen(&self, x: f32, y: f32) -> Vector2f {
+        Vector2f::new(
+            self.screen_pos.x + (x - self.origin.x) / self.scale,
+            self.screen_pos.y + (y - self.origin.y) / self.scale,
+        )
+    }
+}
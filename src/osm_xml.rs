@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use xml::common::Position;
+use xml::reader::{EventReader, XmlEvent};
+use xml::attribute::OwnedAttribute;
+
+use world::{Id, LatLon};
+use parser::{ParseIssue, ParseIssueKind, ParseMode};
+use tags::Tags;
+
+/// Where an element starts in the document, from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: u32,
+    pub column: u32,
+}
+
+#[derive(Debug)]
+pub struct Element {
+    pub location: Location,
+    pub tags: Tags,
+
+    /// Only for nodes
+    pub position: Option<LatLon>,
+
+    /// Only for ways, the ids of their nodes in order
+    pub nodes: Vec<Id>,
+
+    /// Only for relations
+    pub members: Vec<Member>,
+}
+
+#[derive(Debug)]
+pub struct Member {
+    pub kind: ElementKind,
+    pub id: Id,
+    pub role: String,
+}
+
+/// What libosm doesn't tell us about the document, read in a separate pass over the XML
+#[derive(Debug, Default)]
+pub struct OsmXml {
+    pub nodes: HashMap<Id, Element>,
+    pub ways: HashMap<Id, Element>,
+    pub relations: HashMap<Id, Element>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElementKind {
+    Node,
+    Way,
+    Relation,
+}
+
+impl ElementKind {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "node" => Some(ElementKind::Node),
+            "way" => Some(ElementKind::Way),
+            "relation" => Some(ElementKind::Relation),
+            _ => None,
+        }
+    }
+}
+
+impl OsmXml {
+    /// Malformed XML always fails, as libosm can't make sense of it either. Elements without a
+    /// valid id, and nodes without a valid position, fail in strict mode and are skipped with a
+    /// warning otherwise, as are way nodes and relation members without a valid ref
+    pub fn scan(xml: &str, mode: ParseMode, warnings: &mut Vec<ParseIssue>) -> Result<Self, ParseIssue> {
+        let mut osm = OsmXml::default();
+        let mut reader = EventReader::new(xml.as_bytes());
+
+        // the node, way or relation being read, and where to put it when it ends
+        let mut current: Option<(ElementKind, Id, Element)> = None;
+
+        loop {
+            let event = reader.next().map_err(|e| ParseIssue {
+                line: Some(e.position().row as u32 + 1),
+                column: Some(e.position().column as u32 + 1),
+                element: None,
+                kind: ParseIssueKind::BadXml,
+                reason: e.msg().to_owned(),
+            })?;
+
+            let location = {
+                let pos = reader.position();
+                Location { line: pos.row as u32 + 1, column: pos.column as u32 + 1 }
+            };
+
+            match event {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    match (name.local_name.as_ref(), current.as_mut()) {
+                        ("tag", Some(&mut (_, _, ref mut element))) => {
+                            if let (Some(k), Some(v)) = (attribute(&attributes, "k"), attribute(&attributes, "v")) {
+                                element.tags.insert(k, v);
+                            }
+                            continue;
+                        }
+                        ("nd", Some(&mut (_, id, ref mut element))) => {
+                            match attribute(&attributes, "ref").and_then(|r| r.parse().ok()) {
+                                Some(node) => element.nodes.push(node),
+                                None => {
+                                    let issue = ParseIssue {
+                                        line: Some(location.line),
+                                        column: Some(location.column),
+                                        element: Some(id),
+                                        kind: ParseIssueKind::BadRef,
+                                        reason: "way node has no valid ref".to_owned(),
+                                    };
+                                    reject(issue, mode, warnings)?;
+                                }
+                            }
+                            continue;
+                        }
+                        ("member", Some(&mut (_, id, ref mut element))) => {
+                            let kind = attribute(&attributes, "type").and_then(ElementKind::from_name);
+                            let member = attribute(&attributes, "ref").and_then(|r| r.parse().ok());
+                            match (kind, member) {
+                                (Some(kind), Some(member)) => element.members.push(Member {
+                                    kind,
+                                    id: member,
+                                    role: attribute(&attributes, "role").unwrap_or("").to_owned(),
+                                }),
+                                _ => {
+                                    let issue = ParseIssue {
+                                        line: Some(location.line),
+                                        column: Some(location.column),
+                                        element: Some(id),
+                                        kind: ParseIssueKind::BadRef,
+                                        reason: "relation member has no valid type and ref".to_owned(),
+                                    };
+                                    reject(issue, mode, warnings)?;
+                                }
+                            }
+                            continue;
+                        }
+                        _ => {}
+                    }
+
+                    let kind = match ElementKind::from_name(&name.local_name) {
+                        Some(kind) => kind,
+                        None => continue,
+                    };
+
+                    let id = match attribute(&attributes, "id").and_then(|id| id.parse().ok()) {
+                        Some(id) => id,
+                        None => {
+                            let issue = ParseIssue {
+                                line: Some(location.line),
+                                column: Some(location.column),
+                                element: None,
+                                kind: ParseIssueKind::MissingId,
+                                reason: format!("{} has no valid id", name.local_name),
+                            };
+                            reject(issue, mode, warnings)?;
+                            continue;
+                        }
+                    };
+
+                    let position = match kind {
+                        ElementKind::Node => {
+                            let coord = |name| attribute(&attributes, name).and_then(|c| c.parse().ok());
+                            match (coord("lat"), coord("lon")) {
+                                (Some(lat), Some(lon)) => Some(LatLon { lat, lon }),
+                                _ => {
+                                    let issue = ParseIssue {
+                                        line: Some(location.line),
+                                        column: Some(location.column),
+                                        element: Some(id),
+                                        kind: ParseIssueKind::BadPosition,
+                                        reason: "node has no valid lat and lon".to_owned(),
+                                    };
+                                    reject(issue, mode, warnings)?;
+                                    continue;
+                                }
+                            }
+                        }
+                        _ => None,
+                    };
+
+                    current = Some((kind, id, Element {
+                        location,
+                        tags: Tags::new(),
+                        position,
+                        nodes: Vec::new(),
+                        members: Vec::new(),
+                    }));
+                }
+                XmlEvent::EndElement { name } => {
+                    if ElementKind::from_name(&name.local_name).is_none() {
+                        continue;
+                    }
+
+                    if let Some((kind, id, element)) = current.take() {
+                        let elements = match kind {
+                            ElementKind::Node => &mut osm.nodes,
+                            ElementKind::Way => &mut osm.ways,
+                            ElementKind::Relation => &mut osm.relations,
+                        };
+                        elements.insert(id, element);
+                    }
+                }
+                XmlEvent::EndDocument => break,
+                _ => {}
+            }
+        }
+
+        Ok(osm)
+    }
+
+    /// Empty if the way isn't in the document
+    pub fn way_tags(&self, id: Id) -> Tags {
+        self.ways.get(&id).map(|w| w.tags.clone()).unwrap_or_default()
+    }
+}
+
+/// Fails in strict mode, and is only a warning otherwise
+fn reject(issue: ParseIssue, mode: ParseMode, warnings: &mut Vec<ParseIssue>) -> Result<(), ParseIssue> {
+    match mode {
+        ParseMode::Strict => Err(issue),
+        ParseMode::Lenient => {
+            warnings.push(issue);
+            Ok(())
+        }
+    }
+}
+
+fn attribute<'a>(attributes: &'a [OwnedAttribute], name: &str) -> Option<&'a str> {
+    attributes.iter()
+        .find(|a| a.name.local_name == name)
+        .map(|a| a.value.as_ref())
+}
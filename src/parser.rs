@@ -1,8 +1,14 @@
 use libc::*;
-use std::{self, ffi, ptr};
+use std::{self, ffi, fmt, ptr};
 use std::collections::HashMap;
 use error::*;
-use world::{Id, Road, LandUse, Building, Point, LatLon, PointsHolder};
+use world::{Id, Road, LandUse, Building, PointOfInterest, Railway, TransitLine, Point, LatLon, PointsHolder, Area};
+use transit::{RailKind, TransitMode, TransitStop};
+use poi::PoiCategory;
+use classify;
+use picking;
+use osm_xml::{Element, ElementKind, OsmXml};
+use tags::Tags;
 use sfml::system::Vector2f;
 
 #[repr(C)]
@@ -30,12 +36,70 @@ struct OsmVec<T> {
 #[repr(C)]
 struct OsmWorld {
     roads: OsmVec<OsmRoad>,
-    land_uses: OsmVec<OsmLandUse>
+    land_uses: OsmVec<OsmLandUse>,
 }
 
+/// What to do with elements that can't be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Fail the whole parse
+    Strict,
+
+    /// Skip the element and carry on, with a warning
+    Lenient,
+}
+
+/// Something wrong with the OSM, and where it is
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParseIssue {
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub element: Option<Id>,
+    pub kind: ParseIssueKind,
+
+    /// Only for display, match on `kind` instead
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParseIssueKind {
+    /// The document isn't well-formed
+    BadXml,
+
+    /// libosm failed for a reason of its own
+    LibOsm,
+
+    MissingId,
+    BadPosition,
+
+    /// A way node or relation member without a valid reference
+    BadRef,
+
+    BadName,
+
+    /// Tagged as something drawn, but not a kind that's known
+    UnknownType,
+
+    TooFewPoints,
+    UnclosedRing,
+    MissingNode,
+
+    /// A way in a multipolygon, or a stop or way in a route
+    MissingMember,
+
+    MissingOuterRing,
+
+    /// An inner ring of a multipolygon outside all of its outer rings
+    StrayRing,
+}
+
+/// Why an element was rejected or only partly converted, before it's known which element it is
+type Reason = (ParseIssueKind, String);
+
+/// The C parser's classification, only used when the tags don't say more
 #[repr(C)]
-#[derive(Debug, Serialize, Deserialize)]
-pub enum RoadType {
+#[derive(Debug, Clone, Copy)]
+enum OsmRoadType {
     Unknown,
     Motorway,
     Primary,
@@ -46,8 +110,8 @@ pub enum RoadType {
 }
 
 #[repr(C)]
-#[derive(Debug, Serialize, Deserialize)]
-pub enum LandUseType {
+#[derive(Debug, Clone, Copy)]
+enum OsmLandUseType {
     Unknown,
     Residential,
     Commercial,
@@ -57,6 +121,128 @@ pub enum LandUseType {
     Water
 }
 
+/// See `classify`. Variants are never renamed, so cached chunks still deserialize
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RoadType {
+    Unknown,
+    Motorway,
+    MotorwayLink,
+    Trunk,
+    TrunkLink,
+    Primary,
+    PrimaryLink,
+    Secondary,
+    SecondaryLink,
+    Tertiary,
+    TertiaryLink,
+    Minor,
+    Residential,
+    LivingStreet,
+    Service,
+    Track,
+    Pedestrian,
+    Footway,
+    Cycleway,
+    Steps,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LandUseType {
+    Unknown,
+    Residential,
+    Commercial,
+    Retail,
+    Agriculture,
+    Industrial,
+    Brownfield,
+    Military,
+    Education,
+    Cemetery,
+    Railway,
+
+    /// Grass, scrub and anything else green that isn't a park or forest
+    Green,
+    Park,
+    Forest,
+    Water,
+    Wetland,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BuildingType {
+    /// Just building=yes, or something not in `classify`
+    Unknown,
+    Residential,
+    Commercial,
+    Retail,
+    Industrial,
+    Education,
+    Healthcare,
+    Religious,
+    Civic,
+    Transport,
+    Agricultural,
+
+    /// Garages, sheds and other small buildings
+    Outbuilding,
+}
+
+impl Default for BuildingType {
+    fn default() -> Self {
+        BuildingType::Unknown
+    }
+}
+
+impl RoadType {
+    /// The road a link road joins, or itself
+    pub fn base(self) -> Self {
+        match self {
+            RoadType::MotorwayLink => RoadType::Motorway,
+            RoadType::TrunkLink => RoadType::Trunk,
+            RoadType::PrimaryLink => RoadType::Primary,
+            RoadType::SecondaryLink => RoadType::Secondary,
+            RoadType::TertiaryLink => RoadType::Tertiary,
+            t => t,
+        }
+    }
+
+    /// Not for cars, so not measured in lanes
+    pub fn is_path(self) -> bool {
+        match self {
+            RoadType::Pedestrian | RoadType::Footway | RoadType::Cycleway | RoadType::Steps => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<OsmRoadType> for RoadType {
+    fn from(t: OsmRoadType) -> Self {
+        match t {
+            OsmRoadType::Unknown => RoadType::Unknown,
+            OsmRoadType::Motorway => RoadType::Motorway,
+            OsmRoadType::Primary => RoadType::Primary,
+            OsmRoadType::Secondary => RoadType::Secondary,
+            OsmRoadType::Minor => RoadType::Minor,
+            OsmRoadType::Residential => RoadType::Residential,
+            OsmRoadType::Pedestrian => RoadType::Pedestrian,
+        }
+    }
+}
+
+impl From<OsmLandUseType> for LandUseType {
+    fn from(t: OsmLandUseType) -> Self {
+        match t {
+            OsmLandUseType::Unknown => LandUseType::Unknown,
+            OsmLandUseType::Residential => LandUseType::Residential,
+            OsmLandUseType::Commercial => LandUseType::Commercial,
+            OsmLandUseType::Agriculture => LandUseType::Agriculture,
+            OsmLandUseType::Industrial => LandUseType::Industrial,
+            OsmLandUseType::Green => LandUseType::Green,
+            OsmLandUseType::Water => LandUseType::Water,
+        }
+    }
+}
+
 trait OsmIdHolder {
     fn id(&self) -> Id;
 }
@@ -65,16 +251,16 @@ trait OsmIdHolder {
 #[derive(Debug)]
 struct OsmRoad {
     id: Id,
-    road_type: RoadType,
+    road_type: OsmRoadType,
     segments: OsmVec<OsmLatLon>,
-    name: *const c_char
+    name: *const c_char,
 }
 
 #[repr(C)]
 #[derive(Debug)]
 struct OsmLandUse {
     id: Id,
-    land_use_type: LandUseType,
+    land_use_type: OsmLandUseType,
     points: OsmVec<OsmLatLon>,
 }
 
@@ -115,6 +301,43 @@ impl Default for OsmWorld {
     }
 }
 
+impl ParseIssue {
+    fn element(id: Id, (kind, reason): Reason) -> Self {
+        ParseIssue {
+            line: None,
+            column: None,
+            element: Some(id),
+            kind,
+            reason,
+        }
+    }
+
+    /// Fills in where its element is, if it's not already known
+    fn locate(&mut self, elements: &HashMap<Id, Element>) {
+        let location = match self.element.and_then(|id| elements.get(&id)) {
+            Some(e) if self.line.is_none() => e.location,
+            _ => return,
+        };
+
+        self.line = Some(location.line);
+        self.column = Some(location.column);
+    }
+}
+
+impl fmt::Display for ParseIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "line {}, column {}: ", line, column)?,
+            (Some(line), None) => write!(f, "line {}: ", line)?,
+            _ => {}
+        }
+        if let Some(id) = self.element {
+            write!(f, "element {}: ", id)?;
+        }
+        write!(f, "{}", self.reason)
+    }
+}
+
 impl From<LatLon> for OsmLatLon {
     fn from(ll: LatLon) -> Self {
         Self {
@@ -124,17 +347,47 @@ impl From<LatLon> for OsmLatLon {
     }
 }
 
-fn convert_vec_to_map<T, U>(orig: &OsmVec<T>) -> HashMap<Id, U>
+/// Converts each element, which is either rejected with a reason, skipped, or converted, possibly
+/// with warnings about it. Rejections are for malformed elements, and fail the parse in strict
+/// mode and are warnings otherwise. Skipping is for valid elements that just can't be drawn, such
+/// as those cut off by the edge of the chunk. Issues are placed in the document by the element's
+/// id in `elements`
+fn convert_to_map<T, U, I, F>(orig: I, mode: ParseMode, elements: &HashMap<Id, Element>, warnings: &mut Vec<ParseIssue>, mut convert: F) -> Result<HashMap<Id, U>, ParseIssue>
     where
-        T: OsmIdHolder,
-        U: From<T>
+        I: IntoIterator<Item = (Id, T)>,
+        F: FnMut(T, &mut Vec<ParseIssue>) -> Result<Option<U>, Reason>
 {
-    let mut m = HashMap::<Id, U>::with_capacity(orig.length as usize);
-    for i in 0..orig.length {
-        let d: T = unsafe { ptr::read(orig.data.offset(i as isize)) };
-        m.insert(d.id(), From::from(d));
+    let mut m = HashMap::<Id, U>::new();
+    for (id, d) in orig {
+        let before = warnings.len();
+        let converted = convert(d, warnings);
+        for w in &mut warnings[before..] {
+            w.locate(elements);
+        }
+
+        match converted {
+            Ok(Some(u)) => {
+                m.insert(id, u);
+            }
+            Ok(None) => {}
+            Err(reason) => {
+                let mut issue = ParseIssue::element(id, reason);
+                issue.locate(elements);
+                match mode {
+                    ParseMode::Strict => return Err(issue),
+                    ParseMode::Lenient => warnings.push(issue),
+                }
+            }
+        }
     }
-    m
+    Ok(m)
+}
+
+fn read_vec<T: OsmIdHolder>(orig: &OsmVec<T>) -> Vec<(Id, T)> {
+    (0..orig.length)
+        .map(|i| unsafe { ptr::read(orig.data.offset(i as isize)) })
+        .map(|d| (d.id(), d))
+        .collect()
 }
 
 fn convert_latlon_vec(orig: &OsmVec<OsmLatLon>) -> Vec<Point>
@@ -148,28 +401,330 @@ fn convert_latlon_vec(orig: &OsmVec<OsmLatLon>) -> Vec<Point>
     v
 }
 
-impl From<OsmRoad> for Road {
-    fn from(r: OsmRoad) -> Self {
-        let name = if r.name.is_null() {
-            String::new()
-        } else {
-            let cname = unsafe { ffi::CStr::from_ptr(r.name) };
-            cname.to_str().unwrap().to_owned()
-        };
+fn convert_road(r: OsmRoad, xml: &OsmXml, warnings: &mut Vec<ParseIssue>) -> Result<Road, Reason> {
+    if r.segments.length < 2 {
+        return Err((ParseIssueKind::TooFewPoints, format!("road has {} points, at least 2 are needed", r.segments.length)));
+    }
 
-        Self {
-            road_type: r.road_type,
-            segments: convert_latlon_vec(&r.segments),
-            name
+    let name = if r.name.is_null() {
+        String::new()
+    } else {
+        let cname = unsafe { ffi::CStr::from_ptr(r.name) };
+        match cname.to_str() {
+            Ok(name) => name.to_owned(),
+            Err(e) => {
+                warnings.push(ParseIssue::element(r.id, (ParseIssueKind::BadName, format!("road name is not valid UTF-8 ({})", e))));
+                cname.to_string_lossy().into_owned()
+            }
         }
+    };
+
+    let tags = xml.way_tags(r.id);
+    Ok(Road {
+        road_type: classify::road_type(&tags).unwrap_or_else(|| r.road_type.into()),
+        segments: convert_latlon_vec(&r.segments),
+        name,
+        lanes: tags.get("lanes").and_then(|l| l.trim().parse().ok()),
+        tags,
+    })
+}
+
+fn convert_land_use(lu: OsmLandUse, xml: &OsmXml) -> Result<LandUse, Reason> {
+    if lu.points.length < 3 {
+        return Err((ParseIssueKind::TooFewPoints, format!("land use has {} points, at least 3 are needed", lu.points.length)));
     }
+
+    let tags = xml.way_tags(lu.id);
+    Ok(LandUse {
+        land_use_type: classify::land_use_type(&tags).unwrap_or_else(|| lu.land_use_type.into()),
+        points: convert_latlon_vec(&lu.points),
+        holes: Vec::new(),
+        triangles: Vec::new(),
+        tags,
+    })
 }
 
-impl From<OsmLandUse> for LandUse {
-    fn from(lu: OsmLandUse) -> Self {
-        Self {
-            land_use_type: lu.land_use_type,
-            points: convert_latlon_vec(&lu.points),
+fn convert_poi(n: &Element, category: PoiCategory) -> Result<PointOfInterest, Reason> {
+    let pos = n.position.as_ref().ok_or((ParseIssueKind::BadPosition, "node has no position".to_owned()))?;
+    let p = convert_latlon(pos.lat, pos.lon);
+
+    Ok(PointOfInterest {
+        category,
+        position: Point { x: p.x, y: p.y },
+        name: n.tags.get("name").unwrap_or("").to_owned(),
+        tags: n.tags.clone(),
+    })
+}
+
+/// Fails with the first node that isn't in the document
+fn convert_node_points(nodes: &[Id], xml: &OsmXml) -> Result<Vec<Point>, Id> {
+    nodes.iter()
+        .map(|id| {
+            let pos = xml.nodes.get(id)
+                .and_then(|n| n.position.as_ref())
+                .ok_or(*id)?;
+            let p = convert_latlon(pos.lat, pos.lon);
+            Ok(Point { x: p.x, y: p.y })
+        })
+        .collect()
+}
+
+/// Railways cut off by the edge of the chunk are skipped
+fn convert_railway(id: Id, w: &Element, xml: &OsmXml) -> Result<Option<Railway>, Reason> {
+    let kind = RailKind::from_tags(&w.tags).ok_or_else(|| {
+        (ParseIssueKind::UnknownType, format!("unknown railway '{}'", w.tags.get("railway").unwrap_or("")))
+    })?;
+
+    if w.nodes.len() < 2 {
+        debug!("Skipped railway {} with {} points", id, w.nodes.len());
+        return Ok(None);
+    }
+
+    let points = match convert_node_points(&w.nodes, xml) {
+        Ok(points) => points,
+        Err(node) => {
+            debug!("Skipped railway {}, node {} is missing", id, node);
+            return Ok(None);
+        }
+    };
+
+    Ok(Some(Railway {
+        kind,
+        points,
+        name: w.tags.get("name").unwrap_or("").to_owned(),
+        tags: w.tags.clone(),
+    }))
+}
+
+/// Stops are looked up among the points of interest and the path among the roads and railways,
+/// so those must be converted first. Members that weren't are skipped with a warning, and routes
+/// left with fewer than two stops are skipped entirely
+fn convert_transit_line(id: Id, rel: &Element, pois: &HashMap<Id, PointOfInterest>, roads: &HashMap<Id, Road>, railways: &HashMap<Id, Railway>, warnings: &mut Vec<ParseIssue>) -> Result<Option<TransitLine>, Reason> {
+    let tags = rel.tags.clone();
+    let mode = TransitMode::from_tags(&tags).ok_or_else(|| {
+        (ParseIssueKind::UnknownType, format!("unknown route '{}'", tags.get("route").unwrap_or("")))
+    })?;
+
+    let mut stops = Vec::new();
+    let mut path = Vec::new();
+    let mut missing = 0;
+
+    for member in &rel.members {
+        let role = &member.role;
+        match member.kind {
+            ElementKind::Node if role.starts_with("stop") => match pois.get(&member.id) {
+                Some(poi) => stops.push(TransitStop {
+                    id: member.id,
+                    position: poi.position,
+                    name: poi.name.clone(),
+                }),
+                None => missing += 1,
+            },
+            // platforms are usually beside the road, the stops are where vehicles actually stop
+            ElementKind::Way if !role.starts_with("platform") => {
+                match roads.get(&member.id).map(|r| &r.segments).or_else(|| railways.get(&member.id).map(|r| &r.points)) {
+                    Some(points) => path.push(points.clone()),
+                    None => missing += 1,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if missing > 0 {
+        let reason = format!("{} members of the route were not found", missing);
+        warnings.push(ParseIssue::element(id, (ParseIssueKind::MissingMember, reason)));
+    }
+
+    if stops.len() < 2 {
+        debug!("Skipped route {} with {} stops", id, stops.len());
+        return Ok(None);
+    }
+
+    Ok(Some(TransitLine {
+        mode,
+        reference: tags.get("ref").unwrap_or("").to_owned(),
+        name: tags.get("name").unwrap_or("").to_owned(),
+        stops,
+        path,
+        tags,
+    }))
+}
+
+fn is_building(tags: &Tags) -> bool {
+    tags.get("building").map_or(false, |b| b != "no")
+}
+
+/// Building ways must be closed, like land uses. Those cut off by the edge of the chunk are skipped
+fn convert_building(id: Id, w: &Element, xml: &OsmXml) -> Result<Option<Building>, Reason> {
+    let nodes = match w.nodes.split_last() {
+        Some((last, nodes)) if w.nodes[0] == *last => nodes,
+        _ => return Err((ParseIssueKind::UnclosedRing, "building isn't closed".to_owned())),
+    };
+
+    if nodes.len() < 3 {
+        return Err((ParseIssueKind::TooFewPoints, format!("building has {} points, at least 3 are needed", nodes.len())));
+    }
+
+    let points = match convert_node_points(nodes, xml) {
+        Ok(points) => points,
+        Err(node) => {
+            debug!("Skipped building {}, node {} is missing", id, node);
+            return Ok(None);
+        }
+    };
+
+    Ok(Some(Building {
+        building_type: classify::building_type(&w.tags).unwrap_or_default(),
+        points,
+        holes: Vec::new(),
+        triangles: Vec::new(),
+        tags: w.tags.clone(),
+    }))
+}
+
+/// An outer ring of a multipolygon, and the inner rings inside it
+struct Polygon {
+    outline: Vec<Point>,
+    holes: Vec<Vec<Point>>,
+
+    /// If the outline is a single way, which may have the area's tags instead of the relation
+    way: Option<Id>,
+}
+
+/// Joins the member ways into closed rings, and puts each inner ring in the outer ring around it.
+/// Members without a role are taken to be outer. Rings that can't be closed are dropped with a
+/// warning, so the relation is skipped entirely only if none of its outer rings are left
+fn convert_multipolygon(id: Id, rel: &Element, xml: &OsmXml, warnings: &mut Vec<ParseIssue>) -> Vec<Polygon> {
+    let ways = |inner: bool| -> Vec<Id> {
+        rel.members.iter()
+            .filter(|m| m.kind == ElementKind::Way && (m.role == "inner") == inner)
+            .map(|m| m.id)
+            .collect()
+    };
+
+    let mut dropped = Vec::new();
+    let rings = |way_ids: &[Id], dropped: &mut Vec<Reason>| -> Vec<(Vec<Point>, Option<Id>)> {
+        join_rings(way_ids, xml, dropped).into_iter()
+            .filter_map(|(ring, way)| match convert_node_points(&ring, xml) {
+                Ok(points) => Some((points, way)),
+                Err(node) => {
+                    dropped.push((ParseIssueKind::MissingNode, format!("node {} is missing, ring dropped", node)));
+                    None
+                }
+            })
+            .collect()
+    };
+
+    let mut polygons: Vec<_> = rings(&ways(false), &mut dropped).into_iter()
+        .map(|(outline, way)| Polygon { outline, holes: Vec::new(), way })
+        .collect();
+
+    if polygons.is_empty() {
+        dropped.push((ParseIssueKind::MissingOuterRing, "multipolygon has no outer ring, skipped".to_owned()));
+    }
+
+    let mut stray = 0;
+    for (hole, _) in rings(&ways(true), &mut dropped) {
+        let p = hole[0];
+        match polygons.iter_mut().find(|o| picking::contains(&o.outline, f64::from(p.x), f64::from(p.y))) {
+            Some(outer) => outer.holes.push(hole),
+            None => stray += 1,
+        }
+    }
+
+    if stray > 0 {
+        dropped.push((ParseIssueKind::StrayRing, format!("{} inner rings are outside every outer ring", stray)));
+    }
+
+    warnings.extend(dropped.into_iter().map(|reason| ParseIssue::element(id, reason)));
+    polygons
+}
+
+/// Node ids of a closed ring without the repeated last one, and the way if it's the whole ring
+type Ring = (Vec<Id>, Option<Id>);
+
+/// Missing ways are left out, and rings that don't close without them are dropped, each with a
+/// reason in `dropped`
+fn join_rings(way_ids: &[Id], xml: &OsmXml, dropped: &mut Vec<Reason>) -> Vec<Ring> {
+    let mut open: Vec<(Id, &[Id])> = Vec::new();
+    for id in way_ids {
+        match xml.ways.get(id) {
+            Some(way) if way.nodes.len() >= 2 => open.push((*id, &way.nodes[..])),
+            Some(_) => {}
+            None => dropped.push((ParseIssueKind::MissingMember, format!("way {} is missing", id))),
+        }
+    }
+
+    let mut rings = Vec::new();
+    'rings: while let Some((first_id, first)) = open.pop() {
+        let mut ring = first.to_vec();
+        let mut single = true;
+
+        while ring[0] != ring[ring.len() - 1] {
+            let end = ring[ring.len() - 1];
+            let next = match open.iter().position(|&(_, nodes)| nodes[0] == end || nodes[nodes.len() - 1] == end) {
+                Some(next) => next,
+                None => {
+                    dropped.push((ParseIssueKind::UnclosedRing, format!("ring through node {} isn't closed, dropped", end)));
+                    continue 'rings;
+                }
+            };
+
+            let (_, nodes) = open.swap_remove(next);
+            if nodes[0] == end {
+                ring.extend_from_slice(&nodes[1..]);
+            } else {
+                ring.extend(nodes.iter().rev().skip(1));
+            }
+            single = false;
+        }
+
+        ring.pop();
+        if ring.len() >= 3 {
+            rings.push((ring, if single { Some(first_id) } else { None }));
+        }
+    }
+
+    rings
+}
+
+/// Relations share ids with ways, so areas made from their outer rings are negative, with the
+/// ring's index above the bits OSM ids use so each ring gets its own
+fn relation_area_id(relation: Id, ring: usize) -> Id {
+    -(relation | ((ring as Id) << 40))
+}
+
+/// Relations tagged as a land use or building become one for each outer ring. Otherwise the
+/// holes go to the areas of the outer ways, as older multipolygons are tagged on the outer way
+fn add_multipolygon(id: Id, rel: &Element, polygons: Vec<Polygon>, land_uses: &mut HashMap<Id, LandUse>, buildings: &mut HashMap<Id, Building>) {
+    let land_use_type = classify::land_use_type(&rel.tags);
+    let building = is_building(&rel.tags);
+
+    for (i, polygon) in polygons.into_iter().enumerate() {
+        if let Some(land_use_type) = land_use_type {
+            land_uses.insert(relation_area_id(id, i), LandUse {
+                land_use_type,
+                points: polygon.outline,
+                holes: polygon.holes,
+                triangles: Vec::new(),
+                tags: rel.tags.clone(),
+            });
+        } else if building {
+            buildings.insert(relation_area_id(id, i), Building {
+                building_type: classify::building_type(&rel.tags).unwrap_or_default(),
+                points: polygon.outline,
+                holes: polygon.holes,
+                triangles: Vec::new(),
+                tags: rel.tags.clone(),
+            });
+        } else if let Some(way) = polygon.way {
+            if let Some(lu) = land_uses.get_mut(&way) {
+                lu.holes.extend(polygon.holes.iter().cloned());
+            }
+            if let Some(b) = buildings.get_mut(&way) {
+                b.holes.extend(polygon.holes);
+            }
         }
     }
 }
@@ -179,23 +734,84 @@ pub struct PartialWorld {
     pub roads: HashMap<Id, Road>,
     pub land_uses: HashMap<Id, LandUse>,
     pub buildings: HashMap<Id, Building>,
+
+    #[serde(default)]
+    pub pois: HashMap<Id, PointOfInterest>,
+
+    #[serde(default)]
+    pub railways: HashMap<Id, Railway>,
+
+    #[serde(default)]
+    pub transit_lines: HashMap<Id, TransitLine>,
+
+    /// `classify::VERSION` when parsed, 0 if cached before it was versioned
+    #[serde(default)]
+    pub classification: u32,
+
+    /// Elements that were skipped or only partly understood, not kept in the chunk cache
+    #[serde(skip)]
+    pub warnings: Vec<ParseIssue>,
 }
 
-impl From<OsmWorld> for PartialWorld {
-    fn from(w: OsmWorld) -> Self {
-        PartialWorld {
-            roads: convert_vec_to_map(&w.roads),
-            land_uses: convert_vec_to_map(&w.land_uses),
-            buildings: HashMap::new(),
-        }
+fn convert_world(w: &OsmWorld, xml: &OsmXml, mode: ParseMode, mut warnings: Vec<ParseIssue>) -> Result<PartialWorld, ParseIssue> {
+    let roads = convert_to_map(read_vec(&w.roads), mode, &xml.ways, &mut warnings, |r, warnings| convert_road(r, xml, warnings).map(Some))?;
+    let mut land_uses = convert_to_map(read_vec(&w.land_uses), mode, &xml.ways, &mut warnings, |lu, _| convert_land_use(lu, xml).map(Some))?;
+
+    let building_ways = xml.ways.iter()
+        .filter(|&(_, w)| is_building(&w.tags))
+        .map(|(id, w)| (*id, (*id, w)));
+    let mut buildings = convert_to_map(building_ways, mode, &xml.ways, &mut warnings, |(id, way), _| convert_building(id, way, xml))?;
+
+    let multipolygons = xml.relations.iter()
+        .filter(|&(_, r)| r.tags.get("type") == Some("multipolygon"))
+        .map(|(id, r)| (*id, (*id, r)));
+    let multipolygons = convert_to_map(multipolygons, mode, &xml.relations, &mut warnings, |(id, rel), warnings| {
+        Ok(Some(convert_multipolygon(id, rel, xml, warnings)))
+    })?;
+    for (id, polygons) in multipolygons {
+        add_multipolygon(id, &xml.relations[&id], polygons, &mut land_uses, &mut buildings);
     }
+
+    // not way geometry, or tagged nodes like benches and post boxes
+    let classified = xml.nodes.iter()
+        .filter_map(|(id, n)| PoiCategory::classify(&n.tags).map(|c| (*id, (n, c))));
+    let pois = convert_to_map(classified, mode, &xml.nodes, &mut warnings, |(n, c), _| convert_poi(n, c).map(Some))?;
+
+    // other railways such as platforms and disused lines aren't drawn
+    let rails = xml.ways.iter()
+        .filter(|&(_, w)| RailKind::from_tags(&w.tags).is_some())
+        .map(|(id, w)| (*id, (*id, w)));
+    let railways = convert_to_map(rails, mode, &xml.ways, &mut warnings, |(id, way), _| convert_railway(id, way, xml))?;
+
+    // as are hiking, cycling and other routes
+    let routes = xml.relations.iter()
+        .filter(|&(_, r)| r.tags.get("type") == Some("route") && TransitMode::from_tags(&r.tags).is_some())
+        .map(|(id, r)| (*id, (*id, r)));
+    let transit_lines = convert_to_map(routes, mode, &xml.relations, &mut warnings, |(id, rel), warnings| {
+        convert_transit_line(id, rel, &pois, &roads, &railways, warnings)
+    })?;
+
+    Ok(PartialWorld {
+        roads,
+        land_uses,
+        buildings,
+        pois,
+        railways,
+        transit_lines,
+        classification: classify::VERSION,
+        warnings,
+    })
 }
 
 impl PartialWorld {
 
     pub fn make_coords_relative_to(&mut self, origin: &LatLon) {
         fn make_relative<T: PointsHolder>(x: &mut T, origin: &OsmPoint) {
-            for p in x.pixels() {
+            make_points_relative(x.pixels(), origin);
+        }
+
+        fn make_points_relative(points: &mut [Point], origin: &OsmPoint) {
+            for p in points {
                 (*p).x -= origin.x;
                 (*p).y -= origin.y;
             }
@@ -209,6 +825,46 @@ impl PartialWorld {
 
         for lu in self.land_uses.values_mut() {
             make_relative(lu, &rel);
+            for h in &mut lu.holes {
+                make_points_relative(h, &rel);
+            }
+        }
+
+        for b in self.buildings.values_mut() {
+            make_relative(b, &rel);
+            for h in &mut b.holes {
+                make_points_relative(h, &rel);
+            }
+        }
+
+        for p in self.pois.values_mut() {
+            p.position.x -= rel.x;
+            p.position.y -= rel.y;
+        }
+
+        for r in self.railways.values_mut() {
+            make_relative(r, &rel);
+        }
+
+        for l in self.transit_lines.values_mut() {
+            for s in &mut l.stops {
+                s.position.x -= rel.x;
+                s.position.y -= rel.y;
+            }
+            for p in &mut l.path {
+                make_points_relative(p, &rel);
+            }
+        }
+    }
+
+    /// Triangulates all areas that aren't already, so they can be filled when rendered
+    pub fn triangulate(&mut self) {
+        for lu in self.land_uses.values_mut() {
+            lu.triangulate();
+        }
+
+        for b in self.buildings.values_mut() {
+            b.triangulate();
         }
     }
 }
@@ -225,21 +881,40 @@ extern "C" {
     fn free_world(world: *mut OsmWorld);
 }
 
-pub fn parse_osm(xml: String) -> SimResult<PartialWorld> {
+/// libosm only returns a code when it fails, so the XML is first read in Rust to find where
+/// anything is wrong with it
+pub fn parse_osm(xml: String, mode: ParseMode) -> SimResult<PartialWorld> {
+    let mut warnings = Vec::new();
+    let scanned = OsmXml::scan(&xml, mode, &mut warnings).map_err(ErrorKind::OsmParse)?;
+
     let len = xml.len();
     let cstr = ffi::CString::new(xml)?;
     let mut osm_world = OsmWorld::default();
 
-    match unsafe {
+    let ret = unsafe {
         parse_osm_from_buffer(
             cstr.as_ptr() as *const _,
             len as size_t,
             &mut osm_world as *mut _,
         )
-    } {
-        0 => Ok(PartialWorld::from(osm_world)),
-        e => Err(ErrorKind::OsmParse(e).into()),
+    };
+
+    if ret != 0 {
+        let issue = ParseIssue {
+            line: None,
+            column: None,
+            element: None,
+            kind: ParseIssueKind::LibOsm,
+            reason: format!("libosm failed with code {}", ret),
+        };
+        return Err(ErrorKind::OsmParse(issue).into());
+    }
+
+    let world = convert_world(&osm_world, &scanned, mode, warnings).map_err(ErrorKind::OsmParse)?;
+    for warning in &world.warnings {
+        warn!("Skipped or changed element: {}", warning);
     }
+    Ok(world)
 }
 
 
@@ -280,3 +955,130 @@ fn test_from_file(path: &str) -> Result<World, i32> {
     }
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A square split into two outer ways around a square hole, and a building filling the hole
+    const MULTIPOLYGON: &str = r#"<osm>
+        <node id="1" lat="0.0" lon="0.0"/>
+        <node id="2" lat="0.0" lon="0.01"/>
+        <node id="3" lat="0.01" lon="0.01"/>
+        <node id="4" lat="0.01" lon="0.0"/>
+        <node id="5" lat="0.004" lon="0.004"/>
+        <node id="6" lat="0.004" lon="0.006"/>
+        <node id="7" lat="0.006" lon="0.006"/>
+        <node id="8" lat="0.006" lon="0.004"/>
+        <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/></way>
+        <way id="11"><nd ref="3"/><nd ref="4"/><nd ref="1"/></way>
+        <way id="12">
+            <nd ref="5"/><nd ref="6"/><nd ref="7"/><nd ref="8"/><nd ref="5"/>
+            <tag k="building" v="yes"/>
+        </way>
+        <way id="13"><nd ref="5"/><nd ref="6"/><tag k="building" v="yes"/></way>
+        <relation id="20">
+            <member type="way" ref="10" role="outer"/>
+            <member type="way" ref="11" role="outer"/>
+            <member type="way" ref="12" role="inner"/>
+            <tag k="type" v="multipolygon"/>
+            <tag k="landuse" v="forest"/>
+        </relation>
+    </osm>"#;
+
+    /// The outer ring is missing a way, so can't be closed
+    const BROKEN_MULTIPOLYGON: &str = r#"<osm>
+        <node id="1" lat="0.0" lon="0.0"/>
+        <node id="2" lat="0.0" lon="0.01"/>
+        <node id="3" lat="0.01" lon="0.01"/>
+        <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/></way>
+        <relation id="20">
+            <member type="way" ref="10" role="outer"/>
+            <member type="way" ref="11" role="outer"/>
+            <tag k="type" v="multipolygon"/>
+            <tag k="landuse" v="forest"/>
+        </relation>
+    </osm>"#;
+
+    /// A tram line whose stops are all outside the chunk, along a track that's partly outside it
+    const CUT_OFF_ROUTE: &str = r#"<osm>
+        <node id="1" lat="0.0" lon="0.0"/>
+        <node id="2" lat="0.0" lon="0.01"/>
+        <way id="10"><nd ref="1"/><nd ref="2"/><tag k="railway" v="tram"/></way>
+        <way id="11"><nd ref="2"/><nd ref="3"/><tag k="railway" v="tram"/></way>
+        <relation id="20">
+            <member type="node" ref="4" role="stop"/>
+            <member type="way" ref="10" role=""/>
+            <member type="way" ref="11" role=""/>
+            <tag k="type" v="route"/>
+            <tag k="route" v="tram"/>
+        </relation>
+    </osm>"#;
+
+    const TAGGED_NODES: &str = r#"<osm>
+        <node id="1" lat="0.0" lon="0.0"><tag k="highway" v="bus_stop"/></node>
+        <node id="2" lat="0.0" lon="0.01"><tag k="amenity" v="bench"/></node>
+        <node id="3" lat="0.01" lon="0.01"/>
+    </osm>"#;
+
+    fn convert(osm: &str, mode: ParseMode) -> Result<PartialWorld, ParseIssue> {
+        let mut warnings = Vec::new();
+        let xml = OsmXml::scan(osm, mode, &mut warnings)?;
+        convert_world(&OsmWorld::default(), &xml, mode, warnings)
+    }
+
+    #[test]
+    fn multipolygon_outer_ways_joined_around_hole() {
+        let world = convert(MULTIPOLYGON, ParseMode::Lenient).unwrap();
+
+        let forest = &world.land_uses[&relation_area_id(20, 0)];
+        assert_eq!(forest.land_use_type, LandUseType::Forest);
+        assert_eq!(forest.points.len(), 4);
+        assert_eq!(forest.holes.len(), 1);
+        assert_eq!(forest.holes[0].len(), 4);
+    }
+
+    #[test]
+    fn unclosed_building_skipped_when_lenient() {
+        let world = convert(MULTIPOLYGON, ParseMode::Lenient).unwrap();
+
+        assert_eq!(world.buildings.len(), 1);
+        assert_eq!(world.buildings[&12].points.len(), 4);
+        let warning = world.warnings.iter().find(|w| w.element == Some(13)).unwrap();
+        assert_eq!(warning.kind, ParseIssueKind::UnclosedRing);
+        assert_eq!(warning.line, Some(16));
+    }
+
+    #[test]
+    fn unclosed_building_fails_when_strict() {
+        let issue = convert(MULTIPOLYGON, ParseMode::Strict).unwrap_err();
+        assert_eq!(issue.element, Some(13));
+        assert_eq!(issue.kind, ParseIssueKind::UnclosedRing);
+    }
+
+    #[test]
+    fn broken_multipolygon_skipped_when_strict() {
+        let world = convert(BROKEN_MULTIPOLYGON, ParseMode::Strict).unwrap();
+
+        assert!(world.land_uses.is_empty());
+        let kinds: Vec<_> = world.warnings.iter().filter(|w| w.element == Some(20) && w.line == Some(6)).map(|w| w.kind).collect();
+        assert!(kinds.contains(&ParseIssueKind::MissingMember));
+        assert!(kinds.contains(&ParseIssueKind::MissingOuterRing));
+    }
+
+    #[test]
+    fn cut_off_route_skipped_when_strict() {
+        let world = convert(CUT_OFF_ROUTE, ParseMode::Strict).unwrap();
+
+        assert!(world.transit_lines.is_empty());
+        assert_eq!(world.railways.keys().collect::<Vec<_>>(), vec![&10]);
+    }
+
+    #[test]
+    fn only_classified_nodes_are_pois() {
+        let world = convert(TAGGED_NODES, ParseMode::Strict).unwrap();
+
+        assert_eq!(world.pois.len(), 1);
+        assert_eq!(world.pois[&1].category, PoiCategory::BusStop);
+    }
+}
@@ -0,0 +1,108 @@
+use world::{Area, Feature, Point, World};
+
+/// Finds the feature under the given world pixel. Points of interest, then roads, then railways
+/// within `tolerance` pixels win over areas, and the smallest area containing the point wins over
+/// any larger ones around it. Transit lines are never picked, they're found through their stops
+pub fn pick(world: &World, x: f64, y: f64, tolerance: f64) -> Option<Feature> {
+    let at = Point { x: x.round() as i32, y: y.round() as i32 };
+    if let Some(id) = world.nearest_poi(&at, None, tolerance.ceil() as i32) {
+        return Some(Feature::PointOfInterest(id));
+    }
+
+    let closest_road = world.loaded_roads.iter()
+        .map(|(id, r)| (*id, distance_sq_to_polyline(&r.segments, x, y)))
+        .filter(|&(_, d)| d <= tolerance * tolerance)
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+    if let Some((id, _)) = closest_road {
+        return Some(Feature::Road(id));
+    }
+
+    let closest_railway = world.loaded_railways.iter()
+        .map(|(id, r)| (*id, distance_sq_to_polyline(&r.points, x, y)))
+        .filter(|&(_, d)| d <= tolerance * tolerance)
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+    if let Some((id, _)) = closest_railway {
+        return Some(Feature::Railway(id));
+    }
+
+    let buildings = world.loaded_buildings.iter()
+        .filter(|&(_, b)| area_contains(b, x, y))
+        .map(|(id, b)| (Feature::Building(*id), area_size(b)));
+
+    let land_uses = world.loaded_land_uses.iter()
+        .filter(|&(_, lu)| area_contains(lu, x, y))
+        .map(|(id, lu)| (Feature::LandUse(*id), area_size(lu)));
+
+    buildings.chain(land_uses)
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map(|(f, _)| f)
+}
+
+fn distance_sq_to_polyline(points: &[Point], x: f64, y: f64) -> f64 {
+    if points.len() == 1 {
+        let (dx, dy) = (f64::from(points[0].x) - x, f64::from(points[0].y) - y);
+        return dx * dx + dy * dy;
+    }
+
+    points.windows(2)
+        .map(|w| distance_sq_to_segment(&w[0], &w[1], x, y))
+        .fold(::std::f64::INFINITY, f64::min)
+}
+
+fn distance_sq_to_segment(a: &Point, b: &Point, x: f64, y: f64) -> f64 {
+    let (ax, ay) = (f64::from(a.x), f64::from(a.y));
+    let (bx, by) = (f64::from(b.x), f64::from(b.y));
+    let (abx, aby) = (bx - ax, by - ay);
+
+    let len_sq = abx * abx + aby * aby;
+    let t = if len_sq == 0.0 {
+        0.0
+    } else {
+        (((x - ax) * abx + (y - ay) * aby) / len_sq).max(0.0).min(1.0)
+    };
+
+    let (dx, dy) = (ax + t * abx - x, ay + t * aby - y);
+    dx * dx + dy * dy
+}
+
+/// Even-odd rule, so it doesn't matter whether the polygon is closed or which way it winds
+pub fn contains(points: &[Point], x: f64, y: f64) -> bool {
+    if points.len() < 3 {
+        return false;
+    }
+
+    let mut inside = false;
+    let mut j = points.len() - 1;
+    for i in 0..points.len() {
+        let (xi, yi) = (f64::from(points[i].x), f64::from(points[i].y));
+        let (xj, yj) = (f64::from(points[j].x), f64::from(points[j].y));
+
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+/// Inside the outline but not in any of its holes, like a courtyard
+fn area_contains<A: Area>(a: &A, x: f64, y: f64) -> bool {
+    contains(a.outline(), x, y) && !a.holes().iter().any(|h| contains(h, x, y))
+}
+
+/// Not counting its holes
+fn area_size<A: Area>(a: &A) -> f64 {
+    area(a.outline()) - a.holes().iter().map(|h| area(h)).sum::<f64>()
+}
+
+fn area(points: &[Point]) -> f64 {
+    let mut sum = 0.0;
+    let mut j = points.len().saturating_sub(1);
+    for i in 0..points.len() {
+        sum += f64::from(points[j].x) * f64::from(points[i].y) - f64::from(points[i].x) * f64::from(points[j].y);
+        j = i;
+    }
+    (sum / 2.0).abs()
+}
@@ -0,0 +1,248 @@
+use std::f64;
+
+use gtfs::{SimDay, Timetable};
+use poi::PoiIndex;
+use transit::SimTime;
+use world::{Id, Point};
+
+/// Metres per second
+const WALKING_SPEED: f64 = 1.4;
+
+/// Furthest walked to the first stop or from the last one
+const MAX_ACCESS_METRES: f64 = 800.0;
+
+/// Furthest walked between stops to change
+const MAX_TRANSFER_METRES: f64 = 300.0;
+
+/// A hop between consecutive stops of a trip
+#[derive(Debug, Clone, Copy)]
+struct Connection {
+    trip: usize,
+    from: usize,
+    to: usize,
+    departure: SimTime,
+    arrival: SimTime,
+}
+
+#[derive(Debug, Clone)]
+pub enum Leg {
+    Walk {
+        from: Point,
+        to: Point,
+        departure: SimTime,
+        arrival: SimTime,
+    },
+
+    /// Stops are indices into `Timetable::stops`
+    Ride {
+        trip: usize,
+        from: usize,
+        to: usize,
+        departure: SimTime,
+        arrival: SimTime,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Journey {
+    pub legs: Vec<Leg>,
+    pub arrival: SimTime,
+}
+
+/// How a stop was reached most quickly so far
+#[derive(Debug, Clone, Copy)]
+enum Reached {
+    Unreached,
+    FromOrigin,
+
+    /// Indices of the connections boarded and alighted from
+    Ride(usize, usize),
+
+    /// Walked from the stop after alighting there
+    Transfer(usize),
+}
+
+/// Plans journeys on one day's timetable, walking to, between and from stops
+pub struct Planner<'a> {
+    timetable: &'a Timetable,
+
+    /// Of the day's trips, by departure
+    connections: Vec<Connection>,
+
+    /// Other stops within walking distance of each stop, with the time to walk there
+    transfers: Vec<Vec<(usize, SimTime)>>,
+    stop_index: PoiIndex,
+
+    /// World pixels per second
+    walking_speed: f64,
+    max_access: i32,
+}
+
+fn distance(a: &Point, b: &Point) -> f64 {
+    let (dx, dy) = (f64::from(a.x - b.x), f64::from(a.y - b.y));
+    (dx * dx + dy * dy).sqrt()
+}
+
+impl Leg {
+    pub fn arrival(&self) -> SimTime {
+        match *self {
+            Leg::Walk { arrival, .. } | Leg::Ride { arrival, .. } => arrival,
+        }
+    }
+}
+
+impl<'a> Planner<'a> {
+    pub fn new(timetable: &'a Timetable, day: &SimDay, pixels_per_metre: f64) -> Self {
+        let mut connections = Vec::new();
+        for (i, trip) in timetable.trips.iter().enumerate() {
+            if !timetable.runs_on(trip, day) {
+                continue;
+            }
+
+            connections.extend(trip.stop_times.windows(2).map(|w| Connection {
+                trip: i,
+                from: w[0].stop,
+                to: w[1].stop,
+                departure: w[0].departure,
+                arrival: w[1].arrival,
+            }));
+        }
+        connections.sort_by(|a, b| a.departure.partial_cmp(&b.departure).unwrap());
+
+        // stops are indexed in the same grid as points of interest
+        let mut stop_index = PoiIndex::default();
+        for (i, stop) in timetable.stops.iter().enumerate() {
+            stop_index.insert(i as Id, stop.position);
+        }
+
+        let walking_speed = WALKING_SPEED * pixels_per_metre;
+        let max_transfer = (MAX_TRANSFER_METRES * pixels_per_metre) as i32;
+        let transfers = timetable.stops.iter().enumerate()
+            .map(|(i, stop)| {
+                stop_index.within(&stop.position, max_transfer).into_iter()
+                    .map(|other| other as usize)
+                    .filter(|&other| other != i)
+                    .map(|other| (other, distance(&stop.position, &timetable.stops[other].position) / walking_speed))
+                    .collect()
+            })
+            .collect();
+
+        Planner {
+            timetable,
+            connections,
+            transfers,
+            stop_index,
+            walking_speed,
+            max_access: (MAX_ACCESS_METRES * pixels_per_metre) as i32,
+        }
+    }
+
+    fn walk(&self, from: Point, to: Point, departure: SimTime) -> Leg {
+        Leg::Walk {
+            from,
+            to,
+            departure,
+            arrival: departure + distance(&from, &to) / self.walking_speed,
+        }
+    }
+
+    /// The journey arriving soonest when leaving at `departure`, which may be walking all the
+    /// way. Uses the connection scan algorithm, so never waits for a later trip to arrive earlier
+    pub fn plan(&self, from: Point, to: Point, departure: SimTime) -> Journey {
+        let stops = &self.timetable.stops;
+        let mut arrival = vec![f64::INFINITY; stops.len()];
+        let mut reached = vec![Reached::Unreached; stops.len()];
+        let mut boarded: Vec<Option<usize>> = vec![None; self.timetable.trips.len()];
+
+        for s in self.stop_index.within(&from, self.max_access) {
+            let s = s as usize;
+            arrival[s] = self.walk(from, stops[s].position, departure).arrival();
+            reached[s] = Reached::FromOrigin;
+        }
+
+        // time to walk to the destination from the stops near it
+        let mut egress = vec![None; stops.len()];
+        for s in self.stop_index.within(&to, self.max_access) {
+            let s = s as usize;
+            egress[s] = Some(distance(&stops[s].position, &to) / self.walking_speed);
+        }
+
+        // walking all the way, or alighting at a stop and walking from it
+        let mut best_arrival = self.walk(from, to, departure).arrival();
+        let mut best_egress = None;
+
+        let first = self.connections.iter().position(|c| c.departure >= departure).unwrap_or(self.connections.len());
+        for (i, c) in self.connections.iter().enumerate().skip(first) {
+            if c.departure >= best_arrival {
+                break;
+            }
+
+            if boarded[c.trip].is_none() {
+                if arrival[c.from] > c.departure {
+                    continue;
+                }
+                boarded[c.trip] = Some(i);
+            }
+
+            if c.arrival >= arrival[c.to] {
+                continue;
+            }
+            arrival[c.to] = c.arrival;
+            reached[c.to] = Reached::Ride(boarded[c.trip].unwrap(), i);
+            let mut improved = vec![c.to];
+
+            for &(other, walk) in &self.transfers[c.to] {
+                if c.arrival + walk < arrival[other] {
+                    arrival[other] = c.arrival + walk;
+                    reached[other] = Reached::Transfer(c.to);
+                    improved.push(other);
+                }
+            }
+
+            for s in improved {
+                if let Some(walk) = egress[s] {
+                    if arrival[s] + walk < best_arrival {
+                        best_arrival = arrival[s] + walk;
+                        best_egress = Some(s);
+                    }
+                }
+            }
+        }
+
+        let last_stop = match best_egress {
+            Some(s) => s,
+            None => return Journey { legs: vec![self.walk(from, to, departure)], arrival: best_arrival },
+        };
+
+        // back from the destination to the origin, then reversed
+        let mut legs = vec![self.walk(stops[last_stop].position, to, arrival[last_stop])];
+        let mut stop = last_stop;
+        loop {
+            match reached[stop] {
+                Reached::FromOrigin => {
+                    legs.push(self.walk(from, stops[stop].position, departure));
+                    break;
+                }
+                Reached::Ride(board, alight) => {
+                    let (board, alight) = (&self.connections[board], &self.connections[alight]);
+                    legs.push(Leg::Ride {
+                        trip: board.trip,
+                        from: board.from,
+                        to: alight.to,
+                        departure: board.departure,
+                        arrival: alight.arrival,
+                    });
+                    stop = board.from;
+                }
+                Reached::Transfer(prev) => {
+                    legs.push(self.walk(stops[prev].position, stops[stop].position, arrival[prev]));
+                    stop = prev;
+                }
+                Reached::Unreached => unreachable!("journey through an unreached stop"),
+            }
+        }
+
+        legs.reverse();
+        Journey { legs, arrival: best_arrival }
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+use tags::Tags;
+use world::{Id, Point};
+
+/// Side of a `PoiIndex` cell in world pixels
+const CELL_SIZE: i32 = 256;
+
+/// What a point of interest is for, so agents can pick destinations by it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PoiCategory {
+    Shop,
+    Food,
+    School,
+    Hospital,
+    BusStop,
+    Station,
+    TrafficSignals,
+    Parking,
+}
+
+/// The first rule whose key and value globs match a tag wins
+const RULES: &[(&str, &str, PoiCategory)] = &[
+    ("highway", "bus_stop", PoiCategory::BusStop),
+    ("railway", "station", PoiCategory::Station),
+    ("railway", "halt", PoiCategory::Station),
+    ("railway", "tram_stop", PoiCategory::Station),
+    ("public_transport", "station", PoiCategory::Station),
+    // stop positions and platforms, which are rail unless they say they're for buses
+    ("bus", "yes", PoiCategory::BusStop),
+    ("public_transport", "*", PoiCategory::Station),
+    ("highway", "traffic_signals", PoiCategory::TrafficSignals),
+    ("amenity", "hospital", PoiCategory::Hospital),
+    ("amenity", "clinic", PoiCategory::Hospital),
+    ("amenity", "school", PoiCategory::School),
+    ("amenity", "kindergarten", PoiCategory::School),
+    ("amenity", "college", PoiCategory::School),
+    ("amenity", "university", PoiCategory::School),
+    ("amenity", "*parking", PoiCategory::Parking),
+    ("amenity", "restaurant", PoiCategory::Food),
+    ("amenity", "cafe", PoiCategory::Food),
+    ("amenity", "fast_food", PoiCategory::Food),
+    ("amenity", "pub", PoiCategory::Food),
+    ("amenity", "bar", PoiCategory::Food),
+    ("shop", "*", PoiCategory::Shop),
+];
+
+impl PoiCategory {
+    /// None for nodes that aren't points of interest, like benches and post boxes
+    pub fn classify(tags: &Tags) -> Option<Self> {
+        RULES.iter()
+            .find(|&&(key, value, _)| tags.matches(key, value))
+            .map(|&(_, _, category)| category)
+    }
+}
+
+/// Grid of point of interest positions, so those near a point can be found without checking
+/// every loaded one
+#[derive(Debug, Default)]
+pub struct PoiIndex {
+    cells: HashMap<(i32, i32), Vec<(Id, Point)>>,
+}
+
+fn cell_of(p: &Point) -> (i32, i32) {
+    // rounding down, so cells either side of 0 are the same size
+    let floor_div = |v: i32| if v >= 0 { v / CELL_SIZE } else { (v - CELL_SIZE + 1) / CELL_SIZE };
+    (floor_div(p.x), floor_div(p.y))
+}
+
+fn distance_sq(a: &Point, b: &Point) -> i64 {
+    let (dx, dy) = (i64::from(a.x - b.x), i64::from(a.y - b.y));
+    dx * dx + dy * dy
+}
+
+impl PoiIndex {
+    pub fn insert(&mut self, id: Id, position: Point) {
+        self.cells.entry(cell_of(&position)).or_insert_with(Vec::new).push((id, position));
+    }
+
+    pub fn remove(&mut self, id: Id, position: &Point) {
+        let cell = cell_of(position);
+        let now_empty = match self.cells.get_mut(&cell) {
+            Some(ids) => {
+                ids.retain(|&(i, _)| i != id);
+                ids.is_empty()
+            }
+            None => false,
+        };
+
+        if now_empty {
+            self.cells.remove(&cell);
+        }
+    }
+
+    /// All within `radius` world pixels of the point, in no particular order
+    pub fn within(&self, centre: &Point, radius: i32) -> Vec<Id> {
+        let (min_x, min_y) = cell_of(&Point { x: centre.x - radius, y: centre.y - radius });
+        let (max_x, max_y) = cell_of(&Point { x: centre.x + radius, y: centre.y + radius });
+        let radius_sq = i64::from(radius) * i64::from(radius);
+
+        let mut found = Vec::new();
+        for cy in min_y..max_y + 1 {
+            for cx in min_x..max_x + 1 {
+                if let Some(cell) = self.cells.get(&(cx, cy)) {
+                    found.extend(cell.iter()
+                        .filter(|&&(_, ref p)| distance_sq(p, centre) <= radius_sq)
+                        .map(|&(id, _)| id));
+                }
+            }
+        }
+        found
+    }
+
+    /// The closest accepted by `filter` within `max_distance` world pixels, searching outwards a
+    /// ring of cells at a time
+    pub fn nearest<F: Fn(Id) -> bool>(&self, to: &Point, max_distance: i32, filter: F) -> Option<Id> {
+        let (cx, cy) = cell_of(to);
+        let max_sq = i64::from(max_distance) * i64::from(max_distance);
+        let mut best: Option<(i64, Id)> = None;
+
+        for ring in 0..max_distance / CELL_SIZE + 2 {
+            // nothing in this ring or beyond can be closer than its inner edge
+            let inner = i64::from((ring - 1).max(0) * CELL_SIZE);
+            if inner * inner > best.map(|(d, _)| d).unwrap_or(max_sq) {
+                break;
+            }
+
+            for dy in -ring..ring + 1 {
+                for dx in -ring..ring + 1 {
+                    if dx.abs() != ring && dy.abs() != ring {
+                        continue;
+                    }
+
+                    let cell = match self.cells.get(&(cx + dx, cy + dy)) {
+                        Some(cell) => cell,
+                        None => continue,
+                    };
+
+                    for &(id, ref p) in cell {
+                        let d = distance_sq(p, to);
+                        if d <= max_sq && best.map(|(b, _)| d < b).unwrap_or(true) && filter(id) {
+                            best = Some((d, id));
+                        }
+                    }
+                }
+            }
+        }
+
+        best.map(|(_, id)| id)
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use world::{Area, Feature, Id, Point, World};
+use triangulate;
+
+/// Douglas-Peucker tolerance in world pixels of each level of detail. Level 0 is the full detail
+/// geometry in the world itself
+const TOLERANCES: [f64; 5] = [0.0, 2.0, 8.0, 32.0, 128.0];
+
+/// Max error allowed in screen pixels when choosing a level
+const SCREEN_TOLERANCE: f64 = 0.5;
+
+/// Simplified outline of an area, triangulated again as it may have changed shape
+pub struct SimplifiedArea {
+    points: Vec<Point>,
+    holes: Vec<Vec<Point>>,
+    triangles: Vec<u32>,
+}
+
+/// Simplified geometry of a chunk's features at every level but the first
+#[derive(Default)]
+pub struct ChunkLod {
+    roads: HashMap<Id, Vec<Vec<Point>>>,
+    areas: HashMap<Feature, Vec<SimplifiedArea>>,
+}
+
+impl Area for SimplifiedArea {
+    fn outline(&self) -> &[Point] {
+        &self.points
+    }
+
+    fn holes(&self) -> &[Vec<Point>] {
+        &self.holes
+    }
+
+    fn triangles(&self) -> &[u32] {
+        &self.triangles
+    }
+
+    fn triangulate(&mut self) {
+        if self.triangles.is_empty() {
+            self.triangles = triangulate::triangulate(&self.points, &self.holes);
+        }
+    }
+}
+
+/// The coarsest level whose error is still under half a screen pixel at this zoom
+pub fn level_for_zoom(zoom: f64) -> usize {
+    TOLERANCES.iter()
+        .rposition(|&t| t <= zoom * SCREEN_TOLERANCE)
+        .unwrap_or(0)
+}
+
+impl ChunkLod {
+    pub fn build(world: &World, features: &[Feature]) -> Self {
+        let mut lod = ChunkLod::default();
+        let levels = &TOLERANCES[1..];
+
+        for &feature in features {
+            match feature {
+                Feature::Road(id) => if let Some(r) = world.loaded_roads.get(&id) {
+                    let simplified = levels.iter().map(|&t| simplify(&r.segments, t)).collect();
+                    lod.roads.insert(id, simplified);
+                },
+                Feature::LandUse(id) => if let Some(lu) = world.loaded_land_uses.get(&id) {
+                    lod.areas.insert(feature, levels.iter().map(|&t| simplify_area(lu, t)).collect());
+                },
+                Feature::Building(id) => if let Some(b) = world.loaded_buildings.get(&id) {
+                    lod.areas.insert(feature, levels.iter().map(|&t| simplify_area(b, t)).collect());
+                },
+                Feature::PointOfInterest(_) | Feature::Railway(_) | Feature::TransitLine(_) => {}
+            }
+        }
+
+        lod
+    }
+
+    /// None at level 0, or if the road wasn't in this chunk when it was built
+    pub fn road(&self, id: Id, level: usize) -> Option<&[Point]> {
+        if level == 0 {
+            return None;
+        }
+        self.roads.get(&id).map(|levels| &levels[level - 1][..])
+    }
+
+    pub fn area(&self, feature: Feature, level: usize) -> Option<&SimplifiedArea> {
+        if level == 0 {
+            return None;
+        }
+        self.areas.get(&feature).map(|levels| &levels[level - 1])
+    }
+}
+
+/// Rings that simplify down to less than a triangle are dropped, so small areas disappear
+/// entirely when zoomed out
+fn simplify_area<A: Area>(area: &A, tolerance: f64) -> SimplifiedArea {
+    let ring = |points: &[Point]| {
+        let simplified = simplify(points, tolerance);
+        let closed = simplified.len() > 1 &&
+            simplified[0].x == simplified[simplified.len() - 1].x &&
+            simplified[0].y == simplified[simplified.len() - 1].y;
+        let corners = if closed { simplified.len() - 1 } else { simplified.len() };
+        if corners < 3 { Vec::new() } else { simplified }
+    };
+
+    let points = ring(area.outline());
+    let holes = if points.is_empty() {
+        Vec::new()
+    } else {
+        area.holes().iter().map(|h| ring(h)).filter(|h| !h.is_empty()).collect()
+    };
+
+    let mut simplified = SimplifiedArea { points, holes, triangles: Vec::new() };
+    simplified.triangulate();
+    simplified
+}
+
+/// Douglas-Peucker, keeping the first and last points
+pub fn simplify(points: &[Point], tolerance: f64) -> Vec<Point> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let tolerance_sq = tolerance * tolerance;
+    let mut stack = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = stack.pop() {
+        let furthest = (first + 1..last)
+            .map(|i| (i, distance_sq_to_segment(&points[i], &points[first], &points[last])))
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+        if let Some((i, d)) = furthest {
+            if d > tolerance_sq {
+                keep[i] = true;
+                stack.push((first, i));
+                stack.push((i, last));
+            }
+        }
+    }
+
+    points.iter()
+        .zip(keep)
+        .filter(|&(_, k)| k)
+        .map(|(p, _)| *p)
+        .collect()
+}
+
+fn distance_sq_to_segment(p: &Point, a: &Point, b: &Point) -> f64 {
+    let (px, py) = (f64::from(p.x), f64::from(p.y));
+    let (ax, ay) = (f64::from(a.x), f64::from(a.y));
+    let (abx, aby) = (f64::from(b.x) - ax, f64::from(b.y) - ay);
+
+    let len_sq = abx * abx + aby * aby;
+    let t = if len_sq == 0.0 {
+        0.0
+    } else {
+        (((px - ax) * abx + (py - ay) * aby) / len_sq).max(0.0).min(1.0)
+    };
+
+    let (dx, dy) = (ax + t * abx - px, ay + t * aby - py);
+    dx * dx + dy * dy
+}
@@ -0,0 +1,126 @@
+//! Thick polylines as triangles: a quad per segment, with the gap on the outside of each bend
+//! filled by the join.
+
+use std::f32::consts::PI;
+use sfml::graphics::*;
+use sfml::system::*;
+
+use world::Point;
+
+/// Beyond this many half widths, a miter is cut off into a bevel
+const MITER_LIMIT: f32 = 4.0;
+
+/// Max angle in radians covered by each triangle of a round join or cap
+const ROUND_STEP: f32 = 0.4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Join {
+    /// Sharp corners, cut off flat at the ends
+    Miter,
+
+    /// Rounded corners and ends
+    Round,
+}
+
+/// Appends the polyline as triangles of the given width in world pixels
+pub fn stroke(out: &mut Vec<Vertex>, points: &[Point], width: f32, join: Join, colour: Color) {
+    let points = dedup(points);
+    let hw = width / 2.0;
+
+    if points.len() < 2 {
+        if let (Some(&p), Join::Round) = (points.first(), join) {
+            fan(out, p, Vector2f::new(hw, 0.0), 2.0 * PI, colour);
+        }
+        return;
+    }
+
+    let normals: Vec<Vector2f> = points.windows(2)
+        .map(|w| normal(w[0], w[1], hw))
+        .collect();
+
+    for (w, &n) in points.windows(2).zip(&normals) {
+        let (a, b) = (w[0], w[1]);
+        let v = |p: Vector2f| Vertex::with_pos_color(p, colour);
+        out.extend_from_slice(&[v(a + n), v(a - n), v(b + n)]);
+        out.extend_from_slice(&[v(b + n), v(a - n), v(b - n)]);
+    }
+
+    for i in 1..points.len() - 1 {
+        join_at(out, points[i], normals[i - 1], normals[i], hw, join, colour);
+    }
+
+    if join == Join::Round {
+        // half turns from one side of the end to the other, round the outside
+        let last = normals.len() - 1;
+        fan(out, points[0], normals[0], PI, colour);
+        fan(out, points[points.len() - 1], -normals[last], PI, colour);
+    }
+}
+
+fn dedup(points: &[Point]) -> Vec<Vector2f> {
+    let mut out: Vec<Vector2f> = Vec::with_capacity(points.len());
+    for p in points {
+        let v = Vector2f::new(p.x as f32, p.y as f32);
+        if out.last().map(|&l| l != v).unwrap_or(true) {
+            out.push(v);
+        }
+    }
+    out
+}
+
+/// Left hand normal of the segment, scaled to the half width
+fn normal(a: Vector2f, b: Vector2f, hw: f32) -> Vector2f {
+    let d = b - a;
+    let len = (d.x * d.x + d.y * d.y).sqrt();
+    Vector2f::new(-d.y / len * hw, d.x / len * hw)
+}
+
+fn join_at(out: &mut Vec<Vertex>, p: Vector2f, n0: Vector2f, n1: Vector2f, hw: f32, join: Join, colour: Color) {
+    let cross = n0.x * n1.y - n0.y * n1.x;
+    if cross == 0.0 {
+        // straight on, nothing to fill
+        return;
+    }
+
+    // the gap is on the side turned away from
+    let side = if cross > 0.0 { -1.0 } else { 1.0 };
+    let (o0, o1) = (n0 * side, n1 * side);
+    let v = |p: Vector2f| Vertex::with_pos_color(p, colour);
+
+    match join {
+        Join::Round => {
+            let angle = (n0.x * n1.x + n0.y * n1.y) / (hw * hw);
+            fan(out, p, o0, angle.max(-1.0).min(1.0).acos() * -side, colour);
+        }
+        Join::Miter => {
+            let mid = o0 + o1;
+            let mid_len = (mid.x * mid.x + mid.y * mid.y).sqrt();
+
+            // cos of half the angle between the normals
+            let cos_half = (mid.x * o0.x + mid.y * o0.y) / (mid_len * hw);
+            if mid_len == 0.0 || 1.0 / cos_half > MITER_LIMIT {
+                out.extend_from_slice(&[v(p), v(p + o0), v(p + o1)]);
+            } else {
+                let miter = mid * (hw / cos_half / mid_len);
+                out.extend_from_slice(&[v(p), v(p + o0), v(p + miter)]);
+                out.extend_from_slice(&[v(p), v(p + miter), v(p + o1)]);
+            }
+        }
+    }
+}
+
+/// Triangle fan around the centre, starting at offset `from` and turning by `angle` radians
+fn fan(out: &mut Vec<Vertex>, centre: Vector2f, from: Vector2f, angle: f32, colour: Color) {
+    let steps = ((angle.abs() / ROUND_STEP).ceil() as usize).max(1);
+    let step = angle / steps as f32;
+    let v = |p: Vector2f| Vertex::with_pos_color(p, colour);
+
+    let mut prev = from;
+    for i in 1..steps + 1 {
+        let (sin, cos) = (step * i as f32).sin_cos();
+        let next = Vector2f::new(from.x * cos - from.y * sin, from.x * sin + from.y * cos);
+        out.extend_from_slice(&[v(centre), v(centre + prev), v(centre + next)]);
+        prev = next;
+    }
+}
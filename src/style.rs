@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use serde::de::{self, Deserialize, Deserializer};
+use serde_json;
+use sfml::graphics::Color;
+
+use error::*;
+use geometry::Glyph;
+use parser::{BuildingType, LandUseType, RoadType};
+use poi::PoiCategory;
+use stroke::Join;
+use transit::{RailKind, TransitMode};
+
+/// Used if the style file can't be found
+const BUILTIN: &str = include_str!("../res/style.json");
+
+/// Parsed from "#rrggbb" or "#rrggbbaa"
+#[derive(Debug, Clone, Copy)]
+pub struct Colour(pub Color);
+
+/// Zooms a feature is drawn at, read from "hide_above_z", the furthest out it's drawn in world
+/// pixels per screen pixel, see `CameraChange.z`. Drawn at every zoom if that's missing
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct ZoomRange(Option<f64>);
+
+#[derive(Debug, Deserialize)]
+pub struct RoadStyle {
+    pub colour: Colour,
+
+    /// Drawn underneath, a little wider than the road
+    #[serde(default)]
+    pub casing: Option<Colour>,
+
+    /// In metres, unless the road says how many lanes it has
+    pub width: f64,
+
+    /// In screen pixels, so roads are still visible zoomed out
+    #[serde(default = "default_line_width")]
+    pub min_width: f64,
+
+    #[serde(default = "default_join")]
+    pub join: Join,
+
+    /// Higher is drawn on top
+    #[serde(default)]
+    pub z_order: i32,
+
+    #[serde(rename = "hide_above_z", default)]
+    pub zoom_range: ZoomRange,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AreaStyle {
+    pub fill: Colour,
+
+    #[serde(default)]
+    pub outline: Option<Colour>,
+
+    /// In screen pixels
+    #[serde(default = "default_line_width")]
+    pub outline_width: f64,
+
+    #[serde(default)]
+    pub z_order: i32,
+
+    #[serde(rename = "hide_above_z", default)]
+    pub zoom_range: ZoomRange,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PoiStyle {
+    pub colour: Colour,
+
+    #[serde(default = "default_glyph")]
+    pub glyph: Glyph,
+
+    /// Radius in screen pixels, the same at every zoom
+    #[serde(default = "default_glyph_size")]
+    pub size: f64,
+
+    #[serde(rename = "hide_above_z", default)]
+    pub zoom_range: ZoomRange,
+}
+
+/// A transit line drawn over the roads and railways it runs along
+#[derive(Debug, Deserialize)]
+pub struct LineStyle {
+    /// Used if the line has no colour tag of its own
+    pub colour: Colour,
+
+    /// In screen pixels
+    #[serde(default = "default_line_width")]
+    pub width: f64,
+
+    #[serde(rename = "hide_above_z", default)]
+    pub zoom_range: ZoomRange,
+}
+
+#[derive(Debug)]
+pub struct Style {
+    pub background: Colour,
+    roads: HashMap<RoadType, RoadStyle>,
+    default_road: RoadStyle,
+    land_uses: HashMap<LandUseType, AreaStyle>,
+    default_land_use: AreaStyle,
+    buildings: HashMap<BuildingType, AreaStyle>,
+    default_building: AreaStyle,
+    pois: HashMap<PoiCategory, PoiStyle>,
+    default_poi: PoiStyle,
+    railways: HashMap<RailKind, RoadStyle>,
+    default_railway: RoadStyle,
+    transit_lines: HashMap<TransitMode, LineStyle>,
+    default_transit_line: LineStyle,
+}
+
+/// The file as written, with type names not yet checked
+#[derive(Deserialize)]
+struct RawStyle {
+    background: Colour,
+    roads: HashMap<String, RoadStyle>,
+    land_uses: HashMap<String, AreaStyle>,
+    buildings: HashMap<String, AreaStyle>,
+    pois: HashMap<String, PoiStyle>,
+    railways: HashMap<String, RoadStyle>,
+    transit_lines: HashMap<String, LineStyle>,
+}
+
+/// Keeps the style up to date with its file
+pub struct StyleWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    pub style: Style,
+}
+
+fn default_line_width() -> f64 {
+    1.0
+}
+
+fn default_join() -> Join {
+    Join::Round
+}
+
+fn default_glyph() -> Glyph {
+    Glyph::Circle
+}
+
+fn default_glyph_size() -> f64 {
+    4.0
+}
+
+impl<'de> Deserialize<'de> for Colour {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse_colour(&s)
+            .map(Colour)
+            .ok_or_else(|| de::Error::custom(format!("bad colour '{}'", s)))
+    }
+}
+
+impl Style {
+    /// A JSON object of background colour, and road, land use, building, point of interest,
+    /// railway and transit line styles by type name, each with a "default" used for types not
+    /// listed. See res/style.json
+    pub fn load(path: &Path) -> SimResult<Self> {
+        Style::from_raw(serde_json::from_reader(fs::File::open(path)?)?)
+    }
+
+    pub fn builtin() -> Self {
+        Style::from_raw(serde_json::from_str(BUILTIN).expect("Bad builtin style"))
+            .expect("Bad builtin style")
+    }
+
+    fn from_raw(raw: RawStyle) -> SimResult<Self> {
+        let RawStyle { background, mut roads, mut land_uses, mut buildings, mut pois, mut railways, mut transit_lines } = raw;
+
+        let default_road = roads.remove("default")
+            .ok_or_else(|| ErrorKind::BadStyle("missing default road style".to_owned()))?;
+        let default_land_use = land_uses.remove("default")
+            .ok_or_else(|| ErrorKind::BadStyle("missing default land use style".to_owned()))?;
+        let default_building = buildings.remove("default")
+            .ok_or_else(|| ErrorKind::BadStyle("missing default building style".to_owned()))?;
+        let default_poi = pois.remove("default")
+            .ok_or_else(|| ErrorKind::BadStyle("missing default point of interest style".to_owned()))?;
+        let default_railway = railways.remove("default")
+            .ok_or_else(|| ErrorKind::BadStyle("missing default railway style".to_owned()))?;
+        let default_transit_line = transit_lines.remove("default")
+            .ok_or_else(|| ErrorKind::BadStyle("missing default transit line style".to_owned()))?;
+
+        let mut style = Style {
+            background,
+            roads: HashMap::new(),
+            default_road,
+            land_uses: HashMap::new(),
+            default_land_use,
+            buildings: HashMap::new(),
+            default_building,
+            pois: HashMap::new(),
+            default_poi,
+            railways: HashMap::new(),
+            default_railway,
+            transit_lines: HashMap::new(),
+            default_transit_line,
+        };
+
+        for (name, road) in roads {
+            let road_type = road_type_from_name(&name)
+                .ok_or_else(|| ErrorKind::BadStyle(format!("unknown road type '{}'", name)))?;
+            style.roads.insert(road_type, road);
+        }
+
+        for (name, land_use) in land_uses {
+            let land_use_type = land_use_type_from_name(&name)
+                .ok_or_else(|| ErrorKind::BadStyle(format!("unknown land use type '{}'", name)))?;
+            style.land_uses.insert(land_use_type, land_use);
+        }
+
+        for (name, building) in buildings {
+            let building_type = building_type_from_name(&name)
+                .ok_or_else(|| ErrorKind::BadStyle(format!("unknown building type '{}'", name)))?;
+            style.buildings.insert(building_type, building);
+        }
+
+        for (name, poi) in pois {
+            let category = poi_category_from_name(&name)
+                .ok_or_else(|| ErrorKind::BadStyle(format!("unknown point of interest category '{}'", name)))?;
+            style.pois.insert(category, poi);
+        }
+
+        for (name, railway) in railways {
+            let kind = rail_kind_from_name(&name)
+                .ok_or_else(|| ErrorKind::BadStyle(format!("unknown railway type '{}'", name)))?;
+            style.railways.insert(kind, railway);
+        }
+
+        for (name, line) in transit_lines {
+            let mode = transit_mode_from_name(&name)
+                .ok_or_else(|| ErrorKind::BadStyle(format!("unknown transit mode '{}'", name)))?;
+            style.transit_lines.insert(mode, line);
+        }
+
+        Ok(style)
+    }
+
+    /// Link roads not listed are drawn like the road they join
+    pub fn road(&self, road_type: RoadType) -> &RoadStyle {
+        self.roads.get(&road_type)
+            .or_else(|| self.roads.get(&road_type.base()))
+            .unwrap_or(&self.default_road)
+    }
+
+    pub fn land_use(&self, land_use_type: LandUseType) -> &AreaStyle {
+        self.land_uses.get(&land_use_type).unwrap_or(&self.default_land_use)
+    }
+
+    pub fn building(&self, building_type: BuildingType) -> &AreaStyle {
+        self.buildings.get(&building_type).unwrap_or(&self.default_building)
+    }
+
+    pub fn poi(&self, category: PoiCategory) -> &PoiStyle {
+        self.pois.get(&category).unwrap_or(&self.default_poi)
+    }
+
+    pub fn railway(&self, kind: RailKind) -> &RoadStyle {
+        self.railways.get(&kind).unwrap_or(&self.default_railway)
+    }
+
+    pub fn transit_line(&self, mode: TransitMode) -> &LineStyle {
+        self.transit_lines.get(&mode).unwrap_or(&self.default_transit_line)
+    }
+}
+
+impl ZoomRange {
+    pub fn contains(&self, zoom: f64) -> bool {
+        self.0.map(|max| zoom <= max).unwrap_or(true)
+    }
+}
+
+impl StyleWatcher {
+    /// Falls back to the builtin style if the file doesn't exist yet, but not if it's invalid
+    pub fn load(path: PathBuf) -> SimResult<Self> {
+        let modified = modified_time(&path);
+        let style = if path.is_file() {
+            Style::load(&path)?
+        } else {
+            Style::builtin()
+        };
+
+        Ok(StyleWatcher { path, modified, style })
+    }
+
+    /// Reloads the style if its file has changed, returning true if it did. A style that fails
+    /// to load is reported and the current one is kept
+    pub fn poll(&mut self) -> bool {
+        let modified = modified_time(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return false;
+        }
+
+        self.modified = modified;
+        match Style::load(&self.path) {
+            Ok(style) => {
+                info!("Reloaded style from {:?}", self.path);
+                self.style = style;
+                true
+            }
+            Err(e) => {
+                warn!("Failed to reload style: {}", e);
+                false
+            }
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub fn parse_colour(s: &str) -> Option<Color> {
+    if !s.starts_with('#') || !(s.len() == 7 || s.len() == 9) {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(s.get(i..i + 2)?, 16).ok();
+    let alpha = if s.len() == 9 { channel(7)? } else { 255 };
+    Some(Color::rgba(channel(1)?, channel(3)?, channel(5)?, alpha))
+}
+
+fn road_type_from_name(name: &str) -> Option<RoadType> {
+    Some(match name {
+        "unknown" => RoadType::Unknown,
+        "motorway" => RoadType::Motorway,
+        "motorway_link" => RoadType::MotorwayLink,
+        "trunk" => RoadType::Trunk,
+        "trunk_link" => RoadType::TrunkLink,
+        "primary" => RoadType::Primary,
+        "primary_link" => RoadType::PrimaryLink,
+        "secondary" => RoadType::Secondary,
+        "secondary_link" => RoadType::SecondaryLink,
+        "tertiary" => RoadType::Tertiary,
+        "tertiary_link" => RoadType::TertiaryLink,
+        "minor" => RoadType::Minor,
+        "residential" => RoadType::Residential,
+        "living_street" => RoadType::LivingStreet,
+        "service" => RoadType::Service,
+        "track" => RoadType::Track,
+        "pedestrian" => RoadType::Pedestrian,
+        "footway" => RoadType::Footway,
+        "cycleway" => RoadType::Cycleway,
+        "steps" => RoadType::Steps,
+        _ => return None,
+    })
+}
+
+fn land_use_type_from_name(name: &str) -> Option<LandUseType> {
+    Some(match name {
+        "unknown" => LandUseType::Unknown,
+        "residential" => LandUseType::Residential,
+        "commercial" => LandUseType::Commercial,
+        "retail" => LandUseType::Retail,
+        "agriculture" => LandUseType::Agriculture,
+        "industrial" => LandUseType::Industrial,
+        "brownfield" => LandUseType::Brownfield,
+        "military" => LandUseType::Military,
+        "education" => LandUseType::Education,
+        "cemetery" => LandUseType::Cemetery,
+        "railway" => LandUseType::Railway,
+        "green" => LandUseType::Green,
+        "park" => LandUseType::Park,
+        "forest" => LandUseType::Forest,
+        "water" => LandUseType::Water,
+        "wetland" => LandUseType::Wetland,
+        _ => return None,
+    })
+}
+
+fn building_type_from_name(name: &str) -> Option<BuildingType> {
+    Some(match name {
+        "unknown" => BuildingType::Unknown,
+        "residential" => BuildingType::Residential,
+        "commercial" => BuildingType::Commercial,
+        "retail" => BuildingType::Retail,
+        "industrial" => BuildingType::Industrial,
+        "education" => BuildingType::Education,
+        "healthcare" => BuildingType::Healthcare,
+        "religious" => BuildingType::Religious,
+        "civic" => BuildingType::Civic,
+        "transport" => BuildingType::Transport,
+        "agricultural" => BuildingType::Agricultural,
+        "outbuilding" => BuildingType::Outbuilding,
+        _ => return None,
+    })
+}
+
+fn poi_category_from_name(name: &str) -> Option<PoiCategory> {
+    Some(match name {
+        "shop" => PoiCategory::Shop,
+        "food" => PoiCategory::Food,
+        "school" => PoiCategory::School,
+        "hospital" => PoiCategory::Hospital,
+        "bus_stop" => PoiCategory::BusStop,
+        "station" => PoiCategory::Station,
+        "traffic_signals" => PoiCategory::TrafficSignals,
+        "parking" => PoiCategory::Parking,
+        _ => return None,
+    })
+}
+
+fn rail_kind_from_name(name: &str) -> Option<RailKind> {
+    Some(match name {
+        "rail" => RailKind::Rail,
+        "subway" => RailKind::Subway,
+        "tram" => RailKind::Tram,
+        "light_rail" => RailKind::LightRail,
+        _ => return None,
+    })
+}
+
+fn transit_mode_from_name(name: &str) -> Option<TransitMode> {
+    Some(match name {
+        "bus" => TransitMode::Bus,
+        "tram" => TransitMode::Tram,
+        "train" => TransitMode::Train,
+        "subway" => TransitMode::Subway,
+        "light_rail" => TransitMode::LightRail,
+        _ => return None,
+    })
+}
@@ -0,0 +1,166 @@
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use sfml::graphics::Color;
+
+use error::*;
+use geometry;
+use latlon;
+use stroke::Join;
+use style::{AreaStyle, Style};
+use world::{Area, Point, World};
+
+/// Writes all loaded features as SVG, one unit per world pixel, layered and styled the same as
+/// on screen at 1 world pixel per screen pixel
+pub fn export(world: &World, style: &Style, path: &Path) -> SimResult<()> {
+    const ZOOM: f64 = 1.0;
+
+    let mut out = BufWriter::new(fs::File::create(path)?);
+
+    let (min, max) = bounds(world);
+    writeln!(out, r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{} {} {} {}">"#,
+             min.x, min.y, max.x - min.x, max.y - min.y)?;
+    writeln!(out, r#"<rect x="{}" y="{}" width="{}" height="{}" {}/>"#,
+             min.x, min.y, max.x - min.x, max.y - min.y, fill(style.background.0))?;
+
+    let mut land_uses: Vec<_> = world.loaded_land_uses.values()
+        .map(|lu| (lu, style.land_use(lu.land_use_type)))
+        .filter(|&(_, s)| s.zoom_range.contains(ZOOM))
+        .collect();
+    land_uses.sort_by_key(|&(_, s)| s.z_order);
+    for (lu, area_style) in land_uses {
+        write_area(&mut out, lu, area_style, ZOOM)?;
+    }
+
+    for b in world.loaded_buildings.values() {
+        let area_style = style.building(b.building_type);
+        if area_style.zoom_range.contains(ZOOM) {
+            write_area(&mut out, b, area_style, ZOOM)?;
+        }
+    }
+
+    let mut railways: Vec<_> = world.loaded_railways.values()
+        .map(|r| (r, style.railway(r.kind)))
+        .filter(|&(_, s)| s.zoom_range.contains(ZOOM))
+        .collect();
+    railways.sort_by_key(|&(_, s)| s.z_order);
+
+    let pixels_per_metre = latlon::pixels_per_metre(&world.origin);
+    for &(r, railway_style) in &railways {
+        if let (Some(casing), (_, Some(width))) = (railway_style.casing, geometry::line_widths(railway_style.width, railway_style, pixels_per_metre, ZOOM)) {
+            write_line(&mut out, &r.points, casing.0, width, railway_style.join)?;
+        }
+    }
+    for &(r, railway_style) in &railways {
+        let (width, _) = geometry::line_widths(railway_style.width, railway_style, pixels_per_metre, ZOOM);
+        write_line(&mut out, &r.points, railway_style.colour.0, width, railway_style.join)?;
+    }
+
+    let mut roads: Vec<_> = world.loaded_roads.values()
+        .map(|r| (r, style.road(r.road_type)))
+        .filter(|&(_, s)| s.zoom_range.contains(ZOOM))
+        .collect();
+    roads.sort_by_key(|&(_, s)| s.z_order);
+
+    // all casings first, so they never cover another road where they meet
+    for &(r, road_style) in &roads {
+        if let (Some(casing), (_, Some(width))) = (road_style.casing, geometry::road_widths(r, road_style, pixels_per_metre, ZOOM)) {
+            write_line(&mut out, &r.segments, casing.0, width, road_style.join)?;
+        }
+    }
+    for &(r, road_style) in &roads {
+        let (width, _) = geometry::road_widths(r, road_style, pixels_per_metre, ZOOM);
+        write_line(&mut out, &r.segments, road_style.colour.0, width, road_style.join)?;
+    }
+
+    for l in world.loaded_transit_lines.values() {
+        let line_style = style.transit_line(l.mode);
+        if line_style.zoom_range.contains(ZOOM) {
+            let colour = geometry::transit_line_colour(l, line_style);
+            for points in &l.path {
+                write_line(&mut out, points, colour, line_style.width * ZOOM, Join::Round)?;
+            }
+        }
+    }
+
+    for p in world.loaded_pois.values() {
+        let poi_style = style.poi(p.category);
+        if poi_style.zoom_range.contains(ZOOM) {
+            let (x, y) = (f64::from(p.position.x), f64::from(p.position.y));
+            write!(out, r#"<path d=""#)?;
+            for (i, (cx, cy)) in geometry::glyph_outline(poi_style.glyph, x, y, poi_style.size * ZOOM).into_iter().enumerate() {
+                write!(out, "{}{:.1} {:.1} ", if i == 0 { "M" } else { "L" }, cx, cy)?;
+            }
+            writeln!(out, r#"Z" {}/>"#, fill(poi_style.colour.0))?;
+        }
+    }
+
+    writeln!(out, "</svg>")?;
+    Ok(())
+}
+
+fn bounds(world: &World) -> (Point, Point) {
+    let points = world.loaded_roads.values().flat_map(|r| r.segments.iter())
+        .chain(world.loaded_land_uses.values().flat_map(|lu| lu.points.iter()))
+        .chain(world.loaded_buildings.values().flat_map(|b| b.points.iter()))
+        .chain(world.loaded_pois.values().map(|p| &p.position))
+        .chain(world.loaded_railways.values().flat_map(|r| r.points.iter()));
+
+    let mut min = Point { x: ::std::i32::MAX, y: ::std::i32::MAX };
+    let mut max = Point { x: ::std::i32::MIN, y: ::std::i32::MIN };
+    for p in points {
+        min = Point { x: min.x.min(p.x), y: min.y.min(p.y) };
+        max = Point { x: max.x.max(p.x), y: max.y.max(p.y) };
+    }
+
+    if min.x > max.x {
+        // nothing loaded
+        (Point { x: 0, y: 0 }, Point { x: 1, y: 1 })
+    } else {
+        (min, max)
+    }
+}
+
+fn write_area<W: Write, A: Area>(out: &mut W, area: &A, style: &AreaStyle, zoom: f64) -> SimResult<()> {
+    write!(out, r#"<path d=""#)?;
+    let rings = Some(area.outline()).into_iter().chain(area.holes().iter().map(|h| &h[..]));
+    for ring in rings {
+        write_points(out, ring)?;
+        write!(out, "Z")?;
+    }
+
+    write!(out, r#"" fill-rule="evenodd" {}"#, fill(style.fill.0))?;
+    match style.outline {
+        Some(outline) => writeln!(out, r#" {} stroke-width="{}"/>"#, stroke(outline.0), style.outline_width * zoom)?,
+        None => writeln!(out, "/>")?,
+    }
+    Ok(())
+}
+
+fn write_line<W: Write>(out: &mut W, points: &[Point], colour: Color, width: f64, join: Join) -> SimResult<()> {
+    let (join, cap) = match join {
+        Join::Miter => ("miter", "butt"),
+        Join::Round => ("round", "round"),
+    };
+
+    write!(out, r#"<path d=""#)?;
+    write_points(out, points)?;
+    writeln!(out, r#"" fill="none" {} stroke-width="{}" stroke-linejoin="{}" stroke-linecap="{}"/>"#,
+             stroke(colour), width, join, cap)?;
+    Ok(())
+}
+
+fn write_points<W: Write>(out: &mut W, points: &[Point]) -> SimResult<()> {
+    for (i, p) in points.iter().enumerate() {
+        write!(out, "{}{} {} ", if i == 0 { "M" } else { "L" }, p.x, p.y)?;
+    }
+    Ok(())
+}
+
+fn fill(c: Color) -> String {
+    format!(r##"fill="#{:02x}{:02x}{:02x}" fill-opacity="{:.3}""##, c.r, c.g, c.b, f64::from(c.a) / 255.0)
+}
+
+fn stroke(c: Color) -> String {
+    format!(r##"stroke="#{:02x}{:02x}{:02x}" stroke-opacity="{:.3}""##, c.r, c.g, c.b, f64::from(c.a) / 255.0)
+}
@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Keys with only a handful of values in practice, whose values are interned. Others such as names,
+/// addresses and refs are unique to nearly every feature, so would grow the interner forever
+const INTERNED_VALUE_KEYS: &[&str] = &[
+    "amenity",
+    "building",
+    "bus",
+    "highway",
+    "landuse",
+    "leisure",
+    "natural",
+    "oneway",
+    "public_transport",
+    "railway",
+    "route",
+    "shop",
+    "type",
+];
+
+lazy_static! {
+    /// Every distinct key and common value seen, never freed as the same few are seen over and
+    /// over
+    static ref INTERNED: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+}
+
+/// Shares a single copy of each distinct string. Only for strings from a small set, as they're
+/// never freed
+pub fn intern(s: &str) -> &'static str {
+    let mut interned = INTERNED.lock().unwrap();
+    if let Some(&existing) = interned.get(s) {
+        return existing;
+    }
+
+    let leaked: &'static str = Box::leak(s.to_owned().into_boxed_str());
+    interned.insert(leaked);
+    leaked
+}
+
+/// OSM tags of a feature, sorted by key
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Tags(Vec<(&'static str, Value)>);
+
+#[derive(Clone, Eq)]
+enum Value {
+    Interned(&'static str),
+    Owned(Box<str>),
+}
+
+impl Value {
+    fn new(key: &str, value: &str) -> Self {
+        if value == "yes" || value == "no" || INTERNED_VALUE_KEYS.contains(&key) {
+            Value::Interned(intern(value))
+        } else {
+            Value::Owned(value.into())
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match *self {
+            Value::Interned(v) => v,
+            Value::Owned(ref v) => v,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Tags {
+    pub fn new() -> Self {
+        Tags(Vec::new())
+    }
+
+    /// Replaces any existing value
+    pub fn insert(&mut self, key: &str, value: &str) {
+        let value = Value::new(key, value);
+        match self.0.binary_search_by_key(&key, |&(k, _)| k) {
+            Ok(i) => self.0[i].1 = value,
+            Err(i) => self.0.insert(i, (intern(key), value)),
+        }
+    }
+
+    pub fn has(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.binary_search_by_key(&key, |&(k, _)| k)
+            .ok()
+            .map(|i| self.0[i].1.as_str())
+    }
+
+    /// True if any tag's key and value match the glob patterns, where `*` matches any run of
+    /// characters and `?` any single one, e.g. ("amenity", "*school")
+    pub fn matches(&self, key_pattern: &str, value_pattern: &str) -> bool {
+        self.iter().any(|(k, v)| glob(key_pattern, k) && glob(value_pattern, v))
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (&'static str, &'a str)> + 'a {
+        self.0.iter().map(|&(k, ref v)| (k, v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+pub fn glob(pattern: &str, s: &str) -> bool {
+    let (p, s): (Vec<char>, Vec<char>) = (pattern.chars().collect(), s.chars().collect());
+
+    // position after the last star in the pattern, and where in s it started matching from
+    let mut star: Option<(usize, usize)> = None;
+    let (mut pi, mut si) = (0, 0);
+
+    while si < s.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi + 1, si));
+            pi += 1;
+        } else if let Some((after_star, matched_from)) = star {
+            // let the star swallow one more character
+            pi = after_star;
+            si = matched_from + 1;
+            star = Some((after_star, si));
+        } else {
+            return false;
+        }
+    }
+
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+impl fmt::Debug for Tags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// As a plain map of strings, so the chunk cache doesn't depend on the interner
+impl Serialize for Tags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for Tags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let map: BTreeMap<String, String> = BTreeMap::deserialize(deserializer)?;
+        Ok(Tags(map.iter().map(|(k, v)| (intern(k), Value::new(k, v))).collect()))
+    }
+}
@@ -0,0 +1,197 @@
+use world::{Id, Point, TransitLine};
+use tags::Tags;
+
+/// Seconds since midnight of the simulated day
+pub type SimTime = f64;
+
+/// Seconds a vehicle waits at each stop
+const DWELL: SimTime = 20.0;
+
+/// Lines without their own hours run from 05:00 until midnight
+const FIRST_DEPARTURE: SimTime = 5.0 * 3600.0;
+const LAST_DEPARTURE: SimTime = 24.0 * 3600.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RailKind {
+    Rail,
+    Subway,
+    Tram,
+    LightRail,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TransitMode {
+    Bus,
+    Tram,
+    Train,
+    Subway,
+    LightRail,
+}
+
+/// Where a line stops, in the order it stops there
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransitStop {
+    pub id: Id,
+    pub position: Point,
+
+    /// Empty if it has no name
+    pub name: String,
+}
+
+/// A line run at a fixed interval through the day, calling at each of its stops in order
+#[derive(Debug, Clone)]
+pub struct Service {
+    pub line: Id,
+    pub headway: SimTime,
+    pub first_departure: SimTime,
+    pub last_departure: SimTime,
+
+    /// Time after leaving the first stop that each stop is reached
+    offsets: Vec<SimTime>,
+}
+
+impl RailKind {
+    pub fn from_tags(tags: &Tags) -> Option<Self> {
+        Some(match tags.get("railway")? {
+            "rail" => RailKind::Rail,
+            "subway" => RailKind::Subway,
+            "tram" => RailKind::Tram,
+            "light_rail" => RailKind::LightRail,
+            _ => return None,
+        })
+    }
+}
+
+impl TransitMode {
+    pub fn from_tags(tags: &Tags) -> Option<Self> {
+        Some(match tags.get("route")? {
+            "bus" | "trolleybus" => TransitMode::Bus,
+            "tram" => TransitMode::Tram,
+            "train" => TransitMode::Train,
+            "subway" => TransitMode::Subway,
+            "light_rail" => TransitMode::LightRail,
+            _ => return None,
+        })
+    }
+
+    /// Average between stops including speeding up and slowing down, in metres per second
+    fn speed(self) -> f64 {
+        match self {
+            TransitMode::Bus => 5.5,
+            TransitMode::Tram => 6.0,
+            TransitMode::LightRail | TransitMode::Subway => 9.0,
+            TransitMode::Train => 14.0,
+        }
+    }
+
+    /// Used if the line has no interval tag
+    fn default_headway(self) -> SimTime {
+        match self {
+            TransitMode::Bus => 600.0,
+            TransitMode::Tram | TransitMode::LightRail => 480.0,
+            TransitMode::Subway => 300.0,
+            TransitMode::Train => 1800.0,
+        }
+    }
+}
+
+/// "mm", "hh:mm" or "hh:mm:ss", as used by the interval tag
+fn parse_interval(s: &str) -> Option<SimTime> {
+    let parts = s.trim().split(':')
+        .map(|p| p.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>()?;
+
+    let seconds = match parts[..] {
+        [m] => m * 60.0,
+        [h, m] => h * 3600.0 + m * 60.0,
+        [h, m, s] => h * 3600.0 + m * 60.0 + s,
+        _ => return None,
+    };
+
+    if seconds > 0.0 { Some(seconds) } else { None }
+}
+
+impl Service {
+    /// Travel times between stops are estimated from the straight line distance between them
+    pub fn for_line(id: Id, line: &TransitLine, pixels_per_metre: f64) -> Self {
+        let speed = line.mode.speed() * pixels_per_metre;
+        let mut offsets = Vec::with_capacity(line.stops.len());
+        let mut t = 0.0;
+
+        for (i, stop) in line.stops.iter().enumerate() {
+            if i > 0 {
+                let prev = &line.stops[i - 1].position;
+                let (dx, dy) = (f64::from(stop.position.x - prev.x), f64::from(stop.position.y - prev.y));
+                t += DWELL + (dx * dx + dy * dy).sqrt() / speed;
+            }
+            offsets.push(t);
+        }
+
+        Service {
+            line: id,
+            headway: line.tags.get("interval").and_then(parse_interval).unwrap_or_else(|| line.mode.default_headway()),
+            first_departure: FIRST_DEPARTURE,
+            last_departure: LAST_DEPARTURE,
+            offsets,
+        }
+    }
+
+    /// When the next vehicle reaches the stop at or after `after`, or None if no more run today
+    pub fn next_arrival(&self, stop: usize, after: SimTime) -> Option<SimTime> {
+        let offset = *self.offsets.get(stop)?;
+        let runs = ((after - offset - self.first_departure) / self.headway).ceil().max(0.0);
+        let departure = self.first_departure + runs * self.headway;
+
+        if departure > self.last_departure {
+            None
+        } else {
+            Some(departure + offset)
+        }
+    }
+
+    /// Boarding at stop `from` no earlier than `after`, when the vehicle leaves it and reaches
+    /// stop `to`. None if `to` isn't after `from` on the line or no more vehicles run today
+    pub fn ride(&self, from: usize, to: usize, after: SimTime) -> Option<(SimTime, SimTime)> {
+        if to <= from || to >= self.offsets.len() {
+            return None;
+        }
+
+        let arrival = self.next_arrival(from, after)?;
+        Some((arrival + DWELL, arrival + self.offsets[to] - self.offsets[from]))
+    }
+
+    /// Positions of the vehicles running at the time, given the line's stops
+    pub fn vehicles_at(&self, time: SimTime, stops: &[TransitStop]) -> Vec<Point> {
+        let duration = match self.offsets.last() {
+            Some(&d) if stops.len() == self.offsets.len() => d,
+            _ => return Vec::new(),
+        };
+
+        let first_run = ((time - duration - self.first_departure) / self.headway).ceil().max(0.0) as u32;
+        let mut vehicles = Vec::new();
+
+        for run in first_run.. {
+            let departure = self.first_departure + f64::from(run) * self.headway;
+            if departure > time || departure > self.last_departure {
+                break;
+            }
+
+            let elapsed = time - departure;
+            let next = match self.offsets.iter().position(|&o| o > elapsed) {
+                Some(next) => next,
+                None => continue,
+            };
+
+            // waiting at the previous stop, or part way to the next
+            let (from, to) = (&stops[next - 1].position, &stops[next].position);
+            let leaves = self.offsets[next - 1] + DWELL;
+            let progress = ((elapsed - leaves) / (self.offsets[next] - leaves)).max(0.0);
+            vehicles.push(Point {
+                x: from.x + (f64::from(to.x - from.x) * progress).round() as i32,
+                y: from.y + (f64::from(to.y - from.y) * progress).round() as i32,
+            });
+        }
+
+        vehicles
+    }
+}
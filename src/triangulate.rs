@@ -0,0 +1,237 @@
+//! Ear clipping triangulation of simple polygons, with holes bridged into the outer ring first.
+//! Returns triangles as triples of indices into the outer ring followed by each of the holes, in
+//! the order they were given.
+
+use world::Point;
+
+#[derive(Debug, Clone, Copy)]
+struct Vertex {
+    x: f64,
+    y: f64,
+
+    /// Into the concatenation of the input rings
+    index: u32,
+}
+
+pub fn triangulate(outer: &[Point], holes: &[Vec<Point>]) -> Vec<u32> {
+    let mut ring = to_ring(outer, 0);
+    if ring.len() < 3 {
+        return Vec::new();
+    }
+
+    // counter-clockwise outer, clockwise holes
+    if signed_area(&ring) < 0.0 {
+        ring.reverse();
+    }
+
+    let mut offset = outer.len() as u32;
+    let mut hole_rings: Vec<Vec<Vertex>> = holes.iter()
+        .map(|h| {
+            let r = to_ring(h, offset);
+            offset += h.len() as u32;
+            r
+        })
+        .filter(|r| r.len() >= 3)
+        .map(|mut r| {
+            if signed_area(&r) > 0.0 {
+                r.reverse();
+            }
+            r
+        })
+        .collect();
+
+    // bridge the rightmost holes first, so later bridges can't cross earlier ones
+    hole_rings.sort_by(|a, b| max_x(b).partial_cmp(&max_x(a)).unwrap());
+    for hole in &hole_rings {
+        bridge_hole(&mut ring, hole);
+    }
+
+    clip_ears(ring)
+}
+
+/// Drops the closing point if the ring repeats its first point at the end
+fn to_ring(points: &[Point], offset: u32) -> Vec<Vertex> {
+    let mut len = points.len();
+    if len > 1 && points[0].x == points[len - 1].x && points[0].y == points[len - 1].y {
+        len -= 1;
+    }
+
+    points[..len].iter()
+        .enumerate()
+        .map(|(i, p)| Vertex {
+            x: f64::from(p.x),
+            y: f64::from(p.y),
+            index: offset + i as u32,
+        })
+        .collect()
+}
+
+fn signed_area(ring: &[Vertex]) -> f64 {
+    let mut sum = 0.0;
+    let mut j = ring.len() - 1;
+    for i in 0..ring.len() {
+        sum += (ring[j].x - ring[i].x) * (ring[j].y + ring[i].y);
+        j = i;
+    }
+    sum / 2.0
+}
+
+fn max_x(ring: &[Vertex]) -> f64 {
+    ring.iter().map(|v| v.x).fold(::std::f64::NEG_INFINITY, f64::max)
+}
+
+/// Twice the signed area of the triangle, positive if counter-clockwise
+fn cross(a: &Vertex, b: &Vertex, c: &Vertex) -> f64 {
+    (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
+
+fn same_pos(a: &Vertex, b: &Vertex) -> bool {
+    a.x == b.x && a.y == b.y
+}
+
+fn in_triangle(p: &Vertex, a: &Vertex, b: &Vertex, c: &Vertex) -> bool {
+    cross(a, b, p) >= 0.0 && cross(b, c, p) >= 0.0 && cross(c, a, p) >= 0.0
+}
+
+/// Joins the hole to the ring with a pair of coincident edges from the hole's rightmost vertex to
+/// a vertex on the ring that it can see
+fn bridge_hole(ring: &mut Vec<Vertex>, hole: &[Vertex]) {
+    let (m_idx, m) = hole.iter()
+        .enumerate()
+        .max_by(|a, b| a.1.x.partial_cmp(&b.1.x).unwrap())
+        .map(|(i, v)| (i, *v))
+        .unwrap();
+
+    // cast a ray from m to the right, and find the closest edge it hits
+    let mut hit: Option<(f64, usize)> = None;
+    let n = ring.len();
+    for i in 0..n {
+        let (a, b) = (&ring[i], &ring[(i + 1) % n]);
+        if (a.y > m.y) == (b.y > m.y) {
+            continue;
+        }
+
+        let x = a.x + (m.y - a.y) * (b.x - a.x) / (b.y - a.y);
+        if x >= m.x && hit.map(|(hx, _)| x < hx).unwrap_or(true) {
+            // the candidate is the endpoint of the edge furthest to the right
+            let candidate = if a.x > b.x { i } else { (i + 1) % n };
+            hit = Some((x, candidate));
+        }
+    }
+
+    let (hit_x, mut p_idx) = match hit {
+        Some(h) => h,
+        // the hole isn't inside the ring, ignore it
+        None => return,
+    };
+
+    // if any reflex vertices are inside the triangle between m, the hit point and the candidate,
+    // the one making the smallest angle with the ray is visible instead
+    {
+        let i = Vertex { x: hit_x, y: m.y, index: 0 };
+        let p = ring[p_idx];
+        let (a, c) = if p.y < m.y { (i, p) } else { (p, i) };
+        let mut best_angle = ::std::f64::INFINITY;
+
+        for j in 0..n {
+            let v = &ring[j];
+            let prev = &ring[(j + n - 1) % n];
+            let next = &ring[(j + 1) % n];
+            let reflex = cross(prev, v, next) < 0.0;
+
+            if j == p_idx || !reflex || !in_triangle(v, &m, &a, &c) && !in_triangle(v, &m, &c, &a) {
+                continue;
+            }
+
+            let angle = (v.y - m.y).abs().atan2(v.x - m.x);
+            if angle < best_angle {
+                best_angle = angle;
+                p_idx = j;
+            }
+        }
+    }
+
+    // ring: ..., p, m, hole after m..., hole up to m, m, p, ...
+    let p = ring[p_idx];
+    let mut bridged = Vec::with_capacity(ring.len() + hole.len() + 2);
+    bridged.extend_from_slice(&ring[..p_idx + 1]);
+    for k in 0..hole.len() + 1 {
+        bridged.push(hole[(m_idx + k) % hole.len()]);
+    }
+    bridged.push(p);
+    bridged.extend_from_slice(&ring[p_idx + 1..]);
+    *ring = bridged;
+}
+
+fn clip_ears(mut ring: Vec<Vertex>) -> Vec<u32> {
+    let mut triangles = Vec::with_capacity((ring.len() - 2) * 3);
+
+    let mut i = 0;
+    let mut since_last_ear = 0;
+    while ring.len() > 3 {
+        let n = ring.len();
+        let (prev, cur, next) = ((i + n - 1) % n, i % n, (i + 1) % n);
+
+        if is_ear(&ring, prev, cur, next) || since_last_ear > n {
+            // since_last_ear: self-intersecting or otherwise degenerate, so clip anyway rather
+            // than loop forever
+            triangles.extend_from_slice(&[ring[prev].index, ring[cur].index, ring[next].index]);
+            ring.remove(cur);
+            since_last_ear = 0;
+            i = if cur == 0 { 0 } else { cur - 1 };
+        } else {
+            i = next;
+            since_last_ear += 1;
+        }
+    }
+
+    triangles.extend_from_slice(&[ring[0].index, ring[1].index, ring[2].index]);
+    triangles
+}
+
+fn is_ear(ring: &[Vertex], prev: usize, cur: usize, next: usize) -> bool {
+    let (a, b, c) = (&ring[prev], &ring[cur], &ring[next]);
+
+    // reflex or collinear
+    if cross(a, b, c) <= 0.0 {
+        return false;
+    }
+
+    // no other vertex may be inside, ignoring the duplicates introduced by bridging
+    !ring.iter()
+        .enumerate()
+        .any(|(j, v)| {
+            j != prev && j != cur && j != next &&
+                !same_pos(v, a) && !same_pos(v, b) && !same_pos(v, c) &&
+                in_triangle(v, a, b, c)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(x: i32, y: i32, size: i32) -> Vec<Point> {
+        vec![
+            Point { x, y },
+            Point { x: x + size, y },
+            Point { x: x + size, y: y + size },
+            Point { x, y: y + size },
+        ]
+    }
+
+    #[test]
+    fn empty_holes_ignored() {
+        let triangles = triangulate(&square(0, 0, 10), &[Vec::new(), vec![Point { x: 1, y: 1 }]]);
+        assert_eq!(triangles.len(), 2 * 3);
+    }
+
+    #[test]
+    fn hole_bridged() {
+        let triangles = triangulate(&square(0, 0, 10), &[square(3, 3, 4)]);
+
+        // 8 vertices and 2 bridge duplicates make a ring of 10, clipped into 8 triangles
+        assert_eq!(triangles.len(), 8 * 3);
+        assert!(triangles.iter().all(|&i| i < 8));
+    }
+}
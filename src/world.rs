@@ -2,17 +2,36 @@ use error::*;
 use std::io::{self, Read, Write};
 use std::{fs, env};
 use std::collections::{HashMap, HashSet};
-use std::sync::mpsc;
-use std::thread;
-use std_semaphore::Semaphore;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::mem::size_of;
+use std::time::SystemTime;
+use std_semaphore::{Semaphore, SemaphoreGuard};
 use std::path::{PathBuf, Path};
 use serde_json;
 
 use chunk_req;
 use parser;
 use latlon;
+use triangulate;
+use classify;
+use loader::{ChunkLoader, Completion, LoadRequest};
+use logging::Span;
+use tags::Tags;
+use poi::{PoiCategory, PoiIndex};
+use transit::{RailKind, Service, TransitMode, TransitStop};
 
 const CONCURRENT_REQ_COUNT: isize = 3;
+const LOADER_THREADS: usize = 4;
+
+/// Chunks loaded from their serialized file, from cached OSM and from the network
+static CHUNK_CACHE_HITS: AtomicUsize = ATOMIC_USIZE_INIT;
+static OSM_CACHE_HITS: AtomicUsize = ATOMIC_USIZE_INIT;
+static CACHE_MISSES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Requests holding a `REQUEST_SEM` slot
+static IN_FLIGHT: AtomicUsize = ATOMIC_USIZE_INIT;
+
 lazy_static! {
     static ref REQUEST_SEM: Semaphore = Semaphore::new(CONCURRENT_REQ_COUNT);
     static ref WORLD_DIR: PathBuf = {
@@ -31,6 +50,17 @@ pub struct Point {
 
 pub type Id = i64;
 
+/// Reference to a loaded road, land use, building, point of interest, railway or transit line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    Road(Id),
+    LandUse(Id),
+    Building(Id),
+    PointOfInterest(Id),
+    Railway(Id),
+    TransitLine(Id),
+}
+
 #[derive(Debug, Clone)]
 pub struct LatLon {
     pub lat: f64,
@@ -41,22 +71,123 @@ pub trait PointsHolder {
     fn pixels(&mut self) -> &mut Vec<Point>;
 }
 
+/// Polygon that is filled as triangles, which index into the outer points followed by each hole
+pub trait Area {
+    fn outline(&self) -> &[Point];
+    fn holes(&self) -> &[Vec<Point>];
+    fn triangles(&self) -> &[u32];
+
+    /// Triangulates the polygon if it hasn't been already
+    fn triangulate(&mut self);
+
+    /// Iterates the points of the triangles, 3 at a time
+    fn triangle_points<'a>(&'a self) -> Box<Iterator<Item = &'a Point> + 'a> {
+        let outline = self.outline();
+        let holes = self.holes();
+        Box::new(self.triangles().iter().map(move |&i| {
+            let mut i = i as usize;
+            if i < outline.len() {
+                return &outline[i];
+            }
+
+            i -= outline.len();
+            for h in holes {
+                if i < h.len() {
+                    return &h[i];
+                }
+                i -= h.len();
+            }
+            unreachable!("bad triangle index")
+        }))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Road {
     pub road_type: parser::RoadType,
     pub segments: Vec<Point>,
-    pub name: String
+    pub name: String,
+
+    /// Not known for every road, so the width falls back to the road type
+    #[serde(default)]
+    pub lanes: Option<u8>,
+
+    #[serde(default)]
+    pub tags: Tags,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LandUse {
     pub land_use_type: parser::LandUseType,
     pub points: Vec<Point>,
+
+    /// Inner rings of multipolygons
+    #[serde(default)]
+    pub holes: Vec<Vec<Point>>,
+
+    /// Filled on load, see `triangulate`
+    #[serde(default)]
+    pub triangles: Vec<u32>,
+
+    #[serde(default)]
+    pub tags: Tags,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Building {
+    /// Unknown if cached before buildings were classified
+    #[serde(default)]
+    pub building_type: parser::BuildingType,
+    pub points: Vec<Point>,
+
+    #[serde(default)]
+    pub holes: Vec<Vec<Point>>,
+
+    #[serde(default)]
+    pub triangles: Vec<u32>,
+
+    #[serde(default)]
+    pub tags: Tags,
+}
+
+/// A tagged node, such as a shop or a bus stop
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PointOfInterest {
+    pub category: PoiCategory,
+    pub position: Point,
+
+    /// Empty if it has no name
+    pub name: String,
+
+    #[serde(default)]
+    pub tags: Tags,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Railway {
+    pub kind: RailKind,
     pub points: Vec<Point>,
+    pub name: String,
+
+    #[serde(default)]
+    pub tags: Tags,
+}
+
+/// A bus, tram or train route through its stops
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransitLine {
+    pub mode: TransitMode,
+
+    /// What's shown on the vehicles, e.g. "N8", empty if it has none
+    pub reference: String,
+    pub name: String,
+    pub stops: Vec<TransitStop>,
+
+    /// Ways it runs along, not necessarily in order
+    pub path: Vec<Vec<Point>>,
+
+    #[serde(default)]
+    pub tags: Tags,
 }
 
 type IdCountMap = HashMap<Id, u16>;
@@ -65,26 +196,131 @@ pub struct World {
     pub origin: LatLon,
     name: String,
 
+    /// Where chunks and OSM are cached
+    save_dir: PathBuf,
+
     // id -> count
     road_refs: IdCountMap,
     land_use_refs: IdCountMap,
+    building_refs: IdCountMap,
+    poi_refs: IdCountMap,
+    railway_refs: IdCountMap,
+    transit_line_refs: IdCountMap,
 
     // TODO use quadtree?
-    pub loaded_roads: Vec<Road>,
-    pub loaded_land_uses: Vec<LandUse>,
+    pub loaded_roads: HashMap<Id, Road>,
+    pub loaded_land_uses: HashMap<Id, LandUse>,
+    pub loaded_buildings: HashMap<Id, Building>,
+    pub loaded_pois: HashMap<Id, PointOfInterest>,
+    pub loaded_railways: HashMap<Id, Railway>,
+    pub loaded_transit_lines: HashMap<Id, TransitLine>,
+    poi_index: PoiIndex,
 
     loaded_chunks: HashMap<(i32, i32), Chunk>,
     loading_chunks: HashSet<(i32, i32)>,
+
+    /// Chunks sent off to be fetched into the disk cache, so they're not sent again until they've
+    /// finished. See `finish_prefetches`
+    prefetching_chunks: HashSet<(i32, i32)>,
+    prefetch_channel: (mpsc::Sender<PrefetchedChunk>, mpsc::Receiver<PrefetchedChunk>),
+
+    /// Most recent failure of chunks that haven't loaded since
+    failed_chunks: HashMap<(i32, i32), FailedChunk>,
+
+    loader: ChunkLoader,
+    client: Arc<chunk_req::Client>,
+    features: chunk_req::FeatureFilter,
+    parse_mode: parser::ParseMode,
 }
 
 #[derive(Debug)]
 pub struct Chunk {
     road_refs: Vec<Id>,
     land_use_refs: Vec<Id>,
+    building_refs: Vec<Id>,
+    poi_refs: Vec<Id>,
+    railway_refs: Vec<Id>,
+    transit_line_refs: Vec<Id>,
+
+    /// Features this chunk draws, so features shared with neighbours are only drawn once
+    owned: Vec<Feature>,
 }
 
 pub struct PartialChunk(pub SimResult<parser::PartialWorld>, pub (i32, i32));
 
+/// A prefetch that finished, with why it failed if it did
+pub struct PrefetchedChunk(pub Result<(), String>, pub (i32, i32));
+
+#[derive(Debug)]
+pub struct FailedChunk {
+    pub error: Error,
+    pub when: SystemTime,
+
+    /// Failed loads in a row
+    pub attempts: u32,
+}
+
+/// Snapshot of what's loaded and loading, for debugging
+#[derive(Debug, Default)]
+pub struct WorldStats {
+    pub roads: usize,
+    pub land_uses: usize,
+    pub buildings: usize,
+    pub pois: usize,
+    pub railways: usize,
+    pub transit_lines: usize,
+    pub chunks: usize,
+    pub loading: usize,
+    pub prefetching: usize,
+    pub failed: usize,
+    pub queued: usize,
+    pub in_flight: usize,
+    pub chunk_cache_hits: usize,
+    pub osm_cache_hits: usize,
+    pub cache_misses: usize,
+
+    /// Rough estimate of the heap used by loaded features, in bytes
+    pub memory: usize,
+}
+
+/// Counts itself in `IN_FLIGHT` while it holds the request slot
+struct RequestSlot<'a> {
+    _guard: SemaphoreGuard<'a>,
+}
+
+impl<'a> RequestSlot<'a> {
+    fn acquire(sem: &'a Semaphore) -> Self {
+        let guard = sem.access();
+        IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        RequestSlot { _guard: guard }
+    }
+}
+
+impl<'a> Drop for RequestSlot<'a> {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Chunk {
+    fn references(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::Road(id) => self.road_refs.contains(&id),
+            Feature::LandUse(id) => self.land_use_refs.contains(&id),
+            Feature::Building(id) => self.building_refs.contains(&id),
+            Feature::PointOfInterest(id) => self.poi_refs.contains(&id),
+            Feature::Railway(id) => self.railway_refs.contains(&id),
+            Feature::TransitLine(id) => self.transit_line_refs.contains(&id),
+        }
+    }
+}
+
+impl PointsHolder for Railway {
+    fn pixels(&mut self) -> &mut Vec<Point> {
+        &mut self.points
+    }
+}
+
 impl PointsHolder for Road {
     fn pixels(&mut self) -> &mut Vec<Point> {
         &mut self.segments
@@ -103,6 +339,46 @@ impl PointsHolder for Building {
     }
 }
 
+impl Area for LandUse {
+    fn outline(&self) -> &[Point] {
+        &self.points
+    }
+
+    fn holes(&self) -> &[Vec<Point>] {
+        &self.holes
+    }
+
+    fn triangles(&self) -> &[u32] {
+        &self.triangles
+    }
+
+    fn triangulate(&mut self) {
+        if self.triangles.is_empty() {
+            self.triangles = triangulate::triangulate(&self.points, &self.holes);
+        }
+    }
+}
+
+impl Area for Building {
+    fn outline(&self) -> &[Point] {
+        &self.points
+    }
+
+    fn holes(&self) -> &[Vec<Point>] {
+        &self.holes
+    }
+
+    fn triangles(&self) -> &[u32] {
+        &self.triangles
+    }
+
+    fn triangulate(&mut self) {
+        if self.triangles.is_empty() {
+            self.triangles = triangulate::triangulate(&self.points, &self.holes);
+        }
+    }
+}
+
 impl LatLon {
     pub fn new(lat: f64, lon: f64) -> LatLon {
         LatLon {
@@ -113,19 +389,59 @@ impl LatLon {
 }
 
 impl World {
-    pub fn new(name: String, origin: LatLon) -> World {
+    pub fn new(name: String, origin: LatLon, client: chunk_req::Client) -> World {
+        let save_dir = {
+            let mut p = WORLD_DIR.clone();
+            p.push(&name);
+            p
+        };
+
         World {
             origin,
             name,
+            save_dir,
             road_refs: HashMap::new(),
             land_use_refs: HashMap::new(),
-            loaded_roads: Vec::new(),
-            loaded_land_uses: Vec::new(),
+            building_refs: HashMap::new(),
+            poi_refs: HashMap::new(),
+            railway_refs: HashMap::new(),
+            transit_line_refs: HashMap::new(),
+            loaded_roads: HashMap::new(),
+            loaded_land_uses: HashMap::new(),
+            loaded_buildings: HashMap::new(),
+            loaded_pois: HashMap::new(),
+            loaded_railways: HashMap::new(),
+            loaded_transit_lines: HashMap::new(),
+            poi_index: PoiIndex::default(),
             loaded_chunks: HashMap::new(),
             loading_chunks: HashSet::new(),
+            prefetching_chunks: HashSet::new(),
+            prefetch_channel: mpsc::channel(),
+            failed_chunks: HashMap::new(),
+            loader: ChunkLoader::new(LOADER_THREADS),
+            client: Arc::new(client),
+            features: chunk_req::FeatureFilter::default(),
+            parse_mode: parser::ParseMode::Strict,
         }
     }
 
+    /// Only affects chunks that aren't already cached on disk
+    pub fn set_parse_mode(&mut self, mode: parser::ParseMode) {
+        self.parse_mode = mode;
+    }
+
+    /// Caches chunks somewhere other than the world's directory in the temp dir. Only affects
+    /// chunks that aren't already loaded
+    pub fn set_save_dir(&mut self, dir: PathBuf) {
+        self.save_dir = dir;
+    }
+
+    /// Only affects chunks that aren't already loaded. Each filter has its own disk caches, so
+    /// switching back to an earlier one reuses what it cached
+    pub fn set_feature_filter(&mut self, features: chunk_req::FeatureFilter) {
+        self.features = features;
+    }
+
     pub fn request_chunk_async(
         &mut self,
         x: i32,
@@ -141,30 +457,118 @@ impl World {
             self.loading_chunks.insert(coord);
         }
 
-        let dir = self.get_save_dir();
-        thread::spawn(move || {
-            let res = if loaded_already {
-                Err(ErrorKind::ChunkAlreadyLoaded(coord).into())
-            } else {
-                attempt_load(dir, coord, &bounds)
-            };
-            result_channel.send(PartialChunk(res, coord));
-        });
+        // a queued or running prefetch is upgraded to a visible request
+        self.prefetching_chunks.remove(&coord);
+
+        if loaded_already {
+            let _ = result_channel.send(PartialChunk(Err(ErrorKind::ChunkAlreadyLoaded(coord).into()), coord));
+            return;
+        }
+
+        let request = LoadRequest {
+            coord,
+            bounds,
+            world_dir: self.get_save_dir(),
+            client: self.client.clone(),
+            features: self.features.clone(),
+            parse_mode: self.parse_mode,
+            completion: Completion::Load(result_channel),
+        };
 
+        if let Err(request) = self.loader.submit(request) {
+            if let Completion::Load(channel) = request.completion {
+                let _ = channel.send(PartialChunk(Err(ErrorKind::ChunkAlreadyLoaded(coord).into()), coord));
+            }
+        }
     }
 
-    pub fn finish_chunk_request(&mut self, partial_chunk: PartialChunk) {
+    /// Queues the chunk to be fetched and cached on disk in the background, after all visible
+    /// requests, without loading it into the world
+    pub fn prefetch_chunk(&mut self, x: i32, y: i32) {
+        let coord = (x, y);
+        if self.loaded_chunks.contains_key(&coord) ||
+            self.loading_chunks.contains(&coord) ||
+            self.prefetching_chunks.contains(&coord) {
+            return;
+        }
+
+        let world_dir = self.get_save_dir();
+        if get_chunk_path(&world_dir, coord, &self.features).is_file() {
+            return;
+        }
+
+        let request = LoadRequest {
+            coord,
+            bounds: latlon::get_chunk_bounds(&self.origin, coord),
+            world_dir,
+            client: self.client.clone(),
+            features: self.features.clone(),
+            parse_mode: self.parse_mode,
+            completion: Completion::Prefetch(self.prefetch_channel.0.clone()),
+        };
 
-        fn inc_refs<T>(chunk_refs: &[Id], world_refs: &mut IdCountMap, chunk_objs: &mut HashMap<Id, T>, world_objs: &mut Vec<T>, que: &str) {
+        if self.loader.submit(request).is_ok() {
+            self.prefetching_chunks.insert(coord);
+        }
+    }
+
+    /// Forgets prefetches that have finished, so failed ones can be tried again. Should be called
+    /// regularly, like `finish_chunk_request`
+    pub fn finish_prefetches(&mut self) {
+        while let Ok(PrefetchedChunk(res, coord)) = self.prefetch_channel.1.try_recv() {
+            self.prefetching_chunks.remove(&coord);
+            if let Err(reason) = res {
+                warn!("Failed to prefetch chunk {:?}: {}", coord, reason);
+            }
+        }
+    }
+
+    /// Drops queued prefetches that are no longer wanted, e.g. because the camera has moved on
+    pub fn cancel_prefetches<F: Fn((i32, i32)) -> bool>(&mut self, keep: F) {
+        for coord in self.loader.cancel_prefetches(keep) {
+            self.prefetching_chunks.remove(&coord);
+        }
+    }
+
+    /// Drops the request for the given chunk if no worker has picked it up yet. The requester is
+    /// sent a `ChunkRequestCancelled` error
+    pub fn cancel_chunk_request(&mut self, x: i32, y: i32) -> bool {
+        let coord = (x, y);
+        match self.loader.cancel(coord) {
+            Some(request) => {
+                self.loading_chunks.remove(&coord);
+                self.prefetching_chunks.remove(&coord);
+                if let Completion::Load(channel) = request.completion {
+                    let _ = channel.send(PartialChunk(Err(ErrorKind::ChunkRequestCancelled(coord).into()), coord));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Queued chunks closest to this point, in chunk coordinates, are loaded first. Prefetches are
+    /// ordered around the focus offset by `lead`
+    pub fn set_load_focus(&self, focus: (f64, f64), lead: (f64, f64)) {
+        self.loader.set_focus(focus, lead);
+    }
+
+    /// Adds the loaded chunk to the world, or records why it failed to load and returns a
+    /// `ChunkLoadFailed` error. Already loaded and cancelled chunks are returned as is, without
+    /// being recorded
+    pub fn finish_chunk_request(&mut self, partial_chunk: PartialChunk) -> SimResult<()> {
+
+        fn inc_refs<T, F: Fn(Id) -> Feature>(chunk_refs: &[Id], world_refs: &mut IdCountMap, chunk_objs: &mut HashMap<Id, T>, world_objs: &mut HashMap<Id, T>, owned: &mut Vec<Feature>, feature: F, que: &str) {
             for &id in chunk_refs {
                 let count = world_refs.entry(id).or_insert(0);
 
                 // first time load
                 if *count == 0 {
                     let obj = chunk_objs.remove(&id).unwrap();
-                    world_objs.push(obj);
+                    world_objs.insert(id, obj);
+                    owned.push(feature(id));
                 } else {
-                    println!("Incrementing {} {} ref count to {}", que, id, *count + 1);
+                    trace!("Incrementing {} {} ref count to {}", que, id, *count + 1);
                 }
 
                 *count += 1;
@@ -172,30 +576,215 @@ impl World {
         }
 
         let PartialChunk(partial_world, coord) = partial_chunk;
+
+        let mut partial_world = match partial_world {
+            Ok(pw) => pw,
+            Err(e @ Error(ErrorKind::ChunkAlreadyLoaded(_), _)) |
+            Err(e @ Error(ErrorKind::ChunkRequestCancelled(_), _)) => return Err(e),
+            Err(e) => {
+                self.loading_chunks.remove(&coord);
+                let reason = e.to_string();
+                let attempts = self.failed_chunks.get(&coord).map(|f| f.attempts).unwrap_or(0) + 1;
+                self.failed_chunks.insert(coord, FailedChunk {
+                    error: e,
+                    when: SystemTime::now(),
+                    attempts,
+                });
+                return Err(ErrorKind::ChunkLoadFailed(coord, attempts, reason).into());
+            }
+        };
+
         self.loading_chunks.remove(&coord);
+        self.failed_chunks.remove(&coord);
+
+        partial_world.make_coords_relative_to(&self.origin);
 
-        if let Ok(mut partial_world) = partial_world {
-            partial_world.make_coords_relative_to(&self.origin);
+        // create chunk
+        let mut chunk = Chunk {
+            road_refs: partial_world.roads.keys().cloned().collect(),
+            land_use_refs: partial_world.land_uses.keys().cloned().collect(),
+            building_refs: partial_world.buildings.keys().cloned().collect(),
+            poi_refs: partial_world.pois.keys().cloned().collect(),
+            railway_refs: partial_world.railways.keys().cloned().collect(),
+            transit_line_refs: partial_world.transit_lines.keys().cloned().collect(),
+            owned: Vec::new(),
+        };
+
+        inc_refs(&chunk.road_refs, &mut self.road_refs, &mut partial_world.roads, &mut self.loaded_roads, &mut chunk.owned, Feature::Road, "road");
+        inc_refs(&chunk.land_use_refs, &mut self.land_use_refs, &mut partial_world.land_uses, &mut self.loaded_land_uses, &mut chunk.owned, Feature::LandUse, "land use");
+        inc_refs(&chunk.building_refs, &mut self.building_refs, &mut partial_world.buildings, &mut self.loaded_buildings, &mut chunk.owned, Feature::Building, "building");
+        inc_refs(&chunk.poi_refs, &mut self.poi_refs, &mut partial_world.pois, &mut self.loaded_pois, &mut chunk.owned, Feature::PointOfInterest, "point of interest");
+        inc_refs(&chunk.railway_refs, &mut self.railway_refs, &mut partial_world.railways, &mut self.loaded_railways, &mut chunk.owned, Feature::Railway, "railway");
+        inc_refs(&chunk.transit_line_refs, &mut self.transit_line_refs, &mut partial_world.transit_lines, &mut self.loaded_transit_lines, &mut chunk.owned, Feature::TransitLine, "transit line");
+
+        for id in &chunk.poi_refs {
+            if self.poi_refs.get(id) == Some(&1) {
+                self.poi_index.insert(*id, self.loaded_pois[id].position);
+            }
+        }
+
+        self.loaded_chunks.insert(coord, chunk);
+
+        Ok(())
+    }
+
+    pub fn failed_chunk(&self, x: i32, y: i32) -> Option<&FailedChunk> {
+        self.failed_chunks.get(&(x, y))
+    }
+
+    pub fn failed_chunks(&self) -> &HashMap<(i32, i32), FailedChunk> {
+        &self.failed_chunks
+    }
+
+    /// Requests the chunk again if its last load failed, returning false if it didn't. The
+    /// failure is kept until it loads, so the attempts keep counting up
+    pub fn retry_failed_chunk(&mut self, x: i32, y: i32, result_channel: mpsc::Sender<PartialChunk>) -> bool {
+        if !self.failed_chunks.contains_key(&(x, y)) {
+            return false;
+        }
+
+        self.request_chunk_async(x, y, result_channel);
+        true
+    }
+
+    /// Requests all failed chunks again, returning their coords
+    pub fn retry_failed_chunks(&mut self, result_channel: &mpsc::Sender<PartialChunk>) -> Vec<(i32, i32)> {
+        let coords: Vec<_> = self.failed_chunks.keys().cloned().collect();
+        for &(x, y) in &coords {
+            self.request_chunk_async(x, y, result_channel.clone());
+        }
+        coords
+    }
+
+    /// Drops the chunk and any features no other loaded chunk references. Features it owned that
+    /// are still referenced are handed to another chunk, whose coords are returned so their
+    /// geometry can be rebuilt
+    pub fn unload_chunk(&mut self, x: i32, y: i32) -> Vec<(i32, i32)> {
+        fn dec_refs<T>(chunk_refs: &[Id], world_refs: &mut IdCountMap, world_objs: &mut HashMap<Id, T>) {
+            for id in chunk_refs {
+                let remove = match world_refs.get_mut(id) {
+                    Some(count) => {
+                        *count -= 1;
+                        *count == 0
+                    }
+                    None => false,
+                };
+
+                if remove {
+                    world_refs.remove(id);
+                    world_objs.remove(id);
+                }
+            }
+        }
+
+        let chunk = match self.loaded_chunks.remove(&(x, y)) {
+            Some(chunk) => chunk,
+            None => return Vec::new(),
+        };
+
+        dec_refs(&chunk.road_refs, &mut self.road_refs, &mut self.loaded_roads);
+        dec_refs(&chunk.land_use_refs, &mut self.land_use_refs, &mut self.loaded_land_uses);
+        dec_refs(&chunk.building_refs, &mut self.building_refs, &mut self.loaded_buildings);
+
+        for id in &chunk.poi_refs {
+            if self.poi_refs.get(id) == Some(&1) {
+                self.poi_index.remove(*id, &self.loaded_pois[id].position);
+            }
+        }
+        dec_refs(&chunk.poi_refs, &mut self.poi_refs, &mut self.loaded_pois);
+        dec_refs(&chunk.railway_refs, &mut self.railway_refs, &mut self.loaded_railways);
+        dec_refs(&chunk.transit_line_refs, &mut self.transit_line_refs, &mut self.loaded_transit_lines);
+
+        let mut inherited = Vec::new();
+        for feature in chunk.owned {
+            let heir = self.loaded_chunks.iter_mut()
+                .find(|&(_, ref other)| other.references(feature));
+
+            if let Some((coord, other)) = heir {
+                other.owned.push(feature);
+                if !inherited.contains(coord) {
+                    inherited.push(*coord);
+                }
+            }
+        }
 
-            // create chunk
-            let chunk = Chunk {
-                road_refs: partial_world.roads.keys().cloned().collect(),
-                land_use_refs: partial_world.land_uses.keys().cloned().collect(),
-            };
+        inherited
+    }
 
-            inc_refs(&chunk.road_refs, &mut self.road_refs, &mut partial_world.roads, &mut self.loaded_roads, "road");
-            inc_refs(&chunk.land_use_refs, &mut self.land_use_refs, &mut partial_world.land_uses, &mut self.loaded_land_uses, "land use");
+    pub fn stats(&self) -> WorldStats {
+        fn points_size(points: &[Point]) -> usize {
+            points.len() * size_of::<Point>()
+        }
 
-            self.loaded_chunks.insert(coord, chunk);
+        fn area_size<A: Area>(area: &A) -> usize {
+            points_size(area.outline()) +
+                area.holes().iter().map(|h| points_size(h)).sum::<usize>() +
+                area.triangles().len() * size_of::<u32>()
+        }
+
+        let roads: usize = self.loaded_roads.values()
+            .map(|r| size_of::<Road>() + points_size(&r.segments) + r.name.len())
+            .sum();
+        let land_uses: usize = self.loaded_land_uses.values()
+            .map(|lu| size_of::<LandUse>() + area_size(lu))
+            .sum();
+        let buildings: usize = self.loaded_buildings.values()
+            .map(|b| size_of::<Building>() + area_size(b))
+            .sum();
+        let pois: usize = self.loaded_pois.values()
+            .map(|p| size_of::<PointOfInterest>() + p.name.len())
+            .sum();
+        let railways: usize = self.loaded_railways.values()
+            .map(|r| size_of::<Railway>() + points_size(&r.points) + r.name.len())
+            .sum();
+        let transit_lines: usize = self.loaded_transit_lines.values()
+            .map(|l| size_of::<TransitLine>() + l.stops.len() * size_of::<TransitStop>() +
+                l.path.iter().map(|p| points_size(p)).sum::<usize>())
+            .sum();
+        let chunks: usize = self.loaded_chunks.values()
+            .map(|c| (c.road_refs.len() + c.land_use_refs.len() + c.building_refs.len() + c.poi_refs.len() +
+                      c.railway_refs.len() + c.transit_line_refs.len()) * size_of::<Id>() +
+                c.owned.len() * size_of::<Feature>())
+            .sum();
+
+        WorldStats {
+            roads: self.loaded_roads.len(),
+            land_uses: self.loaded_land_uses.len(),
+            buildings: self.loaded_buildings.len(),
+            pois: self.loaded_pois.len(),
+            railways: self.loaded_railways.len(),
+            transit_lines: self.loaded_transit_lines.len(),
+            chunks: self.loaded_chunks.len(),
+            loading: self.loading_chunks.len(),
+            prefetching: self.prefetching_chunks.len(),
+            failed: self.failed_chunks.len(),
+            queued: self.loader.queued_count(),
+            in_flight: IN_FLIGHT.load(Ordering::Relaxed),
+            chunk_cache_hits: CHUNK_CACHE_HITS.load(Ordering::Relaxed),
+            osm_cache_hits: OSM_CACHE_HITS.load(Ordering::Relaxed),
+            cache_misses: CACHE_MISSES.load(Ordering::Relaxed),
+            memory: roads + land_uses + buildings + pois + railways + transit_lines + chunks,
         }
     }
 
+    pub fn loaded_chunk_coords(&self) -> Vec<(i32, i32)> {
+        self.loaded_chunks.keys().cloned().collect()
+    }
+
+    /// The features the given loaded chunk is responsible for drawing
+    pub fn chunk_features(&self, x: i32, y: i32) -> &[Feature] {
+        self.loaded_chunks.get(&(x, y))
+            .map(|c| &c.owned[..])
+            .unwrap_or(&[])
+    }
+
     pub fn request_chunk_sync(&mut self, x: i32, y: i32) -> SimResult<()> {
         let (send, recv) = mpsc::channel();
         self.request_chunk_async(x, y, send);
-        let res = recv.recv()?;
-        self.finish_chunk_request(res);
-        Ok(())
+        match self.finish_chunk_request(recv.recv()?) {
+            Err(Error(ErrorKind::ChunkAlreadyLoaded(_), _)) => Ok(()),
+            res => res,
+        }
     }
 
     pub fn convert_latlon_to_pixel(&self, latlon: &LatLon) -> Point {
@@ -207,10 +796,87 @@ impl World {
         }
     }
 
+    /// All loaded chunks that reference the given feature
+    pub fn chunks_containing(&self, feature: Feature) -> Vec<(i32, i32)> {
+        let mut chunks: Vec<(i32, i32)> = self.loaded_chunks.iter()
+            .filter(|&(_, chunk)| chunk.references(feature))
+            .map(|(coord, _)| *coord)
+            .collect();
+
+        chunks.sort();
+        chunks
+    }
+
+    /// None if the feature isn't loaded
+    pub fn tags(&self, feature: Feature) -> Option<&Tags> {
+        match feature {
+            Feature::Road(id) => self.loaded_roads.get(&id).map(|r| &r.tags),
+            Feature::LandUse(id) => self.loaded_land_uses.get(&id).map(|lu| &lu.tags),
+            Feature::Building(id) => self.loaded_buildings.get(&id).map(|b| &b.tags),
+            Feature::PointOfInterest(id) => self.loaded_pois.get(&id).map(|p| &p.tags),
+            Feature::Railway(id) => self.loaded_railways.get(&id).map(|r| &r.tags),
+            Feature::TransitLine(id) => self.loaded_transit_lines.get(&id).map(|l| &l.tags),
+        }
+    }
+
+    /// The loaded line's timetable, see `transit::Service`
+    pub fn transit_service(&self, line: Id) -> Option<Service> {
+        self.loaded_transit_lines.get(&line)
+            .map(|l| Service::for_line(line, l, latlon::pixels_per_metre(&self.origin)))
+    }
+
+    /// Loaded lines calling at the stop, with where it is in each line's stops
+    pub fn lines_at_stop(&self, stop: Id) -> Vec<(Id, usize)> {
+        self.loaded_transit_lines.iter()
+            .filter_map(|(&id, l)| l.stops.iter().position(|s| s.id == stop).map(|i| (id, i)))
+            .collect()
+    }
+
+    /// Loaded points of interest within `radius` world pixels of the point
+    pub fn pois_near(&self, point: &Point, radius: i32) -> Vec<Id> {
+        self.poi_index.within(point, radius)
+    }
+
+    /// The closest loaded point of interest within `max_distance` world pixels, of the given
+    /// category if there is one
+    pub fn nearest_poi(&self, point: &Point, category: Option<PoiCategory>, max_distance: i32) -> Option<Id> {
+        self.poi_index.nearest(point, max_distance, |id| {
+            category.map(|c| self.loaded_pois[&id].category == c).unwrap_or(true)
+        })
+    }
+
+    /// The closest loaded bus stop or station
+    pub fn nearest_transit_stop(&self, point: &Point, max_distance: i32) -> Option<Id> {
+        self.poi_index.nearest(point, max_distance, |id| {
+            match self.loaded_pois[&id].category {
+                PoiCategory::BusStop | PoiCategory::Station => true,
+                _ => false,
+            }
+        })
+    }
+
+    pub fn pois_by_category(&self, category: PoiCategory) -> Vec<Id> {
+        self.loaded_pois.iter()
+            .filter(|&(_, p)| p.category == category)
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /// Loaded features with a tag matching the glob patterns, see `Tags::matches`
+    pub fn features_tagged(&self, key_pattern: &str, value_pattern: &str) -> Vec<Feature> {
+        let matching = |tags: &Tags| tags.matches(key_pattern, value_pattern);
+
+        self.loaded_roads.iter().filter(|&(_, r)| matching(&r.tags)).map(|(&id, _)| Feature::Road(id))
+            .chain(self.loaded_land_uses.iter().filter(|&(_, lu)| matching(&lu.tags)).map(|(&id, _)| Feature::LandUse(id)))
+            .chain(self.loaded_buildings.iter().filter(|&(_, b)| matching(&b.tags)).map(|(&id, _)| Feature::Building(id)))
+            .chain(self.loaded_pois.iter().filter(|&(_, p)| matching(&p.tags)).map(|(&id, _)| Feature::PointOfInterest(id)))
+            .chain(self.loaded_railways.iter().filter(|&(_, r)| matching(&r.tags)).map(|(&id, _)| Feature::Railway(id)))
+            .chain(self.loaded_transit_lines.iter().filter(|&(_, l)| matching(&l.tags)).map(|(&id, _)| Feature::TransitLine(id)))
+            .collect()
+    }
+
     fn get_save_dir(&self) -> PathBuf {
-        let mut p = WORLD_DIR.clone();
-        p.push(&self.name);
-        p
+        self.save_dir.clone()
     }
 }
 
@@ -222,20 +888,20 @@ fn mkdir(file: &PathBuf) -> SimResult<()> {
     Ok(())
 }
 
-fn attempt_load(world_dir: PathBuf, coord: (i32, i32), bounds: &(LatLon, LatLon)) -> SimResult<parser::PartialWorld> {
-    fn save_chunk(world_dir: &Path, coord: (i32, i32), chunk: &parser::PartialWorld) -> SimResult<()> {
-        let path = get_chunk_path(world_dir, coord);
+pub fn attempt_load(request: &LoadRequest) -> SimResult<parser::PartialWorld> {
+    fn save_chunk(world_dir: &Path, coord: (i32, i32), features: &chunk_req::FeatureFilter, chunk: &parser::PartialWorld) -> SimResult<()> {
+        let path = get_chunk_path(world_dir, coord, features);
 
         mkdir(&path)?;
         serde_json::to_writer(fs::File::create(path)?, &chunk)?;
         Ok(())
     }
 
-    fn load_chunk(world_dir: &Path, coord: (i32, i32)) -> SimResult<Option<parser::PartialWorld>> {
-        let path = get_chunk_path(world_dir, coord);
+    fn load_chunk(world_dir: &Path, coord: (i32, i32), features: &chunk_req::FeatureFilter) -> SimResult<Option<parser::PartialWorld>> {
+        let path = get_chunk_path(world_dir, coord, features);
 
         if path.is_file() {
-            println!("Loading serialized chunk from {:?}", path);
+            debug!("Loading serialized chunk from {:?}", path);
             Ok(Some(serde_json::from_reader(fs::File::open(path)?)?))
 
         } else {
@@ -244,23 +910,55 @@ fn attempt_load(world_dir: PathBuf, coord: (i32, i32), bounds: &(LatLon, LatLon)
     }
 
 
-    // load partial world
-    if let Ok(Some(pw)) = load_chunk(&world_dir, coord) {
+    let world_dir = &request.world_dir;
+    let coord = request.coord;
+    let _span = Span::new("world", format!("load chunk {:?}", coord));
+
+    // load partial world, triangulating in case it was cached before triangles were
+    let cached = {
+        let _span = Span::new("world", format!("deserialize chunk {:?}", coord));
+        load_chunk(world_dir, coord, &request.features)
+    };
+    if let Ok(Some(mut pw)) = cached {
+        CHUNK_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+        pw.triangulate();
+
+        // rewritten so it's only upgraded once
+        if classify::upgrade(&mut pw) {
+            debug!("Reclassified cached chunk {:?}", coord);
+            if let Err(e) = save_chunk(world_dir, coord, &request.features, &pw) {
+                warn!("Failed to rewrite reclassified chunk {:?}: {}", coord, e);
+            }
+        }
         return Ok(pw);
     }
 
     // load cached xml or request it
-    let loaded = parser::parse_osm(fetch_xml(&world_dir, &bounds)?);
-    if let Ok(ref chunk) = loaded {
-        save_chunk(&world_dir, coord, chunk)?;
+    let xml = {
+        let _span = Span::new("world", format!("fetch chunk {:?}", coord));
+        fetch_xml(world_dir, &request.bounds, &request.client, &request.features)?
+    };
+
+    let mut loaded = {
+        let _span = Span::new("parser", format!("parse chunk {:?}", coord));
+        parser::parse_osm(xml, request.parse_mode)
+    };
+
+    if let Ok(ref mut chunk) = loaded {
+        chunk.triangulate();
+
+        let _span = Span::new("world", format!("serialize chunk {:?}", coord));
+        save_chunk(world_dir, coord, &request.features, chunk)?;
     }
 
     loaded
 }
 
-fn get_chunk_path(world_dir: &Path, coord: (i32, i32)) -> PathBuf {
+/// Chunks and OSM are cached separately for each feature filter
+fn get_chunk_path(world_dir: &Path, coord: (i32, i32), features: &chunk_req::FeatureFilter) -> PathBuf {
     let mut p = PathBuf::from(world_dir);
     p.push("chunks");
+    p.push(features.cache_key());
     p.push(format!(
             "r.{}.{}.bin",
             coord.0,
@@ -269,10 +967,11 @@ fn get_chunk_path(world_dir: &Path, coord: (i32, i32)) -> PathBuf {
     p
 }
 
-fn fetch_xml(world_dir: &Path, bounds: &(LatLon, LatLon)) -> SimResult<String> {
+fn fetch_xml(world_dir: &Path, bounds: &(LatLon, LatLon), client: &chunk_req::Client, features: &chunk_req::FeatureFilter) -> SimResult<String> {
     let cache = {
         let mut p = PathBuf::from(world_dir);
         p.push("osm");
+        p.push(features.cache_key());
         p.push(format!(
             "{}_{}_{}_{}.osm",
             (bounds.1).lat,
@@ -284,26 +983,225 @@ fn fetch_xml(world_dir: &Path, bounds: &(LatLon, LatLon)) -> SimResult<String> {
     };
 
     if cache.is_file() {
-        println!("Loading cached OSM from {:?}", cache);
+        OSM_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+        debug!("Loading cached OSM from {:?}", cache);
         let mut contents = String::new();
         fs::File::open(cache)?.read_to_string(&mut contents)?;
         Ok(contents)
     } else {
-        let xml = {
-            let _guard = REQUEST_SEM.access();
-            println!(
+        CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+
+        // only hold a request slot while actually requesting, not while backing off
+        let xml = client.request_osm_with((bounds.0.lat, bounds.0.lon), (bounds.1.lat, bounds.1.lon), features, || {
+            let slot = RequestSlot::acquire(&REQUEST_SEM);
+            info!(
                 "Sending request for {}, {} -> {}, {}",
                 (bounds.0).lat,
                 (bounds.0).lon,
                 (bounds.1).lat,
                 (bounds.1).lon
                 );
-            chunk_req::request_osm((bounds.0.lat, bounds.0.lon), (bounds.1.lat, bounds.1.lon))?
-        };
-        println!("{} bytes read", xml.len());
+            slot
+        })?;
+        debug!("{} bytes read", xml.len());
         mkdir(&cache)?;
         fs::File::create(cache)?.write_all(xml.as_bytes())?;
 
         Ok(xml)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+    use std::thread;
+    use std::time::Duration;
+    use chunk_req::mock::{MockServer, Response};
+
+    const ORIGIN: (f64, f64) = (51.5, -0.12);
+
+    /// Removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let mut p = env::temp_dir();
+            p.push(format!("sim-test-{}-{}", process::id(), name));
+            let _ = fs::remove_dir_all(&p);
+            TempDir(p)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn fixture_path() -> PathBuf {
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("chunk_req");
+        p.push("tests");
+        p.push("fixtures");
+        p.push("chunk.osm");
+        p
+    }
+
+    fn server() -> MockServer {
+        let server = MockServer::start().unwrap();
+        server.add_fixture_file(None, fixture_path()).unwrap();
+        server
+    }
+
+    fn world_for(server: &MockServer, dir: &TempDir) -> World {
+        let config = chunk_req::ClientConfig {
+            base_url: server.base_url(),
+            timeout: Some(Duration::from_secs(5)),
+            retry: chunk_req::RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(50),
+                max_retry_after: Duration::from_millis(100),
+            },
+            rate_limit: None,
+            ..Default::default()
+        };
+
+        let client = chunk_req::Client::new(config).unwrap();
+        let mut world = World::new(String::from("test"), LatLon::new(ORIGIN.0, ORIGIN.1), client);
+        world.set_save_dir(dir.0.clone());
+        world
+    }
+
+    /// Requests the chunk and waits for it to be added to the world or fail
+    fn load(world: &mut World, x: i32, y: i32) -> SimResult<()> {
+        let (tx, rx) = mpsc::channel();
+        world.request_chunk_async(x, y, tx);
+        world.finish_chunk_request(rx.recv_timeout(timeout()).expect("Chunk never loaded"))
+    }
+
+    fn wait_for_prefetches(world: &mut World) {
+        for _ in 0..200 {
+            world.finish_prefetches();
+            if world.stats().prefetching == 0 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("Prefetches never finished");
+    }
+
+    fn has_test_street(world: &World) -> bool {
+        world.loaded_roads.values().any(|r| r.name == "Test Street")
+    }
+
+    #[test]
+    fn second_load_served_from_cache() {
+        let dir = TempDir::new("cache");
+        let server = server();
+
+        let mut world = world_for(&server, &dir);
+        load(&mut world, 0, 0).unwrap();
+        assert!(has_test_street(&world));
+        assert_eq!(server.request_count(), 1);
+
+        // from the chunk cache
+        world.unload_chunk(0, 0);
+        load(&mut world, 0, 0).unwrap();
+        assert!(has_test_street(&world));
+        assert_eq!(server.request_count(), 1);
+
+        // from the osm cache, parsed again
+        fs::remove_dir_all(dir.0.join("chunks")).unwrap();
+        let mut world = world_for(&server, &dir);
+        load(&mut world, 0, 0).unwrap();
+        assert!(has_test_street(&world));
+        assert_eq!(server.request_count(), 1);
+    }
+
+    #[test]
+    fn failure_recorded_and_retried() {
+        let dir = TempDir::new("failure");
+        let server = server();
+
+        // not found isn't retried by the client
+        server.set_default_response(Response::Status(404));
+        let mut world = world_for(&server, &dir);
+        match load(&mut world, 0, 0) {
+            Err(Error(ErrorKind::ChunkLoadFailed((0, 0), 1, _), _)) => {}
+            res => panic!("Unexpected result {:?}", res),
+        }
+        assert_eq!(world.failed_chunk(0, 0).map(|f| f.attempts), Some(1));
+        assert!(world.loaded_chunk_coords().is_empty());
+        assert_eq!(server.request_count(), 1);
+
+        // but a busy server is, so this succeeds on its second request
+        server.set_default_response(Response::Fixture);
+        server.push_response(Response::Status(503));
+        let (tx, rx) = mpsc::channel();
+        assert!(world.retry_failed_chunk(0, 0, tx));
+        world.finish_chunk_request(rx.recv_timeout(timeout()).unwrap()).unwrap();
+
+        assert!(world.failed_chunk(0, 0).is_none());
+        assert!(has_test_street(&world));
+        assert_eq!(server.request_count(), 3);
+        assert!(!world.retry_failed_chunk(0, 0, mpsc::channel().0));
+    }
+
+    #[test]
+    fn concurrent_requests_deduplicated() {
+        let dir = TempDir::new("dedup");
+        let server = server();
+
+        // slow enough for the second request to arrive while the first is still loading
+        server.set_default_response(Response::Slow(Duration::from_millis(300)));
+        let mut world = world_for(&server, &dir);
+
+        let (tx, rx) = mpsc::channel();
+        world.request_chunk_async(0, 0, tx.clone());
+        world.request_chunk_async(0, 0, tx);
+        let loaded = (0..2)
+            .map(|_| rx.recv_timeout(timeout()).unwrap())
+            .map(|chunk| world.finish_chunk_request(chunk))
+            .filter(|res| res.is_ok())
+            .count();
+        assert_eq!(loaded, 1);
+        assert_eq!(server.request_count(), 1);
+
+        // a visible request for a chunk being prefetched joins the prefetch
+        world.prefetch_chunk(1, 0);
+        load(&mut world, 1, 0).unwrap();
+        wait_for_prefetches(&mut world);
+        assert_eq!(server.request_count(), 2);
+    }
+
+    #[test]
+    fn failed_prefetch_retried() {
+        let dir = TempDir::new("prefetch");
+        let server = server();
+        server.set_default_response(Response::Status(404));
+        let mut world = world_for(&server, &dir);
+
+        world.prefetch_chunk(0, 0);
+        assert_eq!(world.stats().prefetching, 1);
+        wait_for_prefetches(&mut world);
+        assert_eq!(server.request_count(), 1);
+
+        server.set_default_response(Response::Fixture);
+        world.prefetch_chunk(0, 0);
+        wait_for_prefetches(&mut world);
+        assert_eq!(server.request_count(), 2);
+        assert!(world.loaded_chunk_coords().is_empty());
+
+        // cached, so neither prefetched nor requested again
+        world.prefetch_chunk(0, 0);
+        assert_eq!(world.stats().prefetching, 0);
+        load(&mut world, 0, 0).unwrap();
+        assert_eq!(server.request_count(), 2);
+    }
+}